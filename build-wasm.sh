@@ -2,3 +2,12 @@
 set -euo pipefail
 
 wasm-pack build --out-dir ../web-src/pkg --target web emulator
+
+# Precompressed copies, which the server sends instead of compressing on every request
+rm -f web-src/pkg/*.br web-src/pkg/*.gz
+for file in web-src/pkg/*.js web-src/pkg/*.wasm; do
+    gzip --best --keep --force "$file"
+    if command -v brotli > /dev/null; then
+        brotli --best --keep --force "$file"
+    fi
+done
@@ -0,0 +1,55 @@
+//! Measures how fast a batch of machines runs a bundled ROM.
+//!
+//! ```bash
+//! cargo run --release -p chip-8-emulator --no-default-features --example batch -- BRIX 4096 600
+//! ```
+
+use chip_8_emulator::{
+    batch::{Batch, Stats},
+    keypad::KEY_COUNT,
+    quirks::Quirks,
+    roms::ROMS_BY_NAME,
+};
+use std::{env, process, time::Duration};
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let rom_name = args.first().map_or("BRIX", String::as_str);
+    let count = args
+        .get(1)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(1024);
+    let frames: u32 = args
+        .get(2)
+        .and_then(|frames| frames.parse().ok())
+        .unwrap_or(600);
+
+    let Some(rom) = ROMS_BY_NAME.get(rom_name) else {
+        eprintln!("Unknown ROM {rom_name}");
+        process::exit(1);
+    };
+
+    let mut batch = Batch::new(rom, Quirks::default(), count, 0, None);
+    let mut total = Stats {
+        instructions: 0,
+        elapsed: Duration::ZERO,
+    };
+    // Each machine holds a different key each second, so that they don't all play the same game
+    for second in 0..frames.div_ceil(60) {
+        let keys = (0..count)
+            .map(|i| 1 << ((i + second as usize) % KEY_COUNT))
+            .collect::<Vec<u16>>();
+        let stats = batch.step(60.min(frames - second * 60), &keys);
+        total.instructions += stats.instructions;
+        total.elapsed += stats.elapsed;
+    }
+
+    let threads = batch.threads();
+    let per_second = total.instructions_per_second() / 1e6;
+    println!(
+        "{count} machines ran {frames} frames of {rom_name} in {:.2?}: {per_second:.1}M \
+         instructions/s, {:.1}M per thread on {threads} threads",
+        total.elapsed,
+        per_second / threads as f64,
+    );
+}
@@ -0,0 +1,62 @@
+//! Measures how much faster the decoded instruction cache behind `Cpu::cycle` runs every bundled
+//! ROM than the reference interpreter, `Cpu::cycle_uncached`. The tests in `cpu.rs` check that
+//! both run them identically.
+//!
+//! ```bash
+//! cargo run --release -p chip-8-emulator --no-default-features --example differential
+//! ```
+
+use chip_8_emulator::{
+    cpu::{Cpu, CYCLES_PER_FRAME},
+    roms::ROMS_BY_NAME,
+};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+const FRAMES: u32 = 60 * 60 * 10;
+
+/// `Cpu::run_frame`, with the reference interpreter.
+fn run_frame_uncached(cpu: &mut Cpu) {
+    cpu.keypad.process_events();
+    for _ in 0..CYCLES_PER_FRAME {
+        cpu.cycle_uncached();
+    }
+    cpu.update_timers();
+}
+
+fn time(rom: &[u8], run_frame: fn(&mut Cpu)) -> Duration {
+    let mut cpu = Cpu::with_seed(rom, 0);
+    let started_at = Instant::now();
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        for _ in 0..FRAMES {
+            run_frame(&mut cpu);
+        }
+    }));
+    started_at.elapsed()
+}
+
+fn main() {
+    // Some programs crash the CPU, which ends their timing early
+    panic::set_hook(Box::new(|_| ()));
+
+    let mut roms = ROMS_BY_NAME.iter().collect::<Vec<_>>();
+    roms.sort();
+
+    let (mut cached_time, mut reference_time) = (Duration::ZERO, Duration::ZERO);
+    for (name, rom) in roms {
+        let (cached, reference) = (time(rom, Cpu::run_frame), time(rom, run_frame_uncached));
+        println!(
+            "{name}: {:.2}x faster",
+            reference.as_secs_f64() / cached.as_secs_f64()
+        );
+        cached_time += cached;
+        reference_time += reference;
+    }
+
+    println!(
+        "Overall: {:.2}x faster ({cached_time:.2?} against {reference_time:.2?})",
+        reference_time.as_secs_f64() / cached_time.as_secs_f64()
+    );
+}
@@ -0,0 +1,297 @@
+use crate::{
+    cpu::MAX_ROM_BYTES,
+    display::{HEIGHT, WIDTH},
+    emulator::{Emulator, EmulatorState},
+    keypad::{KeyInput, KeyState, KEY_COUNT},
+    quirks::Quirks,
+    view::{self, AnimationFrame, View},
+};
+use gloo_events::EventListener;
+use gloo_utils::document;
+use serde::Serialize;
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::prelude::*;
+use web_sys::HtmlCanvasElement;
+
+/// Snapshot of the CPU registers, as returned by [`Chip8::registers`].
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Registers {
+    v: Vec<u8>,
+    i: u16,
+    pc: u16,
+    sp: u8,
+    stack: Vec<u16>,
+    delay_timer: u8,
+    sound_timer: u8,
+}
+
+#[derive(Debug, Default)]
+struct Callbacks {
+    on_frame: Option<js_sys::Function>,
+    on_state_change: Option<js_sys::Function>,
+}
+
+fn state_name(state: EmulatorState) -> &'static str {
+    match state {
+        EmulatorState::Stopped => "stopped",
+        EmulatorState::Running => "running",
+        EmulatorState::Paused => "paused",
+    }
+}
+
+/// An emulator for embedding in other pages, driven entirely from JavaScript:
+///
+/// ```js
+/// const chip8 = new Chip8(canvas);
+/// chip8.loadRom(new Uint8Array(await (await fetch("PONG")).arrayBuffer()));
+/// chip8.onStateChange((state) => console.log(state));
+/// chip8.run();
+/// ```
+///
+/// Nothing is bound to the keyboard, so the host page forwards input with `pressKey` and
+/// `releaseKey`. Call `free()` to stop the emulator and release its canvas.
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct Chip8 {
+    emulator: Rc<RefCell<Emulator>>,
+    view: Rc<RefCell<View>>,
+    keypad: Rc<RefCell<KeyInput>>,
+    callbacks: Rc<RefCell<Callbacks>>,
+    _render_loop: AnimationFrame,
+    _on_visibility_change: EventListener,
+}
+
+#[wasm_bindgen]
+impl Chip8 {
+    /// Creates a stopped emulator that presents on `canvas`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(canvas: &HtmlCanvasElement) -> Self {
+        let emulator = Rc::new(RefCell::new(Emulator::new()));
+        let view = Rc::new(RefCell::new(View::with_canvas(canvas)));
+        let callbacks = Rc::new(RefCell::new(Callbacks::default()));
+
+        let render_loop = {
+            let emulator = Rc::clone(&emulator);
+            let view = Rc::clone(&view);
+            let callbacks = Rc::clone(&callbacks);
+            let mut last_state = EmulatorState::Stopped;
+            view::set_up_render_loop(move |ticks| {
+                let mut emulator = emulator.borrow_mut();
+                let state = emulator.state();
+                emulator.run(ticks, &mut view.borrow_mut());
+                drop(emulator);
+
+                // Callbacks may call back into this emulator, so nothing can be borrowed here
+                if state != last_state {
+                    last_state = state;
+                    let on_state_change = callbacks.borrow().on_state_change.clone();
+                    if let Some(on_state_change) = on_state_change {
+                        let _ = on_state_change.call1(&JsValue::NULL, &state_name(state).into());
+                    }
+                }
+                if state == EmulatorState::Running && ticks > 0 {
+                    let on_frame = callbacks.borrow().on_frame.clone();
+                    if let Some(on_frame) = on_frame {
+                        let _ = on_frame.call0(&JsValue::NULL);
+                    }
+                }
+            })
+        };
+
+        let on_visibility_change = {
+            let emulator = Rc::clone(&emulator);
+            EventListener::new(&document(), "visibilitychange", move |_| {
+                emulator.borrow_mut().set_page_hidden(document().hidden());
+            })
+        };
+
+        Self {
+            emulator,
+            view,
+            keypad: Rc::default(),
+            callbacks,
+            _render_loop: render_loop,
+            _on_visibility_change: on_visibility_change,
+        }
+    }
+
+    /// Loads `rom` and leaves it paused at the first instruction, ready to `run` or `step`.
+    ///
+    /// # Errors
+    /// Returns an error if `rom` does not fit into memory.
+    #[wasm_bindgen(js_name = loadRom)]
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), JsError> {
+        if rom.len() > MAX_ROM_BYTES {
+            return Err(JsError::new(&format!(
+                "ROM is {} bytes, but at most {MAX_ROM_BYTES} bytes fit in memory",
+                rom.len()
+            )));
+        }
+
+        let mut emulator = self.emulator.borrow_mut();
+        emulator.load_rom(rom, Rc::clone(&self.keypad));
+        emulator.pause();
+        Ok(())
+    }
+
+    /// Sets the interpreter quirks from an object such as `{ shiftUsesVy: true }`. Omitted quirks
+    /// are turned off.
+    ///
+    /// # Errors
+    /// Returns an error if `quirks` is not an object of booleans.
+    #[wasm_bindgen(js_name = setQuirks)]
+    pub fn set_quirks(&mut self, quirks: &JsValue) -> Result<(), JsError> {
+        let json = js_sys::JSON::stringify(quirks)
+            .map_err(|_| JsError::new("Quirks must be serializable to JSON"))?;
+        let quirks = serde_json::from_str::<Quirks>(&String::from(json))?;
+
+        self.emulator.borrow_mut().set_quirks(quirks);
+        Ok(())
+    }
+
+    /// Compiles code that runs often to WebAssembly when `isOn`, which plays exactly like
+    /// interpreting it. Off by default.
+    #[wasm_bindgen(js_name = setJit)]
+    pub fn set_jit(&mut self, is_on: bool) {
+        self.emulator.borrow_mut().set_jit(is_on);
+    }
+
+    /// One of `"stopped"`, `"running"` or `"paused"`.
+    #[must_use]
+    pub fn state(&self) -> String {
+        state_name(self.emulator.borrow().state()).to_owned()
+    }
+
+    pub fn run(&mut self) {
+        self.emulator.borrow_mut().resume();
+    }
+
+    pub fn pause(&mut self) {
+        self.emulator.borrow_mut().pause();
+    }
+
+    pub fn reset(&mut self) {
+        self.emulator.borrow_mut().reset();
+    }
+
+    /// Executes a single instruction. Only has an effect while paused.
+    pub fn step(&mut self) {
+        self.emulator.borrow_mut().step();
+        self.present();
+    }
+
+    /// Runs a single 60Hz frame. Only has an effect while paused.
+    #[wasm_bindgen(js_name = stepFrame)]
+    pub fn step_frame(&mut self) {
+        self.emulator
+            .borrow_mut()
+            .step_frame(&mut self.view.borrow_mut());
+        self.present();
+    }
+
+    fn present(&self) {
+        if let Some(cpu) = self.emulator.borrow_mut().cpu_mut() {
+            self.view.borrow_mut().render(&mut cpu.framebuffer);
+        }
+    }
+
+    fn set_key_state(&self, key: u8, state: KeyState) -> Result<(), JsError> {
+        if key as usize >= KEY_COUNT {
+            return Err(JsError::new(&format!("{key} is not a CHIP-8 key")));
+        }
+
+        self.keypad
+            .borrow_mut()
+            .update_key_state(key as usize, state);
+        Ok(())
+    }
+
+    /// Presses CHIP-8 key `key`, from 0 to 15.
+    ///
+    /// # Errors
+    /// Returns an error if `key` is out of range.
+    #[wasm_bindgen(js_name = pressKey)]
+    pub fn press_key(&self, key: u8) -> Result<(), JsError> {
+        self.set_key_state(key, KeyState::Down)
+    }
+
+    /// Releases CHIP-8 key `key`, from 0 to 15.
+    ///
+    /// # Errors
+    /// Returns an error if `key` is out of range.
+    #[wasm_bindgen(js_name = releaseKey)]
+    pub fn release_key(&self, key: u8) -> Result<(), JsError> {
+        self.set_key_state(key, KeyState::Up)
+    }
+
+    /// Serializes the machine, or returns `undefined` if no ROM is loaded.
+    #[wasm_bindgen(js_name = saveState)]
+    #[must_use]
+    pub fn save_state(&self) -> Option<Vec<u8>> {
+        self.emulator.borrow().cpu().map(|cpu| cpu.save_state())
+    }
+
+    /// Restores a state from `saveState`, keeping the current run state and quirks.
+    ///
+    /// # Errors
+    /// Returns an error if no ROM is loaded or `state` is not a valid save state.
+    #[wasm_bindgen(js_name = loadState)]
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsError> {
+        self.emulator
+            .borrow_mut()
+            .load_state(state)
+            .ok_or_else(|| JsError::new("No ROM is loaded"))??;
+
+        self.present();
+        Ok(())
+    }
+
+    /// The display as 64x32 bytes in row-major order, each 1 if the pixel is lit and 0 otherwise.
+    #[must_use]
+    pub fn framebuffer(&self) -> Vec<u8> {
+        let emulator = self.emulator.borrow();
+        let Some(cpu) = emulator.cpu() else {
+            return vec![0; (WIDTH * HEIGHT) as usize];
+        };
+
+        (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+            .map(|(x, y)| u8::from(cpu.framebuffer.pixel(x, y)))
+            .collect()
+    }
+
+    /// The CPU registers as `{ v, i, pc, sp, stack, delayTimer, soundTimer }`, or `undefined` if
+    /// no ROM is loaded.
+    #[must_use]
+    pub fn registers(&self) -> JsValue {
+        let emulator = self.emulator.borrow();
+        let Some(cpu) = emulator.cpu() else {
+            return JsValue::UNDEFINED;
+        };
+
+        let registers = Registers {
+            v: cpu.regs.to_vec(),
+            i: cpu.i_reg,
+            pc: cpu.pc,
+            sp: cpu.sp,
+            stack: cpu.stack.to_vec(),
+            delay_timer: cpu.delay_timer,
+            sound_timer: cpu.sound_timer,
+        };
+        js_sys::JSON::parse(&serde_json::to_string(&registers).unwrap_throw()).unwrap_throw()
+    }
+
+    /// Calls `callback` after every frame in which the emulator ran.
+    #[wasm_bindgen(js_name = onFrame)]
+    pub fn on_frame(&mut self, callback: Option<js_sys::Function>) {
+        self.callbacks.borrow_mut().on_frame = callback;
+    }
+
+    /// Calls `callback` with the new state (see `state`) whenever it changes, including when the
+    /// emulator pauses itself because the page is hidden.
+    #[wasm_bindgen(js_name = onStateChange)]
+    pub fn on_state_change(&mut self, callback: Option<js_sys::Function>) {
+        self.callbacks.borrow_mut().on_state_change = callback;
+    }
+}
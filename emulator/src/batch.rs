@@ -0,0 +1,170 @@
+use crate::{
+    cpu::{Cpu, CYCLES_PER_FRAME},
+    quirks::Quirks,
+    stream::Rows,
+};
+use std::{
+    num::NonZeroUsize,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a [`Batch::step`] took.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Stats {
+    /// Instructions run, across every machine.
+    pub instructions: u64,
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    #[must_use]
+    pub fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Many machines running the same program in lockstep on a pool of threads, e.g. for search,
+/// fuzzing or reinforcement learning.
+///
+/// Each step splits the machines between the threads, so a machine may run on a different thread
+/// every time. Input goes in and results come out as plain data: key bitmasks, displays and
+/// hashes. Native only, as it needs threads.
+#[derive(Debug)]
+pub struct Batch {
+    cpus: Vec<Cpu>,
+    threads: usize,
+}
+
+impl Batch {
+    /// Creates `count` machines running `rom`, where machine `i` is seeded with `seed + i`, to run
+    /// on `threads` threads or one per core if `None`.
+    ///
+    /// # Panics
+    /// Panics if `rom` does not fit into memory.
+    #[must_use]
+    pub fn new(
+        rom: &[u8],
+        quirks: Quirks,
+        count: usize,
+        seed: u64,
+        threads: Option<NonZeroUsize>,
+    ) -> Self {
+        let cpus = (0..count)
+            .map(|i| {
+                let mut cpu = Cpu::with_seed(rom, seed.wrapping_add(i as u64));
+                cpu.quirks = quirks;
+                cpu
+            })
+            .collect();
+        Self::from_cpus(cpus, threads)
+    }
+
+    /// Runs machines that were set up elsewhere, on `threads` threads or one per core if `None`.
+    #[must_use]
+    pub fn from_cpus(cpus: Vec<Cpu>, threads: Option<NonZeroUsize>) -> Self {
+        let threads = threads
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+            .min(cpus.len().max(1));
+        Self { cpus, threads }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.cpus.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_empty()
+    }
+
+    /// How many threads [`Self::step`] runs on.
+    #[must_use]
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    #[must_use]
+    pub fn cpus(&self) -> &[Cpu] {
+        &self.cpus
+    }
+
+    #[must_use]
+    pub fn into_cpus(self) -> Vec<Cpu> {
+        self.cpus
+    }
+
+    /// Runs `frames` frames on every machine, holding the keys in `keys[i]`, a bitmask as from
+    /// [`crate::keypad::key_mask`], on machine `i`.
+    ///
+    /// # Panics
+    /// Panics if there isn't one bitmask per machine, or a program crashes its CPU.
+    pub fn step(&mut self, frames: u32, keys: &[u16]) -> Stats {
+        assert_eq!(keys.len(), self.len(), "Need one set of keys per machine");
+
+        let started_at = Instant::now();
+        // Machines are split as evenly as possible, with the last thread taking fewer
+        let chunk_len = self.len().div_ceil(self.threads).max(1);
+        thread::scope(|scope| {
+            for (cpus, keys) in self.cpus.chunks_mut(chunk_len).zip(keys.chunks(chunk_len)) {
+                scope.spawn(move || {
+                    for (cpu, &mask) in cpus.iter_mut().zip(keys) {
+                        cpu.keypad.hold_keys(mask);
+                        for _ in 0..frames {
+                            cpu.run_frame();
+                        }
+                    }
+                });
+            }
+        });
+
+        Stats {
+            instructions: self.len() as u64 * u64::from(frames) * u64::from(CYCLES_PER_FRAME),
+            elapsed: started_at.elapsed(),
+        }
+    }
+
+    /// Reads something from every machine, in order.
+    pub fn map<T>(&self, f: impl Fn(&Cpu) -> T) -> Vec<T> {
+        self.cpus.iter().map(f).collect()
+    }
+
+    /// Every machine's display.
+    #[must_use]
+    pub fn rows(&self) -> Vec<Rows> {
+        self.map(|cpu| *cpu.framebuffer.rows())
+    }
+
+    /// Every machine's [`Cpu::state_hash`].
+    #[must_use]
+    pub fn state_hashes(&self) -> Vec<u64> {
+        self.map(Cpu::state_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roms::ROMS_BY_NAME;
+
+    #[test]
+    fn batch_plays_like_machines_run_one_at_a_time() {
+        let rom = &ROMS_BY_NAME["BRIX"];
+        let keys = (0..10).map(|i| 1 << i).collect::<Vec<u16>>();
+        let mut batch = Batch::new(rom, Quirks::default(), keys.len(), 7, NonZeroUsize::new(3));
+        batch.step(120, &keys);
+
+        // A batch can be handed to another thread, and its machines taken back out
+        let cpus = thread::spawn(move || batch.into_cpus()).join().unwrap();
+        for (i, (cpu, &mask)) in cpus.iter().zip(&keys).enumerate() {
+            let mut alone = Cpu::with_seed(rom, 7 + i as u64);
+            alone.keypad.hold_keys(mask);
+            for _ in 0..120 {
+                alone.run_frame();
+            }
+            assert_eq!(cpu.state_hash(), alone.state_hash(), "Machine {i} differs");
+        }
+    }
+}
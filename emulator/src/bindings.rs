@@ -0,0 +1,357 @@
+use crate::{gamepad, keypad::KEY_COUNT, roms, storage};
+use gloo_events::EventListener;
+use gloo_utils::document;
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{HtmlButtonElement, HtmlInputElement};
+
+/// The CHIP-8 used a hexadecimal keyboard with the following layout:
+///
+/// 1 2 3 C
+/// 4 5 6 D
+/// 7 8 9 E
+/// A 0 B F
+pub const KEYPAD_LAYOUT: [usize; KEY_COUNT] = [
+    0x1, 0x2, 0x3, 0xC, //
+    0x4, 0x5, 0x6, 0xD, //
+    0x7, 0x8, 0x9, 0xE, //
+    0xA, 0x0, 0xB, 0xF, //
+];
+
+/// By default, we remap these keys to the following layout:
+///
+/// 1 2 3 4
+/// Q W E R
+/// A S D F
+/// Z X C V
+const DEFAULT_KEY_CODES: [&str; KEY_COUNT] = [
+    "KeyX", "Digit1", "Digit2", "Digit3", "KeyQ", // 0 - 4
+    "KeyW", "KeyE", "KeyA", "KeyS", "KeyD", // 5 - 9
+    "KeyZ", "KeyC", "Digit4", "KeyR", "KeyF", "KeyV", // A - F
+];
+
+/// Where host inputs come from. Each source has its own set of bindings.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InputSource {
+    /// Bound by `KeyboardEvent.code`. Bindings are global, with optional per-ROM overrides.
+    Keyboard,
+    /// Bound by the names in [`gamepad`]. Bindings are always per-ROM, as sensible defaults depend
+    /// on the game.
+    Gamepad,
+}
+
+impl InputSource {
+    fn storage_key(self, rom_name: Option<&str>) -> String {
+        let prefix = match self {
+            Self::Keyboard => "bindings",
+            Self::Gamepad => "gamepad",
+        };
+
+        match rom_name {
+            Some(rom_name) => format!("{prefix}/{rom_name}"),
+            None => prefix.to_owned(),
+        }
+    }
+
+    fn default_bindings(self, rom_name: &str) -> KeyBindings {
+        match self {
+            Self::Keyboard => KeyBindings::default(),
+            Self::Gamepad => gamepad::default_bindings(&roms::rom_info(rom_name).controls),
+        }
+    }
+
+    fn container_id(self) -> &'static str {
+        match self {
+            Self::Keyboard => "key-bindings",
+            Self::Gamepad => "gamepad-bindings",
+        }
+    }
+}
+
+/// Host inputs (e.g. `KeyboardEvent.code` values) bound to each CHIP-8 key.
+///
+/// Serialized as a JSON array of 16 arrays of codes, indexed by CHIP-8 key.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct KeyBindings {
+    codes: [Vec<String>; KEY_COUNT],
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            codes: DEFAULT_KEY_CODES.map(|code| vec![code.to_owned()]),
+        }
+    }
+}
+
+impl KeyBindings {
+    #[must_use]
+    pub fn empty() -> Self {
+        Self {
+            codes: Default::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn key_for_code(&self, code: &str) -> Option<usize> {
+        self.codes
+            .iter()
+            .position(|codes| codes.iter().any(|c| c == code))
+    }
+
+    #[must_use]
+    pub fn codes(&self, key: usize) -> &[String] {
+        &self.codes[key]
+    }
+
+    /// Binds `code` to `key`, unbinding it from any other key.
+    pub fn bind(&mut self, key: usize, code: &str) {
+        for codes in &mut self.codes {
+            codes.retain(|c| c != code);
+        }
+        self.codes[key].push(code.to_owned());
+    }
+
+    pub fn clear(&mut self, key: usize) {
+        self.codes[key].clear();
+    }
+
+    fn load(key: &str) -> Option<Self> {
+        storage::get(key).and_then(|json| serde_json::from_str(&json).ok())
+    }
+
+    fn save(&self, key: &str) {
+        storage::set(key, &serde_json::to_string(self).unwrap_throw());
+    }
+}
+
+/// Bindings of one input source for the selected ROM, along with the controls for remapping them.
+#[derive(Debug)]
+pub struct Remapper {
+    pub bindings: KeyBindings,
+    source: InputSource,
+    rom_name: String,
+    /// Whether `bindings` override the global bindings for this ROM only.
+    is_per_rom: bool,
+    /// CHIP-8 key waiting for a host input to be bound to it.
+    capturing: Option<usize>,
+    buttons: Vec<HtmlButtonElement>,
+    input_per_rom: Option<HtmlInputElement>,
+}
+
+impl Remapper {
+    /// Creates a button for each CHIP-8 key inside the source's container element.
+    pub fn new(source: InputSource, rom_name: &str) -> Self {
+        let container = document()
+            .get_element_by_id(source.container_id())
+            .unwrap_throw();
+        let buttons = (0..KEY_COUNT)
+            .map(|_| {
+                document()
+                    .create_element("button")
+                    .unwrap_throw()
+                    .dyn_into::<HtmlButtonElement>()
+                    .unwrap_throw()
+            })
+            .collect::<Vec<_>>();
+        for &key in &KEYPAD_LAYOUT {
+            let button = &buttons[key];
+            button.set_type("button");
+            button.set_class_name(
+                "py-1 px-2 rounded-sm border border-gray-200 hover:bg-gray-100 text-left",
+            );
+            container.append_child(button).unwrap_throw();
+        }
+
+        let input_per_rom = match source {
+            InputSource::Keyboard => Some(
+                document()
+                    .get_element_by_id("input-bindings-per-rom")
+                    .unwrap_throw()
+                    .dyn_into::<HtmlInputElement>()
+                    .unwrap_throw(),
+            ),
+            InputSource::Gamepad => None,
+        };
+
+        let mut remapper = Self {
+            bindings: KeyBindings::default(),
+            source,
+            rom_name: String::new(),
+            is_per_rom: false,
+            capturing: None,
+            buttons,
+            input_per_rom,
+        };
+        remapper.load(rom_name);
+        remapper
+    }
+
+    fn has_global_bindings(&self) -> bool {
+        self.input_per_rom.is_some()
+    }
+
+    /// Loads the bindings for `rom_name`, preferring its per-ROM override.
+    pub fn load(&mut self, rom_name: &str) {
+        let rom_bindings = KeyBindings::load(&self.source.storage_key(Some(rom_name)));
+
+        self.rom_name = rom_name.to_owned();
+        self.is_per_rom = rom_bindings.is_some() || !self.has_global_bindings();
+        self.bindings = rom_bindings
+            .or_else(|| {
+                self.has_global_bindings()
+                    .then(|| KeyBindings::load(&self.source.storage_key(None)))
+                    .flatten()
+            })
+            .unwrap_or_else(|| self.source.default_bindings(rom_name));
+        self.capturing = None;
+        self.show();
+    }
+
+    fn save(&self) {
+        let rom_name = self.is_per_rom.then_some(self.rom_name.as_str());
+        self.bindings.save(&self.source.storage_key(rom_name));
+    }
+
+    fn show(&self) {
+        for (key, button) in self.buttons.iter().enumerate() {
+            let label = if self.capturing == Some(key) {
+                "Press a key…".to_owned()
+            } else if self.bindings.codes(key).is_empty() {
+                "(none)".to_owned()
+            } else {
+                self.bindings.codes(key).join(", ")
+            };
+            button.set_text_content(Some(&format!("{key:X}: {label}")));
+        }
+
+        if let Some(input_per_rom) = &self.input_per_rom {
+            input_per_rom.set_checked(self.is_per_rom);
+        }
+    }
+
+    pub fn start_capture(&mut self, key: usize) {
+        self.capturing = Some(key);
+        self.show();
+    }
+
+    /// Binds `code` to the key being captured. Escape cancels and Backspace unbinds every host
+    /// input from it. Returns whether a capture was in progress.
+    pub fn capture(&mut self, code: &str) -> bool {
+        let Some(key) = self.capturing.take() else {
+            return false;
+        };
+
+        match code {
+            "Escape" => (),
+            "Backspace" => self.bindings.clear(key),
+            _ => self.bindings.bind(key, code),
+        }
+        self.save();
+        self.show();
+
+        true
+    }
+
+    pub fn set_per_rom(&mut self, is_per_rom: bool) {
+        self.is_per_rom = is_per_rom;
+        if is_per_rom {
+            self.save();
+        } else {
+            storage::remove(&self.source.storage_key(Some(&self.rom_name)));
+            self.load(&self.rom_name.clone());
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.bindings = self.source.default_bindings(&self.rom_name);
+        self.capturing = None;
+        self.save();
+        self.show();
+    }
+
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.bindings).unwrap_throw()
+    }
+}
+
+/// Listens to the controls created by [`Remapper::new`] and the per-ROM checkbox.
+pub fn set_up_remapper_controls(remapper: &Rc<RefCell<Remapper>>) -> Vec<EventListener> {
+    let mut listeners = remapper
+        .borrow()
+        .buttons
+        .iter()
+        .enumerate()
+        .map(|(key, button)| {
+            let remapper = Rc::clone(remapper);
+            EventListener::new(button, "click", move |_| {
+                remapper.borrow_mut().start_capture(key);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(input_per_rom) = remapper.borrow().input_per_rom.clone() {
+        let remapper = Rc::clone(remapper);
+        listeners.push(EventListener::new(
+            &input_per_rom.clone(),
+            "change",
+            move |_| {
+                remapper.borrow_mut().set_per_rom(input_per_rom.checked());
+            },
+        ));
+    }
+
+    listeners
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_bindings_follow_the_keypad_layout() {
+        let bindings = KeyBindings::default();
+        assert_eq!(bindings.key_for_code("Digit1"), Some(0x1));
+        assert_eq!(bindings.key_for_code("KeyX"), Some(0x0));
+        assert_eq!(bindings.key_for_code("KeyV"), Some(0xF));
+        assert_eq!(bindings.key_for_code("Space"), None);
+    }
+
+    #[test]
+    fn rebinding_a_code_moves_it_off_its_old_key() {
+        let mut bindings = KeyBindings::default();
+        bindings.bind(0x5, "ArrowUp");
+        bindings.bind(0x5, "KeyQ");
+
+        assert_eq!(bindings.codes(0x5), ["KeyW", "ArrowUp", "KeyQ"]);
+        assert!(bindings.codes(0x4).is_empty());
+        assert_eq!(bindings.key_for_code("KeyQ"), Some(0x5));
+
+        bindings.bind(0x5, "KeyW");
+        assert_eq!(bindings.codes(0x5), ["ArrowUp", "KeyQ", "KeyW"]);
+
+        bindings.clear(0x5);
+        assert_eq!(bindings.key_for_code("ArrowUp"), None);
+    }
+
+    #[test]
+    fn json_round_trips_as_16_arrays() {
+        let mut bindings = KeyBindings::empty();
+        bindings.bind(0x0, "KeyX");
+        bindings.bind(0xF, "KeyV");
+        bindings.bind(0xF, "Enter");
+
+        let json = serde_json::to_string(&bindings).unwrap();
+        let expected = format!(r#"[["KeyX"],{}["KeyV","Enter"]]"#, "[],".repeat(14));
+        assert_eq!(json, expected);
+        assert_eq!(
+            serde_json::from_str::<KeyBindings>(&json).unwrap(),
+            bindings
+        );
+
+        assert!(serde_json::from_str::<KeyBindings>(r#"[["KeyX"]]"#).is_err());
+    }
+}
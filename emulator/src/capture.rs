@@ -0,0 +1,210 @@
+use crate::{
+    display::{HEIGHT, WIDTH},
+    palette::Palette,
+};
+use std::io::Write;
+
+/// A single frame of the display, in the same layout as [`crate::display::Framebuffer::rows`].
+pub type Frame = [u64; HEIGHT as usize];
+
+/// Recordings are captured once per 60Hz tick.
+const TICKS_PER_SECOND: u16 = 60;
+
+fn encoder<W: Write>(w: W, scale: u32, palette: &Palette) -> png::Encoder<'static, W> {
+    let mut encoder = png::Encoder::new(w, WIDTH * scale, HEIGHT * scale);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let Palette {
+        background,
+        foreground,
+    } = *palette;
+    encoder.set_palette(vec![
+        background.r,
+        background.g,
+        background.b,
+        foreground.r,
+        foreground.g,
+        foreground.b,
+    ]);
+
+    encoder
+}
+
+/// Converts `frame` to one palette index per pixel, with each CHIP-8 pixel scaled up to a
+/// `scale`x`scale` square.
+fn indexed_pixels(frame: &Frame, scale: u32) -> Vec<u8> {
+    let scale = scale as usize;
+    let mut pixels = Vec::with_capacity(WIDTH as usize * HEIGHT as usize * scale * scale);
+
+    for row in frame {
+        let start = pixels.len();
+        for x in 0..WIDTH {
+            let index = ((row >> (WIDTH - 1 - x)) & 1) as u8;
+            pixels.extend(std::iter::repeat_n(index, scale));
+        }
+
+        for _ in 1..scale {
+            pixels.extend_from_within(start..start + WIDTH as usize * scale);
+        }
+    }
+
+    pixels
+}
+
+/// Encodes `frame` as a PNG.
+///
+/// # Errors
+/// Returns an error if `scale` is 0 or too large for a PNG.
+pub fn encode_png(
+    frame: &Frame,
+    scale: u32,
+    palette: &Palette,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let mut buf = Vec::new();
+
+    let mut writer = encoder(&mut buf, scale, palette).write_header()?;
+    writer.write_image_data(&indexed_pixels(frame, scale))?;
+    writer.finish()?;
+
+    Ok(buf)
+}
+
+/// Records the display over time for export as an animated PNG.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    /// Each distinct frame along with how many ticks it was shown for.
+    frames: Vec<(Frame, u16)>,
+}
+
+impl Recorder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Appends the next frame. Should be called once per 60Hz tick.
+    pub fn capture(&mut self, frame: &Frame) {
+        match self.frames.last_mut() {
+            Some((last_frame, ticks)) if last_frame == frame && *ticks < u16::MAX => {
+                *ticks += 1;
+            }
+            _ => self.frames.push((*frame, 1)),
+        }
+    }
+
+    /// Encodes the recording as an animated PNG that loops forever.
+    ///
+    /// # Errors
+    /// Returns an error if nothing was recorded, or if `scale` is 0 or too large for a PNG.
+    pub fn encode_apng(
+        &self,
+        scale: u32,
+        palette: &Palette,
+    ) -> Result<Vec<u8>, png::EncodingError> {
+        let mut buf = Vec::new();
+
+        let mut encoder = encoder(&mut buf, scale, palette);
+        encoder.set_animated(self.frames.len() as u32, 0)?;
+
+        let mut writer = encoder.write_header()?;
+        for (frame, ticks) in &self.frames {
+            writer.set_frame_delay(*ticks, TICKS_PER_SECOND)?;
+            writer.write_image_data(&indexed_pixels(frame, scale))?;
+        }
+        writer.finish()?;
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::{Color, THEMES};
+
+    /// Decodes every frame of `png` into one palette index per pixel, along with its size.
+    fn decode(png: &[u8]) -> (u32, u32, Vec<Vec<u8>>) {
+        let mut reader = png::Decoder::new(std::io::Cursor::new(png))
+            .read_info()
+            .unwrap();
+        let mut frames = Vec::new();
+        let mut buf = vec![0; reader.output_buffer_size().unwrap()];
+        while let Ok(info) = reader.next_frame(&mut buf) {
+            frames.push(buf[..info.buffer_size()].to_vec());
+        }
+        let info = reader.info();
+        (info.width, info.height, frames)
+    }
+
+    #[test]
+    fn png_scales_each_pixel_up_to_a_square() {
+        let mut frame = [0; HEIGHT as usize];
+        // The top left and bottom right pixels
+        frame[0] = 1 << 63;
+        frame[HEIGHT as usize - 1] = 1;
+        let palette = THEMES[1].1;
+
+        let png = encode_png(&frame, 2, &palette).unwrap();
+        let (width, height, frames) = decode(&png);
+        assert_eq!((width, height), (WIDTH * 2, HEIGHT * 2));
+
+        let pixels = &frames[0];
+        let lit = (0..pixels.len())
+            .filter(|&i| pixels[i] == 1)
+            .map(|i| (i as u32 % width, i as u32 / width))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lit,
+            [
+                (0, 0),
+                (1, 0),
+                (0, 1),
+                (1, 1),
+                (126, 62),
+                (127, 62),
+                (126, 63),
+                (127, 63)
+            ]
+        );
+
+        let reader = png::Decoder::new(std::io::Cursor::new(&png))
+            .read_info()
+            .unwrap();
+        let colors = reader.info().palette.as_deref().unwrap().to_vec();
+        let Palette {
+            background: Color { r, g, b },
+            foreground,
+        } = palette;
+        assert_eq!(colors, [r, g, b, foreground.r, foreground.g, foreground.b]);
+    }
+
+    #[test]
+    fn png_rejects_a_scale_of_0() {
+        assert!(encode_png(&[0; HEIGHT as usize], 0, &Palette::default()).is_err());
+    }
+
+    #[test]
+    fn recording_keeps_each_distinct_frame_once() {
+        let mut recorder = Recorder::new();
+        let blank = [0; HEIGHT as usize];
+        let mut lit = blank;
+        lit[5] = u64::MAX;
+        for frame in [blank, blank, blank, lit, blank] {
+            recorder.capture(&frame);
+        }
+        assert_eq!(recorder.frames, [(blank, 3), (lit, 1), (blank, 1)]);
+
+        let (_, _, frames) = decode(&recorder.encode_apng(1, &Palette::default()).unwrap());
+        assert_eq!(frames.len(), 3);
+        assert!(frames[1][5 * WIDTH as usize..6 * WIDTH as usize]
+            .iter()
+            .all(|&index| index == 1));
+        assert!(Recorder::new().encode_apng(1, &Palette::default()).is_err());
+    }
+}
@@ -1,13 +1,20 @@
-use crate::{keypad::Keypad, opcode::Opcode, view::View};
-use std::{cell::RefCell, rc::Rc};
+use crate::{display::Framebuffer, keypad::Keypad, opcode::Opcode, quirks::Quirks};
+use rand::SeedableRng;
+use rand_pcg::Pcg32;
+use std::mem;
 
-const TOTAL_MEMORY_BYTES: usize = 4096;
-const REGISTER_COUNT: usize = 16;
-const STACK_SIZE: usize = 16;
+pub const TOTAL_MEMORY_BYTES: usize = 4096;
+pub const REGISTER_COUNT: usize = 16;
+pub const STACK_SIZE: usize = 16;
 const PROGRAM_START_ADDRESS: u16 = 0x200;
+/// Largest ROM that fits in memory after the interpreter area.
+pub const MAX_ROM_BYTES: usize = TOTAL_MEMORY_BYTES - PROGRAM_START_ADDRESS as usize;
+pub const CYCLES_PER_FRAME: u8 = 10;
 
 #[derive(Debug)]
 pub struct Cpu {
+    /// Code that writes here directly, rather than through instructions, must call
+    /// [`Self::invalidate_decoded`] afterwards.
     pub memory: [u8; TOTAL_MEMORY_BYTES],
 
     pub regs: [u8; REGISTER_COUNT],
@@ -23,8 +30,22 @@ pub struct Cpu {
     pub delay_timer: u8,
     pub sound_timer: u8,
 
-    pub view: View,
-    pub keypad: Rc<RefCell<Keypad>>,
+    pub framebuffer: Framebuffer,
+    pub keypad: Keypad,
+
+    pub quirks: Quirks,
+    /// The loaded program, kept so that it can be restarted.
+    pub rom: Vec<u8>,
+
+    /// Seeds `rng` whenever the program starts, so that a replay of the same input plays out
+    /// the same way.
+    pub seed: u64,
+    /// Source of `Cxkk`'s random bytes. Pcg32 gives the same sequence on every platform.
+    pub rng: Pcg32,
+
+    /// The instruction at each address, decoded the first time it runs and forgotten when memory
+    /// under it is written, as some programs modify their own code.
+    decoded: Box<[Option<Opcode>]>,
 }
 
 const FONTSET: [u8; 80] = [
@@ -50,7 +71,12 @@ const _: () = assert!(FONTSET.len() <= PROGRAM_START_ADDRESS as usize);
 
 impl Cpu {
     #[must_use]
-    pub fn new(rom_buf: &[u8], view: View, keypad: Rc<RefCell<Keypad>>) -> Self {
+    pub fn new(rom_buf: &[u8]) -> Self {
+        Self::with_seed(rom_buf, rand::random())
+    }
+
+    #[must_use]
+    pub fn with_seed(rom_buf: &[u8], seed: u64) -> Self {
         let mut cpu = Self {
             memory: [0; TOTAL_MEMORY_BYTES],
 
@@ -64,8 +90,16 @@ impl Cpu {
             delay_timer: 0,
             sound_timer: 0,
 
-            view,
-            keypad,
+            framebuffer: Framebuffer::new(),
+            keypad: Keypad::new(),
+
+            quirks: Quirks::default(),
+            rom: rom_buf.to_vec(),
+
+            seed,
+            rng: Pcg32::seed_from_u64(seed),
+
+            decoded: vec![None; TOTAL_MEMORY_BYTES].into_boxed_slice(),
         };
 
         // Store font data before `PROGRAM_START_ADDRESS`.
@@ -88,13 +122,74 @@ impl Cpu {
         // Fill memory from `PROGRAM_START_ADDRESS`.
         self.memory[PROGRAM_START_ADDRESS as usize..PROGRAM_START_ADDRESS as usize + program.len()]
             .copy_from_slice(program);
+        self.invalidate_decoded(PROGRAM_START_ADDRESS, program.len());
+    }
+
+    /// Forgets decoded instructions that overlap the `len` bytes of memory at `address`, after
+    /// they were written.
+    pub fn invalidate_decoded(&mut self, address: u16, len: usize) {
+        // The instruction starting one byte earlier overlaps the first byte written
+        let start = (address as usize).saturating_sub(1).min(self.decoded.len());
+        let end = (address as usize + len).min(self.decoded.len());
+        self.decoded[start..end].fill(None);
+    }
+
+    /// Restarts the random number sequence from `seed`.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Pcg32::seed_from_u64(seed);
+    }
+
+    /// Restarts the loaded program from a clean machine state, with the same seed. Keys held down
+    /// stay down.
+    pub fn reset(&mut self) {
+        let rom = mem::take(&mut self.rom);
+        let keypad = mem::take(&mut self.keypad);
+        let quirks = self.quirks;
+        *self = Self::with_seed(&rom, self.seed);
+        self.keypad = keypad;
+        // The previous program may have been waiting on `Fx0A`, and its input isn't for this one
+        self.keypad.cancel_wait();
+        self.keypad.apply_all_events();
+        self.quirks = quirks;
     }
 
+    /// Runs one instruction, decoding it only if it hasn't run since its memory last changed.
     pub fn cycle(&mut self) {
+        let pc = self.pc as usize;
+        let opcode = match self.decoded.get(pc) {
+            Some(&Some(opcode)) => opcode,
+            _ => {
+                let opcode = Opcode::from(self.fetch_opcode());
+                self.decoded[pc] = Some(opcode);
+                opcode
+            }
+        };
+
+        self.push_pc();
+        opcode.execute(self);
+    }
+
+    /// Runs one instruction like [`Self::cycle`], but decodes it afresh every time. This is the
+    /// reference that the decoded instruction cache is checked against.
+    pub fn cycle_uncached(&mut self) {
         let opcode = self.fetch_opcode();
         self.decode_and_execute_opcode(opcode);
     }
 
+    /// Runs a single 60Hz frame: applies queued input, executes [`CYCLES_PER_FRAME`]
+    /// instructions and counts the timers down.
+    pub fn run_frame(&mut self) {
+        self.keypad.process_events();
+
+        for _ in 0..CYCLES_PER_FRAME {
+            self.cycle();
+        }
+
+        // Timers should update at 60Hz
+        self.update_timers();
+    }
+
     fn fetch_opcode(&self) -> u16 {
         assert!(
             (self.pc as usize) < TOTAL_MEMORY_BYTES - 1,
@@ -130,3 +225,107 @@ impl Cpu {
         self.pc -= 2;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roms::ROMS_BY_NAME;
+    use rand::Rng;
+    use std::panic::{self, AssertUnwindSafe};
+
+    const SEEDS: u64 = 2;
+    /// 20 seconds of each game, which keeps the test quick in debug builds.
+    const FRAMES: u32 = 20 * 60;
+    /// Frames between changes of the keys held.
+    const INPUT_INTERVAL: u32 = 15;
+
+    /// Adds 1 to V2, then rewrites that instruction with `Fx55` to add 5 instead and runs it
+    /// again, leaving V2 = 6. A stale cache would leave 2.
+    const SELF_MODIFYING: &[u8] = &[
+        0x60, 0x72, // 200: V0 = 0x72
+        0x61, 0x05, // 202: V1 = 0x05
+        0xA2, 0x10, // 204: I = 0x210
+        0x63, 0x00, // 206: V3 = 0
+        0x33, 0x02, // 208: Skip if V3 = 2
+        0x12, 0x0E, // 20A: Jump to 20E
+        0x12, 0x0C, // 20C: Halt
+        0x73, 0x01, // 20E: V3 += 1
+        0x72, 0x01, // 210: V2 += 1, rewritten to V2 += 5
+        0xF1, 0x55, // 212: Store V0 and V1 at 210
+        0x12, 0x08, // 214: Jump to 208
+    ];
+
+    /// [`Cpu::run_frame`], with the reference interpreter.
+    fn run_frame_uncached(cpu: &mut Cpu) {
+        cpu.keypad.process_events();
+        for _ in 0..CYCLES_PER_FRAME {
+            cpu.cycle_uncached();
+        }
+        cpu.update_timers();
+    }
+
+    /// Runs a frame, returning `None` if the program crashed the CPU.
+    fn try_frame(cpu: &mut Cpu, run_frame: fn(&mut Cpu)) -> Option<u64> {
+        panic::catch_unwind(AssertUnwindSafe(|| run_frame(cpu)))
+            .ok()
+            .map(|()| cpu.state_hash())
+    }
+
+    /// Plays `rom` with random keys on both interpreters, and returns the first frame at which
+    /// they differ.
+    fn compare(rom: &[u8], seed: u64) -> Option<u32> {
+        let mut cached = Cpu::with_seed(rom, seed);
+        let mut reference = Cpu::with_seed(rom, seed);
+        let mut input = Pcg32::seed_from_u64(seed);
+
+        for frame in 0..FRAMES {
+            if frame.is_multiple_of(INPUT_INTERVAL) {
+                let keys = input.random::<u16>() & input.random::<u16>();
+                cached.keypad.hold_keys(keys);
+                reference.keypad.hold_keys(keys);
+            }
+
+            let hash = try_frame(&mut cached, Cpu::run_frame);
+            if hash != try_frame(&mut reference, run_frame_uncached) {
+                return Some(frame);
+            }
+            // Both crashing the same way ends the game
+            hash?;
+        }
+        None
+    }
+
+    #[test]
+    fn decoded_instructions_run_every_bundled_rom_like_the_reference() {
+        let mut roms = ROMS_BY_NAME.iter().collect::<Vec<_>>();
+        roms.sort();
+
+        let differences = roms
+            .into_iter()
+            .flat_map(|(name, rom)| (0..SEEDS).map(move |seed| (name, rom, seed)))
+            .filter_map(|(name, rom, seed)| {
+                let frame = compare(rom, seed)?;
+                Some(format!("{name} with seed {seed} differs at frame {frame}"))
+            })
+            .collect::<Vec<_>>();
+        assert!(differences.is_empty(), "{differences:#?}");
+    }
+
+    #[test]
+    fn self_modifying_code_is_decoded_again() {
+        let run = |cpu: &mut Cpu| {
+            for _ in 0..10 {
+                cpu.run_frame();
+            }
+            cpu.regs[2]
+        };
+
+        let mut cpu = Cpu::with_seed(SELF_MODIFYING, 0);
+        let start = cpu.save_state();
+        assert_eq!(run(&mut cpu), 6);
+
+        // Loading a state replaces all of memory, so nothing decoded before it may be used after
+        cpu.load_state(&start).unwrap();
+        assert_eq!(run(&mut cpu), 6);
+    }
+}
@@ -0,0 +1,231 @@
+pub const WIDTH: u32 = 64;
+pub const HEIGHT: u32 = 32;
+
+/// Region of the display that changed since it was last presented.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DirtyRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DirtyRect {
+    pub const FULL: Self = Self {
+        x: 0,
+        y: 0,
+        width: WIDTH,
+        height: HEIGHT,
+    };
+
+    fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+
+        Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+/// The monochrome 64x32 CHIP-8 display.
+///
+/// Each row is stored as a bitmask, with the leftmost pixel in the most significant bit.
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    rows: [u64; HEIGHT as usize],
+    dirty: Option<DirtyRect>,
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self {
+            rows: [0; HEIGHT as usize],
+            dirty: Some(DirtyRect::FULL),
+        }
+    }
+}
+
+impl Framebuffer {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a framebuffer showing `rows`, e.g. when restoring a saved state.
+    #[must_use]
+    pub fn from_rows(rows: [u64; HEIGHT as usize]) -> Self {
+        Self {
+            rows,
+            dirty: Some(DirtyRect::FULL),
+        }
+    }
+
+    #[must_use]
+    pub fn rows(&self) -> &[u64; HEIGHT as usize] {
+        &self.rows
+    }
+
+    #[must_use]
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        (self.rows[y as usize] >> (WIDTH - 1 - x)) & 1 == 1
+    }
+
+    /// XORs `sprite` onto the display at (`sx`, `sy`), either wrapping or clipping at the edges.
+    /// Returns whether any pixel was turned off.
+    pub fn draw_sprite(&mut self, sprite: &[u8], sx: u32, sy: u32, wrap: bool) -> bool {
+        let sx = sx % WIDTH;
+        let sy = sy % HEIGHT;
+
+        let (x_count, y_count) = if wrap {
+            (8, (sprite.len() as u32).min(HEIGHT))
+        } else {
+            (8.min(WIDTH - sx), (sprite.len() as u32).min(HEIGHT - sy))
+        };
+
+        let mut collision = false;
+        for (iy, &byte) in sprite.iter().take(y_count as usize).enumerate() {
+            let bits = (byte as u64) << (WIDTH - 8);
+            let bits = if wrap {
+                bits.rotate_right(sx)
+            } else {
+                bits >> sx
+            };
+            let row = &mut self.rows[(sy as usize + iy) % HEIGHT as usize];
+
+            collision |= *row & bits != 0;
+            *row ^= bits;
+        }
+
+        let dirty = if sx + x_count > WIDTH || sy + y_count > HEIGHT {
+            DirtyRect::FULL
+        } else {
+            DirtyRect {
+                x: sx,
+                y: sy,
+                width: x_count,
+                height: y_count,
+            }
+        };
+        self.mark_dirty(dirty);
+
+        collision
+    }
+
+    pub fn clear(&mut self) {
+        self.rows = [0; HEIGHT as usize];
+        self.mark_dirty(DirtyRect::FULL);
+    }
+
+    fn mark_dirty(&mut self, rect: DirtyRect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect,
+        });
+    }
+
+    /// Returns the region changed since the last call, if any.
+    pub fn take_dirty(&mut self) -> Option<DirtyRect> {
+        self.dirty.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit_pixels(framebuffer: &Framebuffer) -> Vec<(u32, u32)> {
+        (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+            .filter(|&(x, y)| framebuffer.pixel(x, y))
+            .collect()
+    }
+
+    #[test]
+    fn rows_hold_the_leftmost_pixel_in_the_top_bit() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.draw_sprite(&[0b1000_0001], 0, 0, false);
+        framebuffer.draw_sprite(&[0b0000_0001], 56, 1, false);
+
+        assert_eq!(framebuffer.rows()[0], 0x8100_0000_0000_0000);
+        assert_eq!(framebuffer.rows()[1], 1);
+        assert_eq!(lit_pixels(&framebuffer), [(0, 0), (7, 0), (63, 1)]);
+    }
+
+    #[test]
+    fn sprites_wrap_around_both_edges() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.take_dirty();
+        framebuffer.draw_sprite(&[0b1100_0011, 0b1000_0000], 62, 31, true);
+
+        assert_eq!(
+            lit_pixels(&framebuffer),
+            [(62, 0), (4, 31), (5, 31), (62, 31), (63, 31)]
+        );
+        assert_eq!(framebuffer.take_dirty(), Some(DirtyRect::FULL));
+    }
+
+    #[test]
+    fn sprites_are_clipped_at_the_edges() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.take_dirty();
+        framebuffer.draw_sprite(&[0b1100_0011, 0b1000_0000], 62, 31, false);
+
+        assert_eq!(lit_pixels(&framebuffer), [(62, 31), (63, 31)]);
+        assert_eq!(
+            framebuffer.take_dirty(),
+            Some(DirtyRect {
+                x: 62,
+                y: 31,
+                width: 2,
+                height: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn positions_past_the_display_start_again_from_the_top_left() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.draw_sprite(&[0b1000_0000], WIDTH + 3, HEIGHT + 2, false);
+
+        assert_eq!(lit_pixels(&framebuffer), [(3, 2)]);
+    }
+
+    #[test]
+    fn drawing_over_lit_pixels_turns_them_off_and_reports_it() {
+        let mut framebuffer = Framebuffer::new();
+        assert!(!framebuffer.draw_sprite(&[0b1100_0000], 10, 5, false));
+        assert!(!framebuffer.draw_sprite(&[0b0010_0000], 10, 5, false));
+        assert!(framebuffer.draw_sprite(&[0b0110_0000], 10, 5, false));
+
+        assert_eq!(lit_pixels(&framebuffer), [(10, 5)]);
+    }
+
+    #[test]
+    fn dirty_regions_grow_to_cover_every_change_until_taken() {
+        let mut framebuffer = Framebuffer::new();
+        assert_eq!(framebuffer.take_dirty(), Some(DirtyRect::FULL));
+        assert_eq!(framebuffer.take_dirty(), None);
+
+        framebuffer.draw_sprite(&[0xff; 3], 4, 10, false);
+        framebuffer.draw_sprite(&[0xff; 2], 20, 2, false);
+        assert_eq!(
+            framebuffer.take_dirty(),
+            Some(DirtyRect {
+                x: 4,
+                y: 2,
+                width: 24,
+                height: 11,
+            })
+        );
+
+        framebuffer.draw_sprite(&[0xff], 0, 0, false);
+        framebuffer.clear();
+        assert_eq!(framebuffer.take_dirty(), Some(DirtyRect::FULL));
+    }
+}
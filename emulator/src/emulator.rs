@@ -0,0 +1,251 @@
+use crate::{
+    cpu::Cpu,
+    jit::Jit,
+    keypad::{self, KeyInput},
+    quirks::Quirks,
+    replay::Replay,
+    state::StateError,
+    view::View,
+};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, rc::Rc};
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum EmulatorState {
+    /// No ROM has been loaded yet.
+    Stopped,
+    Running,
+    Paused,
+}
+
+/// Runs a [`Cpu`] at 60Hz, and controls when it runs.
+#[derive(Debug)]
+pub struct Emulator {
+    cpu: Option<Cpu>,
+    /// Where the CPU's keys come from.
+    input: Rc<RefCell<KeyInput>>,
+    state: EmulatorState,
+    /// Applied to every ROM loaded from now on, as well as the current one.
+    quirks: Quirks,
+    /// Whether the emulator was paused because the page was hidden, rather than by the user, and
+    /// so should resume once the page is visible again.
+    is_auto_paused: bool,
+    /// The game since the program started, or `None` if something a replay can't reproduce has
+    /// happened since, like loading a state.
+    recording: Option<Replay>,
+    /// Runs hot code as compiled WebAssembly when on, rather than interpreting every instruction.
+    jit: Option<Jit>,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self {
+            cpu: None,
+            input: Rc::default(),
+            state: EmulatorState::Stopped,
+            quirks: Quirks::default(),
+            is_auto_paused: false,
+            recording: None,
+            jit: None,
+        }
+    }
+}
+
+impl Emulator {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn state(&self) -> EmulatorState {
+        self.state
+    }
+
+    #[must_use]
+    pub fn cpu(&self) -> Option<&Cpu> {
+        self.cpu.as_ref()
+    }
+
+    #[must_use]
+    pub fn cpu_mut(&mut self) -> Option<&mut Cpu> {
+        self.cpu.as_mut()
+    }
+
+    /// Loads `rom_buf` and starts running it, with keys from `input`. Only the keys already held
+    /// carry over from before.
+    pub fn load_rom(&mut self, rom_buf: &[u8], input: Rc<RefCell<KeyInput>>) {
+        input.borrow_mut().keypad.apply_all_events();
+        let mut cpu = Cpu::new(rom_buf);
+        cpu.quirks = self.quirks;
+        self.recording = Some(Replay::start(&mut cpu));
+        self.cpu = Some(cpu);
+        self.input = input;
+        self.state = EmulatorState::Running;
+        self.is_auto_paused = false;
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+        if let Some(cpu) = &mut self.cpu {
+            cpu.quirks = quirks;
+            self.stop_recording();
+        }
+    }
+
+    /// Takes the CPU's keys from `input` from now on.
+    pub fn set_input(&mut self, input: Rc<RefCell<KeyInput>>) {
+        self.input = input;
+    }
+
+    /// Turns the JIT on or off. It plays exactly like the interpreter, so a recording carries on.
+    pub fn set_jit(&mut self, is_on: bool) {
+        if is_on != self.jit.is_some() {
+            self.jit = is_on.then(Jit::new);
+        }
+    }
+
+    /// Stops recording the game, e.g. because it isn't played with the local keypad.
+    pub fn stop_recording(&mut self) {
+        if let Some(cpu) = &mut self.cpu {
+            cpu.keypad.stop_log();
+        }
+        self.recording = None;
+    }
+
+    /// The game so far, encoded with [`Replay::encode`], or `None` if it can't be replayed.
+    #[must_use]
+    pub fn replay(&self) -> Option<Vec<u8>> {
+        Some(self.recording.as_ref()?.encode(self.cpu.as_ref()?))
+    }
+
+    /// Restores a save state, or returns `None` if no ROM is loaded. Stops recording the game.
+    pub fn load_state(&mut self, state: &[u8]) -> Option<Result<(), StateError>> {
+        self.stop_recording();
+        Some(self.cpu.as_mut()?.load_state(state))
+    }
+
+    /// Restarts the current ROM from the beginning.
+    pub fn reset(&mut self) {
+        if let Some(cpu) = &mut self.cpu {
+            self.input.borrow_mut().keypad.apply_all_events();
+            cpu.reset();
+            self.recording = Some(Replay::start(cpu));
+            self.state = EmulatorState::Running;
+            self.is_auto_paused = false;
+        }
+    }
+
+    pub fn pause(&mut self) {
+        if self.state == EmulatorState::Running {
+            self.state = EmulatorState::Paused;
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.state == EmulatorState::Paused {
+            self.state = EmulatorState::Running;
+            self.is_auto_paused = false;
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        match self.state {
+            EmulatorState::Running => self.pause(),
+            EmulatorState::Paused => self.resume(),
+            EmulatorState::Stopped => (),
+        }
+    }
+
+    /// Executes a single instruction while paused, without advancing the timers. Replays only
+    /// have whole frames, so this stops recording the game.
+    pub fn step(&mut self) {
+        if self.state != EmulatorState::Paused {
+            return;
+        }
+        self.stop_recording();
+
+        if let Some(cpu) = &mut self.cpu {
+            Self::take_input(cpu, &self.input);
+            cpu.keypad.process_events();
+            cpu.cycle();
+        }
+    }
+
+    /// Runs a single 60Hz tick while paused, without presenting the display.
+    pub fn step_frame(&mut self, view: &mut View) {
+        if self.state != EmulatorState::Paused {
+            return;
+        }
+
+        if let Some(cpu) = &mut self.cpu {
+            Self::tick(
+                cpu,
+                &self.input,
+                self.recording.as_mut(),
+                self.jit.as_mut(),
+                view,
+            );
+        }
+    }
+
+    /// Queues presses and releases on the CPU's keypad to catch up with `input`. Like the CPU,
+    /// the input changes each key at most once a tick, so a quick tap is still seen.
+    fn take_input(cpu: &mut Cpu, input: &RefCell<KeyInput>) {
+        let mut input = input.borrow_mut();
+        input.keypad.process_events();
+        cpu.keypad
+            .hold_keys(keypad::key_mask(&input.keypad.key_states));
+    }
+
+    fn tick(
+        cpu: &mut Cpu,
+        input: &RefCell<KeyInput>,
+        recording: Option<&mut Replay>,
+        jit: Option<&mut Jit>,
+        view: &mut View,
+    ) {
+        Self::take_input(cpu, input);
+        if let Some(replay) = recording {
+            replay.record_frame(cpu);
+        }
+        match jit {
+            Some(jit) => jit.run_frame(cpu),
+            None => cpu.run_frame(),
+        }
+
+        view.sample(&cpu.framebuffer);
+    }
+
+    /// Pauses while the page is hidden, and resumes once it is visible again unless the user
+    /// paused in the meantime.
+    pub fn set_page_hidden(&mut self, is_hidden: bool) {
+        if is_hidden && self.state == EmulatorState::Running {
+            self.pause();
+            self.is_auto_paused = true;
+        } else if !is_hidden && self.is_auto_paused {
+            self.resume();
+        }
+    }
+
+    /// Runs `ticks` 60Hz ticks if running, then presents the display.
+    pub fn run(&mut self, ticks: u32, view: &mut View) {
+        let Some(cpu) = &mut self.cpu else {
+            return;
+        };
+
+        if self.state == EmulatorState::Running {
+            for _ in 0..ticks {
+                Self::tick(
+                    cpu,
+                    &self.input,
+                    self.recording.as_mut(),
+                    self.jit.as_mut(),
+                    view,
+                );
+            }
+        }
+
+        view.render(&mut cpu.framebuffer);
+    }
+}
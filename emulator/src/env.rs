@@ -0,0 +1,417 @@
+use crate::{
+    cpu::Cpu,
+    display::{HEIGHT, WIDTH},
+    quirks::Quirks,
+    roms::{self, ScoreSource, ROMS_BY_NAME},
+    stream::Rows,
+};
+
+/// The display unpacked by [`unpack`], one byte per pixel.
+pub type Pixels = [u8; (WIDTH * HEIGHT) as usize];
+
+/// What an agent sees after a step.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Transition {
+    pub observation: Rows,
+    /// How much the score went up, or 0 for games without a known score.
+    pub reward: i32,
+    /// Whether the game is over: the last life was lost, or the program stopped by jumping to
+    /// itself.
+    pub done: bool,
+}
+
+/// A game as a reinforcement learning environment in the style of Gym.
+///
+/// Observations are the display, as [`crate::display::Framebuffer::rows`]. An action is a set of
+/// the game's keys to hold down: action `n` holds `keys()[i]` for every bit `i` set in `n`, so
+/// action 0 presses nothing.
+#[derive(Debug)]
+pub struct Env {
+    rom: Vec<u8>,
+    keys: Vec<u8>,
+    score: Option<ScoreSource>,
+    lives: Option<u8>,
+    /// Applied on the next [`Self::reset`].
+    pub quirks: Quirks,
+    /// Frames each step runs with the same keys held, e.g. 4 to act at 15Hz.
+    pub frame_skip: u32,
+    cpu: Cpu,
+    last_score: u32,
+    last_lives: u8,
+    done: bool,
+}
+
+impl Env {
+    /// An environment for the bundled ROM `rom_name`, with the score, lives and keys known for
+    /// it, or `None` if there is no such ROM.
+    #[must_use]
+    pub fn new(rom_name: &str) -> Option<Self> {
+        let rom = ROMS_BY_NAME.get(rom_name)?;
+        let info = roms::rom_info(rom_name);
+
+        let mut env = Self::with_program(rom, info.keys);
+        env.score = info.score;
+        env.lives = info.lives;
+        env.reset(0);
+        Some(env)
+    }
+
+    /// An environment for any program, whose actions press `keys`. Without a known score, the
+    /// reward is always 0.
+    ///
+    /// # Panics
+    /// Panics if the program does not fit into memory, or there are more than 16 keys.
+    #[must_use]
+    pub fn with_program(program: &[u8], keys: &[u8]) -> Self {
+        assert!(keys.len() <= 16, "At most 16 keys can be pressed");
+
+        let cpu = Cpu::with_seed(program, 0);
+        let mut env = Self {
+            rom: program.to_vec(),
+            keys: keys.to_vec(),
+            score: None,
+            lives: None,
+            quirks: Quirks::default(),
+            frame_skip: 1,
+            cpu,
+            last_score: 0,
+            last_lives: 0,
+            done: false,
+        };
+        env.reset(0);
+        env
+    }
+
+    /// The keys that actions press.
+    #[must_use]
+    pub fn keys(&self) -> &[u8] {
+        &self.keys
+    }
+
+    #[must_use]
+    pub fn action_count(&self) -> usize {
+        1 << self.keys.len()
+    }
+
+    /// Restarts the program, with the random number sequence started from `seed`.
+    pub fn reset(&mut self, seed: u64) -> Rows {
+        self.cpu = Cpu::with_seed(&self.rom, seed);
+        self.cpu.quirks = self.quirks;
+        self.last_score = self.score();
+        self.last_lives = self.lives();
+        self.done = false;
+        *self.observation()
+    }
+
+    /// Holds the keys of `action` for [`Self::frame_skip`] frames, or until the game is over.
+    /// Once it is, the game stays as it is until [`Self::reset`].
+    ///
+    /// # Panics
+    /// Panics if `action` is not less than [`Self::action_count`], or the program crashes the
+    /// CPU.
+    pub fn step(&mut self, action: usize) -> Transition {
+        assert!(
+            action < self.action_count(),
+            "Action {action} is out of range"
+        );
+
+        if !self.done {
+            let mask = self
+                .keys
+                .iter()
+                .enumerate()
+                .filter(|&(bit, _)| action & (1 << bit) != 0)
+                .fold(0, |mask, (_, &key)| mask | (1 << key));
+            self.cpu.keypad.hold_keys(mask);
+
+            for _ in 0..self.frame_skip.max(1) {
+                self.cpu.run_frame();
+                self.done = self.is_over();
+                if self.done {
+                    break;
+                }
+            }
+        }
+
+        let score = self.score();
+        let reward = score as i32 - self.last_score as i32;
+        self.last_score = score;
+        Transition {
+            observation: *self.observation(),
+            reward,
+            done: self.done,
+        }
+    }
+
+    fn is_over(&mut self) -> bool {
+        let lives = self.lives();
+        let lost_last_life = self.last_lives > 0 && lives == 0;
+        self.last_lives = lives;
+
+        // `1nnn` to its own address is how most games stop for good
+        let pc = self.cpu.pc as usize;
+        let is_halted =
+            self.cpu.memory.get(pc..pc + 2).is_some_and(|opcode| {
+                u16::from_be_bytes([opcode[0], opcode[1]]) == 0x1000 | pc as u16
+            });
+
+        lost_last_life || is_halted
+    }
+
+    #[must_use]
+    pub fn observation(&self) -> &Rows {
+        self.cpu.framebuffer.rows()
+    }
+
+    /// The current score, or 0 for games without a known score.
+    #[must_use]
+    pub fn score(&self) -> u32 {
+        self.score.map_or(0, |score| score.read(&self.cpu.memory))
+    }
+
+    /// The lives left, or 0 for games without known lives.
+    #[must_use]
+    pub fn lives(&self) -> u8 {
+        self.lives
+            .map_or(0, |register| self.cpu.regs[register as usize])
+    }
+
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    #[must_use]
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+}
+
+/// Unpacks `rows` into `pixels`, one byte per pixel and row by row, 1 where the pixel is on, e.g.
+/// as the input of a neural network.
+pub fn unpack(rows: &Rows, pixels: &mut Pixels) {
+    for (row, line) in rows.iter().zip(pixels.chunks_exact_mut(WIDTH as usize)) {
+        for (x, pixel) in line.iter_mut().enumerate() {
+            *pixel = ((row >> (WIDTH as usize - 1 - x)) & 1) as u8;
+        }
+    }
+}
+
+/// Many copies of an environment stepped together, e.g. to collect experience in batches.
+///
+/// Games that end are reset at the start of the next step, with the next seed in sequence, so
+/// the transition that ended them still shows how they ended.
+#[derive(Debug)]
+pub struct VecEnv {
+    envs: Vec<Env>,
+    next_seed: u64,
+}
+
+impl VecEnv {
+    /// # Panics
+    /// Panics if `envs` is empty.
+    #[must_use]
+    pub fn new(envs: Vec<Env>) -> Self {
+        assert!(!envs.is_empty(), "A VecEnv needs at least one environment");
+        Self { envs, next_seed: 0 }
+    }
+
+    /// `count` environments for the bundled ROM `rom_name`, or `None` if there is no such ROM.
+    #[must_use]
+    pub fn with_rom(rom_name: &str, count: usize, frame_skip: u32) -> Option<Self> {
+        let envs = (0..count.max(1))
+            .map(|_| {
+                Env::new(rom_name).map(|mut env| {
+                    env.frame_skip = frame_skip;
+                    env
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self::new(envs))
+    }
+
+    #[must_use]
+    pub fn envs(&self) -> &[Env] {
+        &self.envs
+    }
+
+    /// Resets every environment, the first with `seed` and each following one with the next
+    /// seed.
+    pub fn reset(&mut self, seed: u64) -> Vec<Rows> {
+        self.next_seed = seed;
+        (0..self.envs.len())
+            .map(|index| self.reset_env(index))
+            .collect()
+    }
+
+    fn reset_env(&mut self, index: usize) -> Rows {
+        let seed = self.next_seed;
+        self.next_seed = self.next_seed.wrapping_add(1);
+        self.envs[index].reset(seed)
+    }
+
+    /// Steps every environment with its action in `actions`, writing the transitions to
+    /// `transitions` so that its allocation is reused.
+    ///
+    /// # Panics
+    /// Panics if there isn't one action per environment, or as [`Env::step`] does.
+    pub fn step(&mut self, actions: &[usize], transitions: &mut Vec<Transition>) {
+        assert_eq!(
+            actions.len(),
+            self.envs.len(),
+            "Need one action per environment"
+        );
+
+        transitions.clear();
+        for (index, &action) in actions.iter().enumerate() {
+            if self.envs[index].is_done() {
+                self.reset_env(index);
+            }
+            transitions.push(self.envs[index].step(action));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::CYCLES_PER_FRAME,
+        keypad::{KeyState, KEY_COUNT},
+        roms::ScoreEncoding,
+    };
+
+    /// Counts up in V1 and writes it to 0x300 as the score.
+    const COUNTER: &[u8] = &[0xA3, 0x00, 0x71, 0x01, 0xF1, 0x33, 0x12, 0x02];
+    /// Starts with 3 lives in VE and loses one every few frames.
+    const LOSING_LIVES: &[u8] = &[
+        0x6E, 0x03, 0x65, 0x0A, 0x75, 0xFF, 0x35, 0x00, 0x12, 0x04, 0x3E, 0x00, 0x7E, 0xFF, 0x12,
+        0x02,
+    ];
+    /// Stops by jumping to itself.
+    const HALTING: &[u8] = &[0x60, 0x01, 0x12, 0x02];
+
+    /// Plays `steps` steps of BRIX, moving the paddle back and forth, and returns every transition.
+    fn play_brix(env: &mut Env, seed: u64, steps: usize) -> Vec<Transition> {
+        env.reset(seed);
+        (0..steps).map(|step| env.step(1 + step / 20 % 2)).collect()
+    }
+
+    #[test]
+    fn resetting_with_a_seed_replays_the_same_game() {
+        let mut env = Env::new("BRIX").unwrap();
+        env.frame_skip = 4;
+        let first = play_brix(&mut env, 7, 300);
+        let hash = env.cpu().state_hash();
+
+        assert_eq!(play_brix(&mut env, 7, 300), first);
+        assert_eq!(env.cpu().state_hash(), hash);
+    }
+
+    #[test]
+    fn actions_hold_the_keys_of_their_bits() {
+        let mut env = Env::with_program(COUNTER, &[0x3, 0xA, 0x5]);
+        assert_eq!(env.action_count(), 8);
+
+        env.step(0b101);
+        let down = (0..KEY_COUNT)
+            .filter(|&key| env.cpu().keypad.key_states[key] == KeyState::Down)
+            .collect::<Vec<_>>();
+        assert_eq!(down, [0x3, 0x5]);
+    }
+
+    #[test]
+    fn steps_run_frame_skip_frames_and_reward_the_score_going_up() {
+        let mut env = Env::with_program(COUNTER, &[]);
+        env.score = Some(ScoreSource {
+            address: 0x300,
+            encoding: ScoreEncoding::Bcd { digits: 3 },
+        });
+        env.frame_skip = 3;
+        env.reset(0);
+
+        let mut last_score = 0;
+        for _ in 0..5 {
+            let transition = env.step(0);
+            assert!(transition.reward > 0);
+            assert_eq!(transition.reward, env.score() as i32 - last_score);
+            last_score = env.score() as i32;
+        }
+
+        // After setting I, each pass of the loop takes 3 instructions
+        let cycles = 5 * 3 * u32::from(CYCLES_PER_FRAME);
+        assert_eq!(env.score(), (cycles - 1).div_ceil(3));
+    }
+
+    #[test]
+    fn game_is_over_once_the_last_life_is_lost() {
+        let mut env = Env::with_program(LOSING_LIVES, &[]);
+        env.lives = Some(0xE);
+        env.reset(0);
+
+        let mut steps = 0;
+        while !env.step(0).done {
+            assert!(env.lives() > 0);
+            steps += 1;
+        }
+        assert_eq!(env.lives(), 0);
+        assert!(steps > 3);
+
+        // Nothing runs until the next reset
+        let hash = env.cpu().state_hash();
+        assert!(env.step(0).done);
+        assert_eq!(env.cpu().state_hash(), hash);
+        env.reset(0);
+        assert!(!env.step(0).done);
+    }
+
+    #[test]
+    fn game_is_over_when_it_jumps_to_itself() {
+        let mut env = Env::with_program(HALTING, &[]);
+        env.frame_skip = 10;
+        let transition = env.step(0);
+        assert!(transition.done);
+        assert_eq!(transition.reward, 0);
+        // The step stopped at the frame the program halted in
+        assert_eq!(env.cpu().pc, 0x202);
+    }
+
+    #[test]
+    fn vec_env_plays_like_envs_reset_one_at_a_time() {
+        let action = |step: usize, index: usize| (step + index) / 10 % 3;
+        let mut vec_env = VecEnv::with_rom("BRIX", 3, 8).unwrap();
+        vec_env.reset(10);
+        let mut envs = (0..3)
+            .map(|index| {
+                let mut env = Env::new("BRIX").unwrap();
+                env.frame_skip = 8;
+                env.reset(10 + index);
+                env
+            })
+            .collect::<Vec<_>>();
+
+        // Games that end are restarted with the seeds after the ones the environments started
+        // with, in the order they're stepped
+        let mut next_seed = 13;
+        let mut games_over = 0;
+        let mut transitions = Vec::new();
+        for step in 0..800 {
+            let actions = (0..3).map(|index| action(step, index)).collect::<Vec<_>>();
+            vec_env.step(&actions, &mut transitions);
+
+            for (index, env) in envs.iter_mut().enumerate() {
+                if env.is_done() {
+                    env.reset(next_seed);
+                    next_seed += 1;
+                    games_over += 1;
+                }
+                assert_eq!(env.step(action(step, index)), transitions[index]);
+            }
+        }
+
+        assert!(games_over > 0);
+        for (env, batched) in envs.iter().zip(vec_env.envs()) {
+            assert_eq!(env.cpu().state_hash(), batched.cpu().state_hash());
+        }
+    }
+}
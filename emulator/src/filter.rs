@@ -0,0 +1,174 @@
+use crate::display::{Framebuffer, HEIGHT, WIDTH};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// CHIP-8 games move sprites by XOR-ing them off and back on, so a sprite is often missing from
+/// every other frame. A phosphor filter hides that flicker by blending each pixel's history across
+/// frames. It only affects presentation; the framebuffer and collisions are left untouched.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Phosphor {
+    #[default]
+    Off,
+    /// Turned-off pixels keep `persistence` (between 0 and 1) of their brightness each frame, like
+    /// a CRT.
+    Decay { persistence: f32 },
+    /// A pixel is lit if it was on in any of the last `frames` frames.
+    FrameOr { frames: usize },
+}
+
+#[derive(Clone, Debug)]
+pub struct PhosphorFilter {
+    phosphor: Phosphor,
+    /// Brightness of each pixel, from 0 (background) to 255 (foreground).
+    levels: Vec<u8>,
+    history: VecDeque<[u64; HEIGHT as usize]>,
+}
+
+impl Default for PhosphorFilter {
+    fn default() -> Self {
+        Self {
+            phosphor: Phosphor::Off,
+            levels: vec![0; (WIDTH * HEIGHT) as usize],
+            history: VecDeque::new(),
+        }
+    }
+}
+
+impl PhosphorFilter {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.phosphor != Phosphor::Off
+    }
+
+    pub fn set_phosphor(&mut self, phosphor: Phosphor) {
+        if self.phosphor != phosphor {
+            *self = Self {
+                phosphor,
+                ..Self::default()
+            };
+        }
+    }
+
+    /// Blends in the next frame. Should be called once per 60Hz tick.
+    pub fn update(&mut self, framebuffer: &Framebuffer) {
+        match self.phosphor {
+            Phosphor::Off => (),
+            Phosphor::Decay { persistence } => {
+                for y in 0..HEIGHT {
+                    for x in 0..WIDTH {
+                        let level = &mut self.levels[(y * WIDTH + x) as usize];
+                        *level = if framebuffer.pixel(x, y) {
+                            u8::MAX
+                        } else {
+                            (*level as f32 * persistence) as u8
+                        };
+                    }
+                }
+            }
+            Phosphor::FrameOr { frames } => {
+                self.history.push_front(*framebuffer.rows());
+                self.history.truncate(frames.max(1));
+
+                for y in 0..HEIGHT {
+                    let row = self
+                        .history
+                        .iter()
+                        .fold(0, |acc, rows| acc | rows[y as usize]);
+                    for x in 0..WIDTH {
+                        let is_lit = (row >> (WIDTH - 1 - x)) & 1 == 1;
+                        self.levels[(y * WIDTH + x) as usize] = if is_lit { u8::MAX } else { 0 };
+                    }
+                }
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn level(&self, x: u32, y: u32) -> u8 {
+        self.levels[(y * WIDTH + x) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A display with only the pixel at (3, 2) lit, or none.
+    fn display(is_lit: bool) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new();
+        if is_lit {
+            framebuffer.draw_sprite(&[0b1000_0000], 3, 2, false);
+        }
+        framebuffer
+    }
+
+    /// The level of the pixel at (3, 2) after each of `frames`.
+    fn levels(filter: &mut PhosphorFilter, frames: &[bool]) -> Vec<u8> {
+        frames
+            .iter()
+            .map(|&is_lit| {
+                filter.update(&display(is_lit));
+                filter.level(3, 2)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_persistence_shows_each_frame_as_it_is() {
+        let mut filter = PhosphorFilter::new();
+        filter.set_phosphor(Phosphor::Decay { persistence: 0. });
+        assert_eq!(
+            levels(&mut filter, &[true, false, true, true, false]),
+            [255, 0, 255, 255, 0]
+        );
+        assert_eq!(filter.level(4, 2), 0);
+    }
+
+    #[test]
+    fn turned_off_pixels_fade_out() {
+        let mut filter = PhosphorFilter::new();
+        filter.set_phosphor(Phosphor::Decay { persistence: 0.5 });
+        let mut frames = [false; 10];
+        frames[0] = true;
+        assert_eq!(
+            levels(&mut filter, &frames),
+            [255, 127, 63, 31, 15, 7, 3, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn frame_or_holds_pixels_for_its_frames() {
+        let mut filter = PhosphorFilter::new();
+        filter.set_phosphor(Phosphor::FrameOr { frames: 3 });
+        assert_eq!(
+            levels(&mut filter, &[true, false, false, false, true, false]),
+            [255, 255, 255, 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn changing_the_mode_forgets_the_history() {
+        let mut filter = PhosphorFilter::new();
+        filter.set_phosphor(Phosphor::FrameOr { frames: 3 });
+        levels(&mut filter, &[true]);
+
+        // Setting the same mode again keeps it
+        filter.set_phosphor(Phosphor::FrameOr { frames: 3 });
+        assert_eq!(levels(&mut filter, &[false]), [255]);
+
+        filter.set_phosphor(Phosphor::FrameOr { frames: 4 });
+        assert_eq!(filter.level(3, 2), 0);
+        assert_eq!(levels(&mut filter, &[false]), [0]);
+
+        filter.set_phosphor(Phosphor::Decay { persistence: 0.9 });
+        levels(&mut filter, &[true]);
+        filter.set_phosphor(Phosphor::Off);
+        assert!(!filter.is_enabled());
+        assert_eq!(filter.level(3, 2), 0);
+    }
+}
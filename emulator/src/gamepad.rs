@@ -0,0 +1,172 @@
+use crate::{
+    bindings::{KeyBindings, Remapper},
+    keypad::{KeyInput, KeyState, KEY_COUNT},
+    roms::Controls,
+    view::{self, AnimationFrame},
+};
+use gloo_utils::window;
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::JsCast;
+use web_sys::Gamepad;
+
+/// How far a stick must be pushed along an axis to count as pressed.
+const AXIS_THRESHOLD: f64 = 0.5;
+
+// Indices from the standard gamepad layout: https://w3c.github.io/gamepad/#remapping
+const BUTTON_BOTTOM: u32 = 0;
+const BUTTON_RIGHT: u32 = 1;
+const BUTTON_DPAD_UP: u32 = 12;
+const BUTTON_DPAD_DOWN: u32 = 13;
+const BUTTON_DPAD_LEFT: u32 = 14;
+const BUTTON_DPAD_RIGHT: u32 = 15;
+const AXIS_LEFT_STICK_X: u32 = 0;
+const AXIS_LEFT_STICK_Y: u32 = 1;
+
+/// Binding name of a gamepad button, e.g. `GamepadButton12`.
+fn button(index: u32) -> String {
+    format!("GamepadButton{index}")
+}
+
+/// Binding name of a gamepad axis pushed in one direction, e.g. `GamepadAxis1-`.
+fn axis(index: u32, is_positive: bool) -> String {
+    format!("GamepadAxis{index}{}", if is_positive { '+' } else { '-' })
+}
+
+/// Maps the D-pad and left stick to the game's directions, and the bottom and right face buttons
+/// to its action key.
+#[must_use]
+pub fn default_bindings(controls: &Controls) -> KeyBindings {
+    let mut bindings = KeyBindings::empty();
+
+    let directions = [
+        (controls.up, BUTTON_DPAD_UP, AXIS_LEFT_STICK_Y, false),
+        (controls.down, BUTTON_DPAD_DOWN, AXIS_LEFT_STICK_Y, true),
+        (controls.left, BUTTON_DPAD_LEFT, AXIS_LEFT_STICK_X, false),
+        (controls.right, BUTTON_DPAD_RIGHT, AXIS_LEFT_STICK_X, true),
+    ];
+    for (key, button_index, axis_index, is_positive) in directions {
+        if let Some(key) = key {
+            bindings.bind(key as usize, &button(button_index));
+            bindings.bind(key as usize, &axis(axis_index, is_positive));
+        }
+    }
+
+    if let Some(key) = controls.action {
+        bindings.bind(key as usize, &button(BUTTON_BOTTOM));
+        bindings.bind(key as usize, &button(BUTTON_RIGHT));
+    }
+
+    bindings
+}
+
+/// Binding names of every input currently pressed on any connected gamepad.
+fn active_inputs() -> Vec<String> {
+    let Ok(gamepads) = window().navigator().get_gamepads() else {
+        return Vec::new();
+    };
+
+    let mut inputs = Vec::new();
+    for gamepad in gamepads.iter() {
+        let Ok(gamepad) = gamepad.dyn_into::<Gamepad>() else {
+            continue;
+        };
+        if !gamepad.connected() {
+            continue;
+        }
+
+        for (index, gamepad_button) in gamepad.buttons().iter().enumerate() {
+            let is_pressed = gamepad_button
+                .dyn_into::<web_sys::GamepadButton>()
+                .is_ok_and(|gamepad_button| gamepad_button.pressed());
+            if is_pressed {
+                inputs.push(button(index as u32));
+            }
+        }
+
+        for (index, value) in gamepad.axes().iter().enumerate() {
+            let value = value.as_f64().unwrap_or(0.);
+            if value.abs() >= AXIS_THRESHOLD {
+                inputs.push(axis(index as u32, value > 0.));
+            }
+        }
+    }
+
+    inputs
+}
+
+/// Feeds gamepad input into the [`KeyInput`]. Gamepads have no events for button presses, so they
+/// are polled once per animation frame.
+#[derive(Debug, Default)]
+struct GamepadPoller {
+    active_inputs: Vec<String>,
+    key_states: [KeyState; KEY_COUNT],
+}
+
+impl GamepadPoller {
+    fn poll(&mut self, keypad: &RefCell<KeyInput>, remapper: &RefCell<Remapper>) {
+        let active_inputs = active_inputs();
+
+        for input in &active_inputs {
+            if !self.active_inputs.contains(input) && remapper.borrow_mut().capture(input) {
+                // The input was bound rather than passed to the game
+                self.active_inputs = active_inputs;
+                return;
+            }
+        }
+
+        let mut key_states = [KeyState::Up; KEY_COUNT];
+        for input in &active_inputs {
+            if let Some(key) = remapper.borrow().bindings.key_for_code(input) {
+                key_states[key] = KeyState::Down;
+            }
+        }
+
+        for (key, (&state, &prev_state)) in key_states.iter().zip(&self.key_states).enumerate() {
+            if state != prev_state {
+                keypad.borrow_mut().update_key_state(key, state);
+            }
+        }
+
+        self.active_inputs = active_inputs;
+        self.key_states = key_states;
+    }
+}
+
+pub fn set_up_gamepad_polling(
+    keypad: &Rc<RefCell<KeyInput>>,
+    remapper: &Rc<RefCell<Remapper>>,
+) -> AnimationFrame {
+    let keypad = Rc::clone(keypad);
+    let remapper = Rc::clone(remapper);
+    let mut poller = GamepadPoller::default();
+
+    view::set_up_render_loop(move |_| poller.poll(&keypad, &remapper))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roms;
+
+    #[test]
+    fn default_bindings_follow_the_games_controls() {
+        let bindings = default_bindings(&roms::rom_info("TANK").controls);
+        assert_eq!(bindings.codes(0x2), ["GamepadButton12", "GamepadAxis1-"]);
+        assert_eq!(bindings.codes(0x8), ["GamepadButton13", "GamepadAxis1+"]);
+        assert_eq!(bindings.codes(0x4), ["GamepadButton14", "GamepadAxis0-"]);
+        assert_eq!(bindings.codes(0x6), ["GamepadButton15", "GamepadAxis0+"]);
+        assert_eq!(bindings.codes(0x5), ["GamepadButton0", "GamepadButton1"]);
+    }
+
+    #[test]
+    fn controls_a_game_lacks_are_left_unbound() {
+        let bindings = default_bindings(&roms::rom_info("BRIX").controls);
+        assert_eq!(bindings.key_for_code("GamepadButton14"), Some(0x4));
+        assert_eq!(bindings.key_for_code("GamepadButton15"), Some(0x6));
+        assert_eq!(bindings.key_for_code("GamepadButton12"), None);
+        assert_eq!(bindings.key_for_code("GamepadButton0"), None);
+
+        let bindings = default_bindings(&Controls::default());
+        assert!((0..KEY_COUNT).all(|key| bindings.codes(key).is_empty()));
+    }
+}
@@ -0,0 +1,775 @@
+//! Compiles blocks of CHIP-8 instructions into WebAssembly modules. The translation is part of
+//! the core, so that it can be tested natively, and the `web` feature adds [`Jit`], which runs the
+//! modules in the browser.
+
+use crate::{
+    cpu::{CYCLES_PER_FRAME, REGISTER_COUNT},
+    opcode::{Opcode, BYTES_PER_SPRITE},
+    quirks::Quirks,
+};
+#[cfg(feature = "web")]
+use {
+    crate::cpu::{Cpu, TOTAL_MEMORY_BYTES},
+    gloo_console::log,
+    js_sys::{Function, Object, Reflect, Uint8Array, WebAssembly},
+    std::mem,
+    wasm_bindgen::{prelude::*, JsCast},
+};
+
+/// Times an address has to be reached before the block starting there is compiled.
+#[cfg(feature = "web")]
+const HOT_THRESHOLD: u16 = 32;
+/// Most instructions in a block. A block never runs past the end of a frame, so longer ones
+/// could never run.
+const MAX_BLOCK_LEN: u32 = CYCLES_PER_FRAME as u32;
+
+// Layout of the registers that compiled blocks work on: V0 to VF, then I and the program counter,
+// both little-endian
+pub const I_OFFSET: usize = REGISTER_COUNT;
+pub const PC_OFFSET: usize = I_OFFSET + 2;
+pub const STATE_BYTES: usize = PC_OFFSET + 2;
+
+// Parameters and locals of a block's `run` function
+const STATE: u8 = 0;
+const BUDGET: u8 = 1;
+const COUNT: u8 = 2;
+const TEMP: u8 = 3;
+
+// WebAssembly instructions and types used by blocks
+const I32: u8 = 0x7F;
+const LOOP: u8 = 0x03;
+const EMPTY_BLOCK_TYPE: u8 = 0x40;
+const END: u8 = 0x0B;
+const BR_IF: u8 = 0x0D;
+const SELECT: u8 = 0x1B;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const I32_LOAD8_U: u8 = 0x2D;
+const I32_LOAD16_U: u8 = 0x2F;
+const I32_STORE8: u8 = 0x3A;
+const I32_STORE16: u8 = 0x3B;
+const I32_CONST: u8 = 0x41;
+const I32_EQ: u8 = 0x46;
+const I32_NE: u8 = 0x47;
+const I32_LE_S: u8 = 0x4C;
+const I32_GE_S: u8 = 0x4E;
+const I32_ADD: u8 = 0x6A;
+const I32_SUB: u8 = 0x6B;
+const I32_MUL: u8 = 0x6C;
+const I32_AND: u8 = 0x71;
+const I32_OR: u8 = 0x72;
+const I32_XOR: u8 = 0x73;
+const I32_SHL: u8 = 0x74;
+const I32_SHR_U: u8 = 0x76;
+
+fn write_leb128(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// The body of a WebAssembly function, built an instruction at a time.
+#[derive(Debug, Default)]
+struct Code(Vec<u8>);
+
+impl Code {
+    fn op(&mut self, op: u8) -> &mut Self {
+        self.0.push(op);
+        self
+    }
+
+    fn local_get(&mut self, local: u8) -> &mut Self {
+        self.op(LOCAL_GET).op(local)
+    }
+
+    fn local_set(&mut self, local: u8) -> &mut Self {
+        self.op(LOCAL_SET).op(local)
+    }
+
+    fn i32_const(&mut self, mut value: i32) -> &mut Self {
+        self.op(I32_CONST);
+        // Signed LEB128, which ends once the rest is just copies of the sign bit
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            let is_last = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+            if is_last {
+                return self.op(byte);
+            }
+            self.op(byte | 0x80);
+        }
+    }
+
+    /// A load or store at `offset` into the state, whose address is on the stack.
+    fn memory_op(&mut self, op: u8, offset: usize) -> &mut Self {
+        // Byte alignment, which is always valid
+        self.op(op).op(0);
+        write_leb128(&mut self.0, offset as u32);
+        self
+    }
+
+    fn reg(&mut self, vx: u8) -> &mut Self {
+        self.local_get(STATE).memory_op(I32_LOAD8_U, vx.into())
+    }
+
+    /// Stores the value that `value` pushes in Vx.
+    fn set_reg(&mut self, vx: u8, value: impl FnOnce(&mut Self)) -> &mut Self {
+        self.local_get(STATE);
+        value(self);
+        self.memory_op(I32_STORE8, vx.into())
+    }
+
+    fn i_reg(&mut self) -> &mut Self {
+        self.local_get(STATE).memory_op(I32_LOAD16_U, I_OFFSET)
+    }
+
+    fn set_i_reg(&mut self, value: impl FnOnce(&mut Self)) -> &mut Self {
+        self.local_get(STATE);
+        value(self);
+        self.memory_op(I32_STORE16, I_OFFSET)
+    }
+
+    /// Sets Vx from TEMP, the result of an arithmetic instruction, and VF from `flag`, in the
+    /// order [`Opcode::execute`] does so that the flag wins when x is F.
+    fn set_result_and_flag(&mut self, vx: u8, flag: impl FnOnce(&mut Self)) -> &mut Self {
+        self.set_reg(vx, |code| {
+            code.local_get(TEMP);
+        })
+        .set_reg(0xF, flag)
+    }
+
+    /// Pushes the address after the skip instruction ending at `next`, depending on the
+    /// condition that `condition` pushes.
+    fn skip(&mut self, next: u16, condition: impl FnOnce(&mut Self)) -> &mut Self {
+        self.i32_const(i32::from(next) + 2).i32_const(next.into());
+        condition(self);
+        self.op(SELECT)
+    }
+
+    /// Appends an instruction that neither jumps nor skips.
+    fn instruction(&mut self, opcode: Opcode, quirks: Quirks) {
+        match opcode {
+            Opcode::LD { vx, byte } => {
+                self.set_reg(vx, |code| {
+                    code.i32_const(byte.into());
+                });
+            }
+            Opcode::ADD { vx, byte } => {
+                // Storing a byte wraps the sum
+                self.set_reg(vx, |code| {
+                    code.reg(vx).i32_const(byte.into()).op(I32_ADD);
+                });
+            }
+            Opcode::LD_R { vx, vy } => {
+                self.set_reg(vx, |code| {
+                    code.reg(vy);
+                });
+            }
+            Opcode::OR_R { vx, vy } | Opcode::AND_R { vx, vy } | Opcode::XOR_R { vx, vy } => {
+                let op = match opcode {
+                    Opcode::OR_R { .. } => I32_OR,
+                    Opcode::AND_R { .. } => I32_AND,
+                    _ => I32_XOR,
+                };
+                self.set_reg(vx, |code| {
+                    code.reg(vx).reg(vy).op(op);
+                });
+                if quirks.logic_resets_vf {
+                    self.set_reg(0xF, |code| {
+                        code.i32_const(0);
+                    });
+                }
+            }
+            Opcode::ADD_R { vx, vy } => {
+                self.reg(vx).reg(vy).op(I32_ADD).local_set(TEMP);
+                self.set_result_and_flag(vx, |code| {
+                    code.local_get(TEMP).i32_const(8).op(I32_SHR_U);
+                });
+            }
+            Opcode::SUB_R { vx, vy } | Opcode::SUBN_R { vx, vy } => {
+                let (minuend, subtrahend) = match opcode {
+                    Opcode::SUB_R { .. } => (vx, vy),
+                    _ => (vy, vx),
+                };
+                self.reg(minuend)
+                    .reg(subtrahend)
+                    .op(I32_SUB)
+                    .local_set(TEMP);
+                // Not borrowing is the difference not going below 0
+                self.set_result_and_flag(vx, |code| {
+                    code.local_get(TEMP).i32_const(0).op(I32_GE_S);
+                });
+            }
+            Opcode::SHR { vx, vy } | Opcode::SHL { vx, vy } => {
+                let src = if quirks.shift_uses_vy { vy } else { vx };
+                let (flag, shift) = match opcode {
+                    Opcode::SHR { .. } => ((I32_AND, 1), I32_SHR_U),
+                    _ => ((I32_SHR_U, 7), I32_SHL),
+                };
+                self.reg(src).local_set(TEMP);
+                // The flag is set first, so the result wins when x is F
+                self.set_reg(0xF, |code| {
+                    code.local_get(TEMP).i32_const(flag.1).op(flag.0);
+                })
+                .set_reg(vx, |code| {
+                    code.local_get(TEMP).i32_const(1).op(shift);
+                });
+            }
+            Opcode::LD_A { addr } => {
+                self.set_i_reg(|code| {
+                    code.i32_const(addr.into());
+                });
+            }
+            Opcode::ADD_I { vx } => {
+                // Storing 16 bits wraps the sum
+                self.set_i_reg(|code| {
+                    code.i_reg().reg(vx).op(I32_ADD);
+                });
+            }
+            Opcode::LD_F { vx } => {
+                self.set_i_reg(|code| {
+                    code.reg(vx).i32_const(BYTES_PER_SPRITE.into()).op(I32_MUL);
+                });
+            }
+            _ => unreachable!("{opcode:?} can't be compiled"),
+        }
+    }
+}
+
+/// Decodes the instructions that blocks can contain, and `None` for anything else, including
+/// bytes that aren't instructions at all.
+fn decode(opcode: u16) -> Option<Opcode> {
+    let can_compile = match opcode >> 12 {
+        0x1 | 0x3..=0x7 | 0x9 | 0xA => true,
+        0x8 => matches!(opcode & 0xF, 0x0..=0x7 | 0xE),
+        0xF => matches!(opcode & 0xFF, 0x1E | 0x29),
+        _ => false,
+    };
+    can_compile.then(|| Opcode::from(opcode))
+}
+
+/// A block translated into a WebAssembly module.
+#[derive(Debug)]
+pub struct Translation {
+    pub wasm: Vec<u8>,
+    /// Address of the block's first instruction.
+    pub start: u16,
+    /// The memory the block was translated from.
+    pub code: Vec<u8>,
+    /// Instructions in one pass through the block.
+    pub len: u32,
+}
+
+impl Translation {
+    /// Whether the program has overwritten the block's code in `memory` since it was translated.
+    #[must_use]
+    pub fn is_stale(&self, memory: &[u8]) -> bool {
+        let start = self.start as usize;
+        memory.get(start..start + self.code.len()) != Some(self.code.as_slice())
+    }
+}
+
+/// Translates the block of instructions at `start`: as many as can be compiled, up to and
+/// including the first jump or skip. Returns `None` if the first one can't be compiled.
+///
+/// The module imports a memory as `env.memory` and exports `run(state, budget)`, which runs the
+/// block on the [`STATE_BYTES`] of registers at address `state` and returns the number of
+/// instructions it ran. A block that jumps back to its own start keeps going for as long as
+/// another pass fits into `budget` instructions.
+#[must_use]
+pub fn translate(memory: &[u8], start: u16, quirks: Quirks) -> Option<Translation> {
+    let mut body = Code::default();
+    // Pushes the address to continue at
+    let mut exit = Code::default();
+    let mut is_loop = false;
+    let mut address = start;
+    let mut len = 0;
+
+    loop {
+        let opcode = memory
+            .get(address as usize..address as usize + 2)
+            .and_then(|bytes| decode(u16::from_be_bytes([bytes[0], bytes[1]])))
+            .filter(|_| len < MAX_BLOCK_LEN);
+        let Some(opcode) = opcode else {
+            exit.i32_const(address.into());
+            break;
+        };
+        len += 1;
+        address += 2;
+
+        match opcode {
+            Opcode::JP { addr } => {
+                exit.i32_const(addr.into());
+                is_loop = addr == start;
+                break;
+            }
+            Opcode::SE { vx, byte } | Opcode::SNE { vx, byte } => {
+                let op = if matches!(opcode, Opcode::SE { .. }) {
+                    I32_EQ
+                } else {
+                    I32_NE
+                };
+                exit.skip(address, |code| {
+                    code.reg(vx).i32_const(byte.into()).op(op);
+                });
+                break;
+            }
+            Opcode::SE_R { vx, vy } | Opcode::SNE_R { vx, vy } => {
+                let op = if matches!(opcode, Opcode::SE_R { .. }) {
+                    I32_EQ
+                } else {
+                    I32_NE
+                };
+                exit.skip(address, |code| {
+                    code.reg(vx).reg(vy).op(op);
+                });
+                break;
+            }
+            _ => body.instruction(opcode, quirks),
+        }
+    }
+
+    if len == 0 {
+        return None;
+    }
+
+    let mut function = Code(vec![1, 2, I32]);
+    function.op(LOOP).op(EMPTY_BLOCK_TYPE);
+    function.0.extend(body.0);
+    function
+        .local_get(COUNT)
+        .i32_const(len as i32)
+        .op(I32_ADD)
+        .local_set(COUNT);
+    if is_loop {
+        function
+            .local_get(COUNT)
+            .i32_const(len as i32)
+            .op(I32_ADD)
+            .local_get(BUDGET)
+            .op(I32_LE_S)
+            .op(BR_IF)
+            .op(0);
+    }
+    function.op(END).local_get(STATE);
+    function.0.extend(exit.0);
+    function
+        .memory_op(I32_STORE16, PC_OFFSET)
+        .local_get(COUNT)
+        .op(END);
+
+    Some(Translation {
+        wasm: module(&function.0),
+        start,
+        code: memory[start as usize..address as usize].to_vec(),
+        len,
+    })
+}
+
+fn write_section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    write_leb128(module, contents.len() as u32);
+    module.extend(contents);
+}
+
+/// A module that imports a memory as `env.memory`, and exports `function` as `run`.
+fn module(function: &[u8]) -> Vec<u8> {
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    // (state, budget) -> instructions run
+    write_section(&mut module, 1, &[1, 0x60, 2, I32, I32, 1, I32]);
+    write_section(&mut module, 2, b"\x01\x03env\x06memory\x02\x00\x00");
+    write_section(&mut module, 3, &[1, 0]);
+    write_section(&mut module, 7, b"\x01\x03run\x00\x00");
+
+    let mut code = vec![1];
+    write_leb128(&mut code, function.len() as u32);
+    code.extend(function);
+    write_section(&mut module, 10, &code);
+
+    module
+}
+
+#[cfg(feature = "web")]
+#[derive(Debug)]
+struct Block {
+    translation: Translation,
+    run: Function,
+}
+
+/// Runs a [`Cpu`] like [`Cpu::run_frame`], but compiles blocks of instructions that run often
+/// into WebAssembly modules, and calls them instead of interpreting those instructions.
+///
+/// Blocks are straight runs of register arithmetic ending at a jump or skip. Anything touching
+/// memory, the display, timers or keys ends a block and is left to the interpreter, so the
+/// machine behaves exactly as it does without the JIT. A block is thrown away when its code is
+/// overwritten, and all of them when the quirks change, as they are compiled in.
+#[cfg(feature = "web")]
+#[derive(Debug)]
+pub struct Jit {
+    /// The block starting at each address, once it has been compiled.
+    blocks: Vec<Option<Block>>,
+    /// How many times each address without a block has been reached.
+    heat: Vec<u16>,
+    /// The quirks the blocks were compiled with.
+    quirks: Quirks,
+    /// The registers that a block runs on, copied in and out around every call.
+    state: Box<[u8; STATE_BYTES]>,
+    imports: Object,
+    /// Whether compiling failed, e.g. because the page's Content Security Policy forbids it, so
+    /// only the interpreter runs.
+    is_unavailable: bool,
+}
+
+#[cfg(feature = "web")]
+impl Default for Jit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "web")]
+impl Jit {
+    #[must_use]
+    pub fn new() -> Self {
+        // Blocks work directly on this module's memory
+        let env = Object::new();
+        Reflect::set(&env, &"memory".into(), &wasm_bindgen::memory()).unwrap_throw();
+        let imports = Object::new();
+        Reflect::set(&imports, &"env".into(), &env).unwrap_throw();
+
+        Self {
+            blocks: (0..TOTAL_MEMORY_BYTES).map(|_| None).collect(),
+            heat: vec![0; TOTAL_MEMORY_BYTES],
+            quirks: Quirks::default(),
+            state: Box::new([0; STATE_BYTES]),
+            imports,
+            is_unavailable: false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.blocks.fill_with(|| None);
+        self.heat.fill(0);
+    }
+
+    /// Runs a single 60Hz frame, as [`Cpu::run_frame`] does.
+    pub fn run_frame(&mut self, cpu: &mut Cpu) {
+        if self.is_unavailable {
+            cpu.run_frame();
+            return;
+        }
+        if cpu.quirks != self.quirks {
+            self.clear();
+            self.quirks = cpu.quirks;
+        }
+
+        cpu.keypad.process_events();
+
+        let mut cycles = u32::from(CYCLES_PER_FRAME);
+        while cycles > 0 {
+            if let Some(ran) = self.run_block(cpu, cycles) {
+                cycles -= ran;
+            } else {
+                cpu.cycle();
+                cycles -= 1;
+            }
+        }
+
+        cpu.update_timers();
+    }
+
+    /// Runs the block at the program counter, compiling it if it has become hot, and returns the
+    /// number of instructions it ran. Returns `None` if there is no block to run, or it is longer
+    /// than `budget` instructions.
+    fn run_block(&mut self, cpu: &mut Cpu, budget: u32) -> Option<u32> {
+        let pc = cpu.pc as usize;
+        let is_stale = self
+            .blocks
+            .get(pc)?
+            .as_ref()
+            .is_some_and(|block| block.translation.is_stale(&cpu.memory));
+        if is_stale {
+            self.blocks[pc] = None;
+        }
+
+        if self.blocks[pc].is_none() {
+            self.heat[pc] += 1;
+            if self.heat[pc] < HOT_THRESHOLD {
+                return None;
+            }
+            self.heat[pc] = 0;
+            self.blocks[pc] = self.compile(&cpu.memory, cpu.pc);
+        }
+
+        let block = self.blocks[pc].as_ref()?;
+        if block.translation.len > budget {
+            return None;
+        }
+
+        self.state[..I_OFFSET].copy_from_slice(&cpu.regs);
+        self.state[I_OFFSET..PC_OFFSET].copy_from_slice(&cpu.i_reg.to_le_bytes());
+        let ran = block
+            .run
+            .call2(
+                &JsValue::NULL,
+                &(self.state.as_ptr() as usize).into(),
+                &budget.into(),
+            )
+            .ok()
+            .and_then(|ran| ran.as_f64());
+        let Some(ran) = ran else {
+            // The machine is untouched, as only `state` was changed
+            log!("Block at", pc, "failed to run");
+            self.blocks[pc] = None;
+            return None;
+        };
+
+        cpu.regs.copy_from_slice(&self.state[..I_OFFSET]);
+        cpu.i_reg = u16::from_le_bytes([self.state[I_OFFSET], self.state[I_OFFSET + 1]]);
+        cpu.pc = u16::from_le_bytes([self.state[PC_OFFSET], self.state[PC_OFFSET + 1]]);
+        Some(ran as u32)
+    }
+
+    fn compile(&mut self, memory: &[u8], pc: u16) -> Option<Block> {
+        let mut translation = translate(memory, pc, self.quirks)?;
+
+        // The module is only needed until it is instantiated
+        let wasm = mem::take(&mut translation.wasm);
+        let run = WebAssembly::Module::new(&Uint8Array::from(wasm.as_slice()))
+            .and_then(|module| WebAssembly::Instance::new(&module, &self.imports))
+            .and_then(|instance| Reflect::get(&instance.exports(), &"run".into()))
+            .and_then(|run| run.dyn_into::<Function>());
+        match run {
+            Ok(run) => Some(Block { translation, run }),
+            Err(err) => {
+                log!("Failed to compile a block, so the JIT is off:", err);
+                self.is_unavailable = true;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Cpu, TOTAL_MEMORY_BYTES};
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg32;
+    use wasmi::{Engine, Linker, Memory, MemoryType, Module, Store};
+
+    /// Where the registers go in the block's memory, away from address 0 as in the emulator.
+    const STATE_AT: usize = 0x1234;
+
+    /// Every combination of the quirks that compiled blocks depend on.
+    fn quirks() -> impl Iterator<Item = Quirks> {
+        (0..4).map(|bits| Quirks {
+            shift_uses_vy: bits & 1 != 0,
+            logic_resets_vf: bits & 2 != 0,
+            ..Quirks::default()
+        })
+    }
+
+    /// Runs `translation` in a native WebAssembly runtime on `cpu`'s registers, and returns the
+    /// number of instructions it ran and the registers it left.
+    fn run(translation: &Translation, cpu: &Cpu, budget: u32) -> (u32, [u8; STATE_BYTES]) {
+        let engine = Engine::default();
+        let module = Module::new(&engine, &translation.wasm[..]).unwrap();
+        let mut store = Store::new(&engine, ());
+        let memory = Memory::new(&mut store, MemoryType::new(1, None).unwrap()).unwrap();
+        let mut linker = Linker::new(&engine);
+        linker.define("env", "memory", memory).unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .unwrap();
+        let run = instance
+            .get_typed_func::<(i32, i32), i32>(&store, "run")
+            .unwrap();
+
+        let mut state = [0; STATE_BYTES];
+        state[..I_OFFSET].copy_from_slice(&cpu.regs);
+        state[I_OFFSET..PC_OFFSET].copy_from_slice(&cpu.i_reg.to_le_bytes());
+        memory.write(&mut store, STATE_AT, &state).unwrap();
+        let ran = run
+            .call(&mut store, (STATE_AT as i32, budget as i32))
+            .unwrap();
+        memory.read(&store, STATE_AT, &mut state).unwrap();
+        (ran as u32, state)
+    }
+
+    /// Runs the block at the start of `program` from registers `regs`, both compiled and
+    /// interpreted, checks that both leave the same registers, and returns the number of
+    /// instructions it ran.
+    fn check(program: &[u8], regs: [u8; REGISTER_COUNT], quirks: Quirks, budget: u32) -> u32 {
+        let mut cpu = Cpu::with_seed(program, 0);
+        cpu.regs = regs;
+        cpu.i_reg = 0x345;
+        cpu.quirks = quirks;
+
+        let translation = translate(&cpu.memory, cpu.pc, quirks).unwrap();
+        let (ran, state) = run(&translation, &cpu, budget);
+        for _ in 0..ran {
+            cpu.cycle();
+        }
+
+        let context = format!("{program:02X?} on {regs:02X?} with {quirks:?}");
+        assert_eq!(state[..I_OFFSET], cpu.regs, "V registers after {context}");
+        assert_eq!(
+            u16::from_le_bytes([state[I_OFFSET], state[I_OFFSET + 1]]),
+            cpu.i_reg,
+            "I after {context}"
+        );
+        assert_eq!(
+            u16::from_le_bytes([state[PC_OFFSET], state[PC_OFFSET + 1]]),
+            cpu.pc,
+            "pc after {context}"
+        );
+        ran
+    }
+
+    /// Registers where every bit differs between neighbours, so shifts and flags show.
+    fn regs() -> [u8; REGISTER_COUNT] {
+        std::array::from_fn(|i| if i % 2 == 0 { 0x81 } else { 0x7E })
+    }
+
+    #[test]
+    fn shifts_set_the_flag_before_the_result() {
+        for quirks in quirks() {
+            for op in [0x6, 0xE] {
+                for (vx, vy) in [(0x0, 0x1), (0x0, 0xF), (0xF, 0x1), (0xF, 0xF)] {
+                    let program = [0x80 | vx, vy << 4 | op, 0x13, 0x00];
+                    assert_eq!(check(&program, regs(), quirks, 10), 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn logic_resets_vf_only_with_the_quirk() {
+        for quirks in quirks() {
+            for op in 0x1..=0x3 {
+                for (vx, vy) in [(0x0, 0x1), (0xF, 0x1), (0x1, 0xF)] {
+                    let program = [0x80 | vx, vy << 4 | op, 0x13, 0x00];
+                    check(&program, regs(), quirks, 10);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_blocks_run_like_the_interpreter() {
+        let mut rng = Pcg32::seed_from_u64(0);
+        let arithmetic = [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE];
+        for _ in 0..2000 {
+            let mut program = Vec::new();
+            for _ in 0..rng.random_range(0..12) {
+                let (x, y, byte): (u16, u16, u16) = (
+                    rng.random_range(0..16),
+                    rng.random_range(0..16),
+                    rng.random_range(0..0x100),
+                );
+                let opcode: u16 = match rng.random_range(0..6) {
+                    0 => 0x6000 | x << 8 | byte,
+                    1 => 0x7000 | x << 8 | byte,
+                    2 => 0x8000 | x << 8 | y << 4 | arithmetic[rng.random_range(0..9)],
+                    3 => 0xA000 | rng.random_range(0..0x1000),
+                    4 => 0xF01E | x << 8,
+                    _ => 0xF029 | x << 8,
+                };
+                program.extend(opcode.to_be_bytes());
+            }
+            let (x, y, byte): (u16, u16, u16) = (
+                rng.random_range(0..16),
+                rng.random_range(0..16),
+                rng.random_range(0..0x100),
+            );
+            let end: u16 = match rng.random_range(0..5) {
+                0 => 0x1300,
+                1 => 0x3000 | x << 8 | byte,
+                2 => 0x4000 | x << 8 | byte,
+                3 => 0x5000 | x << 8 | y << 4,
+                _ => 0x9000 | x << 8 | y << 4,
+            };
+            program.extend(end.to_be_bytes());
+
+            let regs = rng.random::<[u8; REGISTER_COUNT]>();
+            let quirks = quirks().nth(rng.random_range(0..4)).unwrap();
+            check(&program, regs, quirks, 10);
+        }
+    }
+
+    #[test]
+    fn loop_runs_while_a_pass_fits_in_the_budget() {
+        // V0 += 1, and jump back to the start
+        let program = [0x70, 0x01, 0x12, 0x00];
+        assert_eq!(
+            check(&program, [0; REGISTER_COUNT], Quirks::default(), 10),
+            10
+        );
+        assert_eq!(
+            check(&program, [0; REGISTER_COUNT], Quirks::default(), 9),
+            8
+        );
+        assert_eq!(
+            check(&program, [0; REGISTER_COUNT], Quirks::default(), 2),
+            2
+        );
+
+        // A skip back to the start doesn't loop
+        let program = [0x70, 0x01, 0x30, 0x00];
+        assert_eq!(
+            check(&program, [0; REGISTER_COUNT], Quirks::default(), 10),
+            2
+        );
+    }
+
+    #[test]
+    fn blocks_end_at_jumps_skips_and_instructions_that_cant_be_compiled() {
+        let block = |program: &[u8]| {
+            let cpu = Cpu::with_seed(program, 0);
+            translate(&cpu.memory, cpu.pc, Quirks::default())
+                .map(|translation| (translation.len, translation.code.len()))
+        };
+
+        // Drawing is left to the interpreter
+        assert_eq!(block(&[0x60, 0x05, 0xD0, 0x01]), Some((1, 2)));
+        assert_eq!(block(&[0xD0, 0x01, 0x60, 0x05]), None);
+        // Skips and jumps are the last instruction of a block
+        assert_eq!(block(&[0x60, 0x05, 0x30, 0x05, 0x60, 0x06]), Some((2, 4)));
+        assert_eq!(block(&[0x12, 0x00, 0x60, 0x06]), Some((1, 2)));
+        // Blocks are no longer than a frame
+        assert_eq!(block(&[0x60, 0x01].repeat(12)), Some((10, 20)));
+
+        // A block at the end of memory stops there
+        let mut memory = [0; TOTAL_MEMORY_BYTES];
+        memory[TOTAL_MEMORY_BYTES - 2..].copy_from_slice(&[0x60, 0x05]);
+        let translation = translate(&memory, TOTAL_MEMORY_BYTES as u16 - 2, Quirks::default());
+        assert_eq!(translation.map(|translation| translation.len), Some(1));
+    }
+
+    #[test]
+    fn overwriting_a_block_makes_it_stale() {
+        let program = [0x60, 0x05, 0x70, 0x01, 0x13, 0x00, 0xD0, 0x01];
+        let mut cpu = Cpu::with_seed(&program, 0);
+        let translation = translate(&cpu.memory, cpu.pc, Quirks::default()).unwrap();
+        assert!(!translation.is_stale(&cpu.memory));
+
+        // Writes around the block leave it as it is
+        cpu.memory[0x1FF] = 0xFF;
+        cpu.memory[0x206] = 0xFF;
+        assert!(!translation.is_stale(&cpu.memory));
+
+        // Writing the same byte again changes nothing, but any other value does
+        cpu.memory[0x205] = 0x00;
+        assert!(!translation.is_stale(&cpu.memory));
+        cpu.memory[0x205] = 0x02;
+        assert!(translation.is_stale(&cpu.memory));
+    }
+}
@@ -1,65 +1,129 @@
-use gloo_events::EventListener;
-use gloo_utils::window;
-use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::LazyLock};
-use wasm_bindgen::JsCast;
-use web_sys::{Event, KeyboardEvent};
+use std::collections::VecDeque;
+#[cfg(feature = "web")]
+use {
+    crate::bindings::Remapper,
+    gloo_events::EventListener,
+    gloo_utils::{document, window},
+    js_sys::{Atomics, Int32Array, SharedArrayBuffer},
+    std::{cell::RefCell, rc::Rc},
+    wasm_bindgen::{prelude::*, JsCast},
+    web_sys::{Event, HtmlInputElement, KeyboardEvent},
+};
 
-/// The CHIP-8 used a hexadecimal keyboard with the following layout:
-///
-/// 1 2 3 C
-/// 4 5 6 D
-/// 7 8 9 E
-/// A 0 B F
-///
-/// We remap these keys to the following layout:
-///
-/// 1 2 3 4
-/// Q W E R
-/// A S D F
-/// Z X C V
-const KEY_CODES: &[&str] = &[
-    "KeyX", "Digit1", "Digit2", "Digit3", "KeyQ", // 0 - 4
-    "KeyW", "KeyE", "KeyA", "KeyS", "KeyD", // 5 - 9
-    "KeyZ", "KeyC", "Digit4", "KeyR", "KeyF", "KeyV", // A - F
-];
-
-static KEY_CODE_INDICES: LazyLock<HashMap<String, usize>> = LazyLock::new(|| {
-    KEY_CODES
-        .iter()
-        .enumerate()
-        .map(|(i, key)| ((*key).to_owned(), i))
-        .collect()
-});
+pub const KEY_COUNT: usize = 16;
+/// Most events a keypad queues. Beyond this, e.g. while the game is paused, the oldest are applied
+/// straight away rather than a tick at a time.
+const MAX_QUEUED_EVENTS: usize = 4 * KEY_COUNT;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum KeyState {
     Down,
+    #[default]
     Up,
 }
 
-impl Default for KeyState {
-    fn default() -> Self {
-        Self::Up
-    }
+/// Packs the keys that are down into a bitmask, with key 0 in the least significant bit.
+#[must_use]
+pub fn key_mask(key_states: &[KeyState; KEY_COUNT]) -> u16 {
+    (0..KEY_COUNT)
+        .filter(|&key| key_states[key] == KeyState::Down)
+        .fold(0, |keys, key| keys | (1 << key))
 }
 
-#[derive(Debug)]
-pub enum LastKeypressState {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub key: usize,
+    pub state: KeyState,
+}
+
+/// Progress of an `Fx0A` instruction. Like the COSMAC VIP, it waits for a key to be pressed and
+/// then for that same key to be released. Keys already held when it starts are ignored.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum KeyWait {
+    #[default]
     NotWaiting,
-    Waiting,
-    Found(usize),
+    WaitingForPress,
+    WaitingForRelease(usize),
+    Released(usize),
+}
+
+/// Key states shared between the page, which receives input, and the worker running the CPU.
+///
+/// Each key's entry counts how many times it has changed, so it is odd while the key is down and a
+/// press and release between two ticks is not lost. Only the page writes to it.
+#[cfg(feature = "web")]
+#[derive(Clone, Debug)]
+pub struct SharedKeyStates {
+    changes: Int32Array,
+    /// Change counts already passed on to the worker's [`Keypad`].
+    seen: [i32; KEY_COUNT],
 }
 
-impl Default for LastKeypressState {
-    fn default() -> Self {
-        Self::NotWaiting
+#[cfg(feature = "web")]
+impl SharedKeyStates {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::from_buffer(&SharedArrayBuffer::new(
+            (KEY_COUNT * size_of::<i32>()) as u32,
+        ))
+    }
+
+    pub fn from_buffer(buffer: &SharedArrayBuffer) -> Self {
+        Self {
+            changes: Int32Array::new(buffer),
+            seen: [0; KEY_COUNT],
+        }
+    }
+
+    #[must_use]
+    pub fn buffer(&self) -> JsValue {
+        self.changes.buffer().into()
+    }
+
+    fn changes(&self, key: usize) -> i32 {
+        Atomics::load(&self.changes, key as u32).unwrap_throw()
+    }
+
+    fn state(changes: i32) -> KeyState {
+        if changes % 2 == 1 {
+            KeyState::Down
+        } else {
+            KeyState::Up
+        }
+    }
+
+    fn publish(&self, key: usize, state: KeyState) {
+        if Self::state(self.changes(key)) != state {
+            Atomics::add(&self.changes, key as u32, 1).unwrap_throw();
+        }
+    }
+
+    /// Queues changes published since the last call on `keypad`.
+    pub fn receive(&mut self, keypad: &mut Keypad) {
+        for key in 0..KEY_COUNT {
+            let changes = self.changes(key);
+            let unseen = changes - self.seen[key];
+
+            // Anything before the last press and release is already superseded
+            if unseen >= 2 {
+                keypad.update_key_state(key, Self::state(changes - 1));
+            }
+            if unseen >= 1 {
+                keypad.update_key_state(key, Self::state(changes));
+            }
+            self.seen[key] = changes;
+        }
     }
 }
 
 #[derive(Debug, Default)]
 pub struct Keypad {
-    pub key_states: [KeyState; KEY_CODES.len()],
-    pub last_keypress: LastKeypressState,
+    pub key_states: [KeyState; KEY_COUNT],
+    wait: KeyWait,
+    /// Events from input sources that have not been seen by the CPU yet.
+    events: VecDeque<KeyEvent>,
+    /// Every event queued since the last [`Self::take_log`], while a replay is being recorded.
+    log: Option<Vec<KeyEvent>>,
 }
 
 impl Keypad {
@@ -68,57 +132,368 @@ impl Keypad {
         Self::default()
     }
 
-    pub fn update_key_state(&mut self, i: usize, state: KeyState) {
-        self.key_states[i] = state;
+    /// Queues a key press or release, to be applied by [`Self::process_events`].
+    pub fn update_key_state(&mut self, key: usize, state: KeyState) {
+        let event = KeyEvent { key, state };
+        if let Some(log) = &mut self.log {
+            log.push(event);
+        }
+        if self.events.len() == MAX_QUEUED_EVENTS {
+            if let Some(KeyEvent { key, state }) = self.events.pop_front() {
+                self.apply(key, state);
+            }
+        }
+        self.events.push_back(event);
+    }
+
+    /// Events from input sources that have not been applied yet, oldest first.
+    #[must_use]
+    pub fn events(&self) -> &VecDeque<KeyEvent> {
+        &self.events
+    }
+
+    /// Applies every queued event at once, so that only the keys they leave down count, e.g.
+    /// when a program starts and shouldn't see presses from before it did.
+    pub fn apply_all_events(&mut self) {
+        while let Some(KeyEvent { key, state }) = self.events.pop_front() {
+            self.apply(key, state);
+        }
+    }
+
+    /// Starts logging queued events, beginning with those still waiting to be applied.
+    pub fn start_log(&mut self) {
+        self.log = Some(self.events.iter().copied().collect());
+    }
+
+    /// Returns the events logged since the last call.
+    pub fn take_log(&mut self) -> Vec<KeyEvent> {
+        self.log.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn stop_log(&mut self) {
+        self.log = None;
+    }
 
-        // `keyup` should only fire if previous state was down
-        if let LastKeypressState::Waiting = self.last_keypress {
-            if let KeyState::Up = state {
-                self.last_keypress = LastKeypressState::Found(i);
+    /// Queues presses and releases so that exactly the keys in `mask`, as from [`key_mask`], are
+    /// down once applied.
+    pub fn hold_keys(&mut self, mask: u16) {
+        for key in 0..KEY_COUNT {
+            let state = if mask & (1 << key) != 0 {
+                KeyState::Down
+            } else {
+                KeyState::Up
+            };
+            if self.key_states[key] != state {
+                self.update_key_state(key, state);
             }
         }
     }
 
-    pub fn try_take_last_keypress(&mut self) -> Option<usize> {
-        if let LastKeypressState::Found(key) = self.last_keypress {
-            self.last_keypress = LastKeypressState::NotWaiting;
-            Some(key)
-        } else {
-            self.last_keypress = LastKeypressState::Waiting;
-            None
+    /// Applies queued events. Should be called once per 60Hz tick.
+    ///
+    /// At most one change is applied to each key per call, so that a key pressed and released
+    /// within a single frame is still seen as down for a tick.
+    pub fn process_events(&mut self) {
+        let mut changed = [false; KEY_COUNT];
+
+        while let Some(&KeyEvent { key, state }) = self.events.front() {
+            if self.key_states[key] != state {
+                if changed[key] {
+                    break;
+                }
+                changed[key] = true;
+                self.apply(key, state);
+            }
+            self.events.pop_front();
+        }
+    }
+
+    fn apply(&mut self, key: usize, state: KeyState) {
+        if self.key_states[key] == state {
+            return;
+        }
+        self.key_states[key] = state;
+
+        self.wait = match (self.wait, state) {
+            (KeyWait::WaitingForPress, KeyState::Down) => KeyWait::WaitingForRelease(key),
+            (KeyWait::WaitingForRelease(pressed), KeyState::Up) if pressed == key => {
+                KeyWait::Released(key)
+            }
+            (wait, _) => wait,
+        };
+    }
+
+    /// Used by `Fx0A`. Starts waiting if not already, and returns the key once it has been pressed
+    /// and released.
+    pub fn try_take_keypress(&mut self) -> Option<usize> {
+        match self.wait {
+            KeyWait::NotWaiting => {
+                self.wait = KeyWait::WaitingForPress;
+                None
+            }
+            KeyWait::WaitingForPress | KeyWait::WaitingForRelease(_) => None,
+            KeyWait::Released(key) => {
+                self.wait = KeyWait::NotWaiting;
+                Some(key)
+            }
         }
     }
+
+    /// Abandons an in-progress `Fx0A`, e.g. when a new program is loaded.
+    pub fn cancel_wait(&mut self) {
+        self.wait = KeyWait::NotWaiting;
+    }
+}
+
+/// Where the page's input sources put key presses and releases. The emulator moves them from
+/// `keypad` to its CPU's own [`Keypad`] every tick, unless they are forwarded to a CPU in a worker.
+#[cfg(feature = "web")]
+#[derive(Debug, Default)]
+pub struct KeyInput {
+    pub keypad: Keypad,
+    forward_to: Option<SharedKeyStates>,
 }
 
-fn on_keypress(keystate: KeyState, keypad: &Rc<RefCell<Keypad>>) -> impl Fn(&Event) {
+#[cfg(feature = "web")]
+impl KeyInput {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Input that only passes on to a CPU in a worker, through `shared`.
+    #[must_use]
+    pub fn forwarding_to(shared: SharedKeyStates) -> Self {
+        Self {
+            forward_to: Some(shared),
+            ..Self::default()
+        }
+    }
+
+    pub fn update_key_state(&mut self, key: usize, state: KeyState) {
+        match &self.forward_to {
+            Some(shared) => shared.publish(key, state),
+            None => self.keypad.update_key_state(key, state),
+        }
+    }
+
+    /// Queues a release of every key that is down, e.g. when the page loses focus and `keyup`
+    /// events would otherwise be missed.
+    pub fn release_all(&mut self) {
+        for key in 0..KEY_COUNT {
+            self.update_key_state(key, KeyState::Up);
+        }
+    }
+}
+
+#[cfg(feature = "web")]
+fn on_keypress(
+    keystate: KeyState,
+    keypad: &Rc<RefCell<KeyInput>>,
+    remapper: &Rc<RefCell<Remapper>>,
+    gamepad_remapper: &Rc<RefCell<Remapper>>,
+) -> impl Fn(&Event) {
     let keypad = Rc::clone(keypad);
+    let remapper = Rc::clone(remapper);
+    let gamepad_remapper = Rc::clone(gamepad_remapper);
     move |event: &Event| {
         let event = event.dyn_ref::<KeyboardEvent>().unwrap();
         let code = event.code();
-        if let Some(&key_index) = KEY_CODE_INDICES.get(&code) {
+
+        // Holding a key down fires repeated `keydown`s, which aren't new presses
+        if event.repeat() {
+            return;
+        }
+
+        // Typing into a text field, e.g. a player name, doesn't play the game
+        let is_typing = event
+            .target()
+            .and_then(|target| target.dyn_into::<HtmlInputElement>().ok())
+            .is_some_and(|input| input.type_() == "text");
+        if keystate == KeyState::Down && is_typing {
+            return;
+        }
+
+        // A key pressed while remapping is bound rather than passed to the game
+        if keystate == KeyState::Down && remapper.borrow_mut().capture(&code) {
+            event.prevent_default();
+            return;
+        }
+
+        // A gamepad has no keys for cancelling or unbinding, so the keyboard's are used
+        let is_capture_key = code == "Escape" || code == "Backspace";
+        if keystate == KeyState::Down
+            && is_capture_key
+            && gamepad_remapper.borrow_mut().capture(&code)
+        {
+            event.prevent_default();
+            return;
+        }
+
+        let key_index = remapper.borrow().bindings.key_for_code(&code);
+        if let Some(key_index) = key_index {
             keypad.borrow_mut().update_key_state(key_index, keystate);
         }
     }
 }
 
+#[cfg(feature = "web")]
 #[derive(Debug)]
 pub struct KeyPressListeners {
     pub on_keydown: EventListener,
     pub on_keyup: EventListener,
+    pub on_blur: EventListener,
+    pub on_visibility_change: EventListener,
 }
 
+#[cfg(feature = "web")]
 impl KeyPressListeners {
-    pub fn new(keypad: &Rc<RefCell<Keypad>>) -> Self {
+    /// Plays keys bound by `remapper`. Escape and Backspace also end a capture by
+    /// `gamepad_remapper`.
+    pub fn new(
+        keypad: &Rc<RefCell<KeyInput>>,
+        remapper: &Rc<RefCell<Remapper>>,
+        gamepad_remapper: &Rc<RefCell<Remapper>>,
+    ) -> Self {
         let window = window();
 
-        let on_keydown =
-            EventListener::new(&window, "keydown", on_keypress(KeyState::Down, keypad));
+        let on_keydown = EventListener::new(
+            &window,
+            "keydown",
+            on_keypress(KeyState::Down, keypad, remapper, gamepad_remapper),
+        );
+
+        let on_keyup = EventListener::new(
+            &window,
+            "keyup",
+            on_keypress(KeyState::Up, keypad, remapper, gamepad_remapper),
+        );
 
-        let on_keyup = EventListener::new(&window, "keyup", on_keypress(KeyState::Up, keypad));
+        // Keys released while the page doesn't have focus never fire `keyup`
+        let on_blur = {
+            let keypad = Rc::clone(keypad);
+            EventListener::new(&window, "blur", move |_| keypad.borrow_mut().release_all())
+        };
+
+        let on_visibility_change = {
+            let keypad = Rc::clone(keypad);
+            EventListener::new(&document(), "visibilitychange", move |_| {
+                keypad.borrow_mut().release_all();
+            })
+        };
 
         Self {
             on_keydown,
             on_keyup,
+            on_blur,
+            on_visibility_change,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queues `state` for `key` and applies it, as a frame would.
+    fn set(keypad: &mut Keypad, key: usize, state: KeyState) {
+        keypad.update_key_state(key, state);
+        keypad.process_events();
+    }
+
+    #[test]
+    fn wait_ends_once_the_pressed_key_is_released() {
+        let mut keypad = Keypad::new();
+        assert_eq!(keypad.try_take_keypress(), None);
+        assert_eq!(keypad.wait, KeyWait::WaitingForPress);
+
+        set(&mut keypad, 0xA, KeyState::Down);
+        assert_eq!(keypad.try_take_keypress(), None);
+        assert_eq!(keypad.wait, KeyWait::WaitingForRelease(0xA));
+
+        set(&mut keypad, 0xA, KeyState::Up);
+        assert_eq!(keypad.try_take_keypress(), Some(0xA));
+        assert_eq!(keypad.wait, KeyWait::NotWaiting);
+    }
+
+    #[test]
+    fn wait_ignores_keys_held_before_it_started_and_other_releases() {
+        let mut keypad = Keypad::new();
+        set(&mut keypad, 0x1, KeyState::Down);
+        assert_eq!(keypad.try_take_keypress(), None);
+
+        // Releasing a key held from before isn't a keypress
+        set(&mut keypad, 0x1, KeyState::Up);
+        assert_eq!(keypad.try_take_keypress(), None);
+
+        // Only the release of the key that was pressed counts
+        set(&mut keypad, 0x2, KeyState::Down);
+        set(&mut keypad, 0x3, KeyState::Down);
+        set(&mut keypad, 0x3, KeyState::Up);
+        assert_eq!(keypad.try_take_keypress(), None);
+        set(&mut keypad, 0x2, KeyState::Up);
+        assert_eq!(keypad.try_take_keypress(), Some(0x2));
+    }
+
+    #[test]
+    fn repeated_presses_dont_restart_the_wait() {
+        let mut keypad = Keypad::new();
+        assert_eq!(keypad.try_take_keypress(), None);
+        set(&mut keypad, 0x4, KeyState::Down);
+        set(&mut keypad, 0x4, KeyState::Down);
+        assert_eq!(keypad.wait, KeyWait::WaitingForRelease(0x4));
+        set(&mut keypad, 0x4, KeyState::Up);
+        assert_eq!(keypad.try_take_keypress(), Some(0x4));
+    }
+
+    #[test]
+    fn cancelled_wait_starts_again() {
+        let mut keypad = Keypad::new();
+        assert_eq!(keypad.try_take_keypress(), None);
+        set(&mut keypad, 0x5, KeyState::Down);
+        keypad.cancel_wait();
+        assert_eq!(keypad.wait, KeyWait::NotWaiting);
+
+        set(&mut keypad, 0x5, KeyState::Up);
+        assert_eq!(keypad.try_take_keypress(), None);
+        assert_eq!(keypad.wait, KeyWait::WaitingForPress);
+    }
+
+    #[test]
+    fn tap_within_a_frame_is_down_for_one_frame() {
+        let mut keypad = Keypad::new();
+        keypad.update_key_state(0x6, KeyState::Down);
+        keypad.update_key_state(0x6, KeyState::Up);
+        keypad.update_key_state(0x7, KeyState::Down);
+
+        // Events stay in order, so the press of 7 waits behind the release of 6
+        keypad.process_events();
+        assert_eq!(keypad.key_states[0x6], KeyState::Down);
+        assert_eq!(keypad.key_states[0x7], KeyState::Up);
+        assert_eq!(keypad.events().len(), 2);
+
+        keypad.process_events();
+        assert_eq!(keypad.key_states[0x6], KeyState::Up);
+        assert_eq!(keypad.key_states[0x7], KeyState::Down);
+        assert!(keypad.events().is_empty());
+    }
+
+    #[test]
+    fn queue_is_bounded_and_keeps_the_last_state() {
+        let mut keypad = Keypad::new();
+        for i in 0..1000 {
+            let state = if i % 2 == 0 {
+                KeyState::Down
+            } else {
+                KeyState::Up
+            };
+            keypad.update_key_state(i % KEY_COUNT, state);
         }
+        assert_eq!(keypad.events().len(), MAX_QUEUED_EVENTS);
+
+        keypad.apply_all_events();
+        assert!(keypad.events().is_empty());
+        // Even keys were last pressed, and odd ones released
+        assert_eq!(key_mask(&keypad.key_states), 0x5555);
     }
 }
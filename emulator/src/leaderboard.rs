@@ -0,0 +1,149 @@
+use crate::{
+    page::{element_by_id, selected_rom_name},
+    roms,
+    session::Command,
+    storage,
+    worker::Backend,
+};
+use gloo_console::log;
+use gloo_events::EventListener;
+use gloo_utils::{document, window};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    HtmlButtonElement, HtmlInputElement, HtmlSelectElement, Request, RequestInit, Response,
+};
+
+const PLAYER_NAME_KEY: &str = "player-name";
+
+#[derive(Debug, Serialize)]
+struct NewScore<'a> {
+    rom: &'a str,
+    player: &'a str,
+    score: u32,
+    /// Lets the server check the score by playing the game again.
+    replay: &'a [u8],
+}
+
+#[derive(Debug, Deserialize)]
+struct Score {
+    player: String,
+    score: u32,
+}
+
+/// Sends a request to the server's API and returns the response body.
+async fn fetch(method: &str, url: &str, body: Option<String>) -> Result<String, JsValue> {
+    let init = RequestInit::new();
+    init.set_method(method);
+    if let Some(body) = body {
+        init.set_body(&body.into());
+    }
+
+    let request = Request::new_with_str_and_init(url, &init)?;
+    request.headers().set("Content-Type", "application/json")?;
+
+    let response = JsFuture::from(window().fetch_with_request(&request))
+        .await?
+        .dyn_into::<Response>()?;
+    let text = JsFuture::from(response.text()?).await?;
+    if !response.ok() {
+        return Err(text);
+    }
+
+    Ok(text.as_string().unwrap_or_default())
+}
+
+/// Lists the top scores for `rom_name`.
+async fn show_leaderboard(rom_name: String) {
+    let list = document().get_element_by_id("leaderboard").unwrap_throw();
+    list.set_text_content(None);
+
+    let scores = fetch("GET", &format!("/api/scores/{rom_name}"), None)
+        .await
+        .ok()
+        .and_then(|json| serde_json::from_str::<Vec<Score>>(&json).ok());
+    let Some(scores) = scores else {
+        list.set_text_content(Some("The leaderboard is unavailable."));
+        return;
+    };
+    if scores.is_empty() {
+        list.set_text_content(Some("No scores yet."));
+        return;
+    }
+
+    for Score { player, score } in scores {
+        let item = document().create_element("li").unwrap_throw();
+        item.set_text_content(Some(&format!("{player}: {score}")));
+        list.append_child(&item).unwrap_throw();
+    }
+}
+
+/// Submits a score read from the emulator, then shows the updated leaderboard.
+pub fn submit_score(rom_name: String, score: Option<u32>, replay: Option<Vec<u8>>) {
+    let Some(score) = score else {
+        log!("The score of", rom_name, "can't be read");
+        return;
+    };
+    let Some(replay) = replay else {
+        log!("Games that loaded a state or were stepped through can't be submitted");
+        return;
+    };
+    let player = element_by_id::<HtmlInputElement>("input-player-name").value();
+    let player = player.trim().to_owned();
+    if player.is_empty() {
+        log!("Enter a name to submit a score");
+        return;
+    }
+    storage::set(PLAYER_NAME_KEY, &player);
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let new_score = NewScore {
+            rom: &rom_name,
+            player: &player,
+            score,
+            replay: &replay,
+        };
+        let body = serde_json::to_string(&new_score).unwrap_throw();
+        if let Err(err) = fetch("POST", "/api/scores", Some(body)).await {
+            log!("Failed to submit score:", err);
+        }
+
+        show_leaderboard(rom_name).await;
+    });
+}
+
+/// Shows the selected game's leaderboard, and only enables submitting for games whose score can
+/// be read.
+fn show_selected_game() {
+    let rom_name = selected_rom_name();
+    element_by_id::<HtmlButtonElement>("btn-submit-score")
+        .set_disabled(roms::rom_info(&rom_name).score.is_none());
+    wasm_bindgen_futures::spawn_local(show_leaderboard(rom_name));
+}
+
+pub fn set_up_leaderboard_controls(backend: &Rc<Backend>) -> Vec<EventListener> {
+    if let Some(player) = storage::get(PLAYER_NAME_KEY) {
+        element_by_id::<HtmlInputElement>("input-player-name").set_value(&player);
+    }
+    show_selected_game();
+
+    // The score is submitted once the emulator replies with it
+    let on_submit = {
+        let backend = Rc::clone(backend);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-submit-score"),
+            "click",
+            move |_| backend.send(Command::ReadScore),
+        )
+    };
+
+    let on_select_game = EventListener::new(
+        &element_by_id::<HtmlSelectElement>("select-game"),
+        "change",
+        |_| show_selected_game(),
+    );
+
+    vec![on_submit, on_select_game]
+}
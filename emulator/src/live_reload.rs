@@ -0,0 +1,102 @@
+use crate::{
+    page::{element_by_id, selected_rom_name},
+    roms::ROMS_BY_NAME,
+    session::Command,
+    worker::Backend,
+};
+use gloo_console::log;
+use gloo_events::EventListener;
+use gloo_utils::{document, window};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{EventSource, HtmlSelectElement, MessageEvent};
+
+/// Where the game to resume after a reload is kept. Session storage is per tab, so other tabs
+/// reloading at the same time don't resume each other's games.
+const RESUME_KEY: &str = "live-reload/resume";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Resume {
+    rom_name: String,
+    state: Vec<u8>,
+}
+
+/// Connects to the dev server's events if it marked the page with
+/// `<meta name="live-reload" content="<url>">`. On `reload`, the running game is saved and resumed
+/// once the page has reloaded.
+pub fn set_up_live_reload(backend: &Rc<Backend>) -> Vec<EventListener> {
+    let Some(url) = document()
+        .query_selector(r#"meta[name="live-reload"]"#)
+        .ok()
+        .flatten()
+        .and_then(|meta| meta.get_attribute("content"))
+    else {
+        return Vec::new();
+    };
+    let Ok(event_source) = EventSource::new(&url) else {
+        return Vec::new();
+    };
+    log!("Live reload enabled");
+
+    // Reloads once the emulator replies with its state
+    let on_reload = {
+        let backend = Rc::clone(backend);
+        EventListener::new(&event_source, "reload", move |_| {
+            backend.send(Command::SaveState);
+        })
+    };
+
+    let on_build_failed = EventListener::new(&event_source, "build-failed", |event| {
+        let output = event.unchecked_ref::<MessageEvent>().data();
+        log!("Build failed:\n", output);
+    });
+
+    vec![on_reload, on_build_failed]
+}
+
+/// Saves `state` for [`resume`] and reloads the page.
+pub fn reload_with(state: Option<Vec<u8>>) {
+    if let (Some(state), Ok(Some(storage))) = (state, window().session_storage()) {
+        let resume = Resume {
+            rom_name: selected_rom_name(),
+            state,
+        };
+        let _ = storage.set_item(RESUME_KEY, &serde_json::to_string(&resume).unwrap_throw());
+    }
+
+    window().location().reload().unwrap_throw();
+}
+
+/// Resumes the game that was running before a live reload, if there was one.
+pub fn resume(backend: &Backend) {
+    let Ok(Some(storage)) = window().session_storage() else {
+        return;
+    };
+    let resume = storage
+        .get_item(RESUME_KEY)
+        .ok()
+        .flatten()
+        .and_then(|json| serde_json::from_str::<Resume>(&json).ok());
+    let _ = storage.remove_item(RESUME_KEY);
+
+    let Some(Resume { rom_name, state }) = resume else {
+        return;
+    };
+    let Some(rom) = ROMS_BY_NAME.get(&rom_name) else {
+        return;
+    };
+
+    // Let the palette and bindings follow the game, as if it had been selected
+    let select_game = element_by_id::<HtmlSelectElement>("select-game");
+    select_game.set_value(&rom_name);
+    select_game
+        .dispatch_event(&web_sys::Event::new("change").unwrap_throw())
+        .unwrap_throw();
+
+    backend.send(Command::LoadRom {
+        name: rom_name,
+        rom: rom.to_vec(),
+    });
+    backend.send(Command::LoadState { state });
+}
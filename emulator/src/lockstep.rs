@@ -0,0 +1,427 @@
+use crate::{
+    cpu::Cpu,
+    keypad::{self, KeyState, Keypad, KEY_COUNT},
+    state::StateError,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, mem};
+
+/// Frames between sampling a player's keys and running the frame they apply to, which gives
+/// them time to reach the other player.
+pub const INPUT_DELAY: u32 = 4;
+/// Frames between comparisons of the two players' machines.
+pub const HASH_INTERVAL: u32 = 60;
+
+/// What the two players send each other, through the server's relay.
+///
+/// Each carries the epoch it belongs to, which a [`Message::Resync`] advances, so that messages
+/// sent before a resync are ignored after it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Message {
+    /// The sender's keys during `frame`, as a bitmask.
+    Input { epoch: u32, frame: u32, keys: u16 },
+    /// [`Cpu::state_hash`] of the sender's machine after running up to `frame`.
+    Hash { epoch: u32, frame: u32, hash: u64 },
+    /// Sent by the host after the machines diverged: both players continue from `state` at
+    /// `frame`, with the random number sequence restarted from `seed`.
+    Resync {
+        epoch: u32,
+        frame: u32,
+        seed: u64,
+        state: Vec<u8>,
+    },
+}
+
+/// Keeps two players' machines in step, by only running a frame once both players' input for it
+/// has arrived. Player 0 is the host, which resolves desyncs.
+#[derive(Debug)]
+pub struct Lockstep {
+    player: usize,
+    /// The keys each player controls, as bitmasks.
+    masks: [u16; 2],
+    epoch: u32,
+    /// Next frame to run.
+    frame: u32,
+    /// Next frame to sample local input for.
+    input_frame: u32,
+    inputs: BTreeMap<u32, [Option<u16>; 2]>,
+    /// Hashes that have not been compared with the other player's yet.
+    hashes: BTreeMap<u32, [Option<u64>; 2]>,
+    /// Keys that were down during the last frame that ran.
+    keys: u16,
+    outbox: Vec<Message>,
+    /// The frame of the last desync, until taken.
+    desynced_at: Option<u32>,
+    /// Why the host's state couldn't be loaded, after which the game can't go on.
+    resync_error: Option<StateError>,
+}
+
+fn mask(keys: &[u8]) -> u16 {
+    keys.iter().fold(0, |mask, &key| mask | (1 << key))
+}
+
+impl Lockstep {
+    /// Starts a game at frame 0 as `player`, where `keys` are the keys each player controls.
+    #[must_use]
+    pub fn new(player: usize, keys: [&[u8]; 2]) -> Self {
+        let mut lockstep = Self {
+            player,
+            masks: keys.map(mask),
+            epoch: 0,
+            frame: 0,
+            input_frame: 0,
+            inputs: BTreeMap::new(),
+            hashes: BTreeMap::new(),
+            keys: 0,
+            outbox: Vec::new(),
+            desynced_at: None,
+            resync_error: None,
+        };
+        lockstep.restart(0, 0);
+        lockstep
+    }
+
+    /// Forgets all input and hashes, and continues from `frame`. Nobody can have pressed
+    /// anything in the first [`INPUT_DELAY`] frames.
+    fn restart(&mut self, epoch: u32, frame: u32) {
+        self.epoch = epoch;
+        self.frame = frame;
+        self.input_frame = frame + INPUT_DELAY;
+        self.inputs = (frame..self.input_frame)
+            .map(|frame| (frame, [Some(0); 2]))
+            .collect();
+        self.hashes.clear();
+        self.keys = 0;
+    }
+
+    /// Samples the local player's keys for the frame [`INPUT_DELAY`] frames ahead, unless input
+    /// is already that far ahead because the other player's input hasn't arrived.
+    pub fn sample_input(&mut self, key_states: &[KeyState; KEY_COUNT]) {
+        if self.input_frame > self.frame + INPUT_DELAY {
+            return;
+        }
+
+        let keys = keypad::key_mask(key_states) & self.masks[self.player];
+        let frame = self.input_frame;
+        self.inputs.entry(frame).or_default()[self.player] = Some(keys);
+        self.input_frame += 1;
+
+        self.outbox.push(Message::Input {
+            epoch: self.epoch,
+            frame,
+            keys,
+        });
+    }
+
+    /// Runs the next frame on `cpu` if both players' input for it has arrived, and returns
+    /// whether it did.
+    pub fn advance(&mut self, cpu: &mut Cpu) -> bool {
+        let Some(&[Some(host), Some(guest)]) = self.inputs.get(&self.frame) else {
+            return false;
+        };
+        self.inputs.remove(&self.frame);
+
+        let keys = (host & self.masks[0]) | (guest & self.masks[1]);
+        for key in (0..KEY_COUNT).filter(|key| (keys ^ self.keys) & (1 << key) != 0) {
+            let state = if keys & (1 << key) != 0 {
+                KeyState::Down
+            } else {
+                KeyState::Up
+            };
+            cpu.keypad.update_key_state(key, state);
+        }
+        self.keys = keys;
+
+        cpu.run_frame();
+        self.frame += 1;
+
+        if self.frame.is_multiple_of(HASH_INTERVAL) {
+            let hash = cpu.state_hash();
+            self.outbox.push(Message::Hash {
+                epoch: self.epoch,
+                frame: self.frame,
+                hash,
+            });
+            self.add_hash(cpu, self.player, self.frame, hash);
+        }
+
+        true
+    }
+
+    /// Handles a message from the other player.
+    pub fn receive(&mut self, cpu: &mut Cpu, message: Message) {
+        let peer = 1 - self.player;
+        match message {
+            Message::Input { epoch, frame, keys } if epoch == self.epoch && frame >= self.frame => {
+                self.inputs.entry(frame).or_default()[peer] = Some(keys);
+            }
+            Message::Hash { epoch, frame, hash } if epoch == self.epoch => {
+                self.add_hash(cpu, peer, frame, hash);
+            }
+            Message::Resync {
+                epoch,
+                frame,
+                seed,
+                state,
+            } if epoch > self.epoch && self.player != 0 => {
+                self.resync(cpu, epoch, frame, seed, &state);
+            }
+            _ => (),
+        }
+    }
+
+    fn add_hash(&mut self, cpu: &mut Cpu, player: usize, frame: u32, hash: u64) {
+        let hashes = self.hashes.entry(frame).or_default();
+        hashes[player] = Some(hash);
+        let [Some(host), Some(guest)] = *hashes else {
+            return;
+        };
+        self.hashes.remove(&frame);
+
+        if host != guest {
+            self.desynced_at = Some(frame);
+            if self.player == 0 {
+                self.send_resync(cpu);
+            }
+        }
+    }
+
+    /// Makes the guest continue from the host's current state.
+    fn send_resync(&mut self, cpu: &mut Cpu) {
+        let epoch = self.epoch + 1;
+        let frame = self.frame;
+        let seed = rand::random();
+        let state = cpu.save_state();
+
+        self.resync(cpu, epoch, frame, seed, &state);
+        self.outbox.push(Message::Resync {
+            epoch,
+            frame,
+            seed,
+            state,
+        });
+    }
+
+    fn resync(&mut self, cpu: &mut Cpu, epoch: u32, frame: u32, seed: u64, state: &[u8]) {
+        // The keypad is replaced, as its state isn't part of `state`
+        cpu.keypad = Keypad::new();
+        if let Err(err) = cpu.load_state(state) {
+            self.resync_error = Some(err);
+            return;
+        }
+        cpu.reseed(seed);
+        self.restart(epoch, frame);
+    }
+
+    /// Messages to send to the other player.
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        mem::take(&mut self.outbox)
+    }
+
+    /// The frame at which the machines were last found to differ, if they have since the last
+    /// call.
+    pub fn take_desync(&mut self) -> Option<u32> {
+        self.desynced_at.take()
+    }
+
+    /// Why a resync from the host failed, if one has. The machines can no longer be kept in step,
+    /// so the game has to end.
+    pub fn take_resync_error(&mut self) -> Option<StateError> {
+        self.resync_error.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roms::ROMS_BY_NAME;
+    use std::collections::VecDeque;
+
+    const KEYS: [&[u8]; 2] = [&[1, 4], &[0xC, 0xD]];
+
+    #[derive(Debug)]
+    struct Player {
+        lockstep: Lockstep,
+        cpu: Cpu,
+    }
+
+    fn players() -> [Player; 2] {
+        [0, 1].map(|player| Player {
+            lockstep: Lockstep::new(player, KEYS),
+            cpu: Cpu::with_seed(&ROMS_BY_NAME["PONG"], 3),
+        })
+    }
+
+    fn key_states(mask: u16) -> [KeyState; KEY_COUNT] {
+        std::array::from_fn(|key| {
+            if mask & (1 << key) != 0 {
+                KeyState::Down
+            } else {
+                KeyState::Up
+            }
+        })
+    }
+
+    /// Messages on their way between the players, each with the tick it arrives at.
+    type InFlight = VecDeque<(u32, usize, Message)>;
+
+    /// Runs `ticks` ticks in which each player holds `keys(player, tick)`, and each message
+    /// arrives `latency` ticks after it was sent.
+    fn play(
+        players: &mut [Player; 2],
+        in_flight: &mut InFlight,
+        ticks: std::ops::Range<u32>,
+        latency: u32,
+        keys: impl Fn(usize, u32) -> u16,
+    ) {
+        for tick in ticks {
+            while in_flight.front().is_some_and(|&(at, ..)| at <= tick) {
+                let (_, to, message) = in_flight.pop_front().unwrap();
+                let Player { lockstep, cpu } = &mut players[to];
+                lockstep.receive(cpu, message);
+            }
+
+            for (player, Player { lockstep, cpu }) in players.iter_mut().enumerate() {
+                lockstep.sample_input(&key_states(keys(player, tick)));
+                lockstep.advance(cpu);
+                for message in lockstep.take_messages() {
+                    in_flight.push_back((tick + latency, 1 - player, message));
+                }
+            }
+        }
+    }
+
+    /// Delivers every message in flight and runs both players as far as they can go.
+    fn settle(players: &mut [Player; 2], in_flight: &mut InFlight) {
+        while let Some((_, to, message)) = in_flight.pop_front() {
+            let Player { lockstep, cpu } = &mut players[to];
+            lockstep.receive(cpu, message);
+            while lockstep.advance(cpu) {}
+            for message in lockstep.take_messages() {
+                in_flight.push_back((0, 1 - to, message));
+            }
+        }
+    }
+
+    /// Both players press every key, switching between them every few frames.
+    fn mashing(player: usize, tick: u32) -> u16 {
+        if (tick / 7 + player as u32).is_multiple_of(2) {
+            u16::MAX
+        } else {
+            0
+        }
+    }
+
+    #[test]
+    fn players_stay_in_step_when_input_arrives_late() {
+        let mut players = players();
+        let mut in_flight = InFlight::new();
+        play(&mut players, &mut in_flight, 0..600, 3, mashing);
+        settle(&mut players, &mut in_flight);
+
+        let [host, guest] = &mut players;
+        assert!(host.lockstep.frame > 500);
+        assert_eq!(host.lockstep.frame, guest.lockstep.frame);
+        assert_eq!(host.cpu.state_hash(), guest.cpu.state_hash());
+        assert_eq!(host.lockstep.take_desync(), None);
+        assert_eq!(guest.lockstep.take_desync(), None);
+    }
+
+    #[test]
+    fn frames_wait_for_the_other_players_input() {
+        let [mut host, _] = players();
+        for _ in 0..10 {
+            host.lockstep.sample_input(&key_states(0));
+            host.lockstep.advance(&mut host.cpu);
+        }
+
+        // Only the frames before anyone could press anything ran, and input stopped being
+        // sampled once it got that far ahead
+        assert_eq!(host.lockstep.frame, INPUT_DELAY);
+        assert_eq!(
+            host.lockstep.take_messages().len(),
+            INPUT_DELAY as usize + 1
+        );
+    }
+
+    #[test]
+    fn players_only_press_their_own_keys() {
+        let [mut host, _] = players();
+        host.lockstep.sample_input(&key_states(u16::MAX));
+        for frame in 0..=INPUT_DELAY {
+            host.lockstep.receive(
+                &mut host.cpu,
+                Message::Input {
+                    epoch: 0,
+                    frame,
+                    keys: u16::MAX,
+                },
+            );
+        }
+        for _ in 0..=INPUT_DELAY {
+            assert!(host.lockstep.advance(&mut host.cpu));
+        }
+
+        let down = (0..KEY_COUNT)
+            .filter(|&key| host.cpu.keypad.key_states[key] == KeyState::Down)
+            .collect::<Vec<_>>();
+        assert_eq!(down, [1, 4, 0xC, 0xD]);
+    }
+
+    #[test]
+    fn drifting_apart_is_detected_and_resynced_from_the_host() {
+        let mut players = players();
+        let mut in_flight = InFlight::new();
+        play(&mut players, &mut in_flight, 0..30, 2, mashing);
+        players[1].cpu.memory[0xFFF] ^= 1;
+        play(&mut players, &mut in_flight, 30..90, 2, mashing);
+
+        // Both notice at the first comparison, and the host sends its state
+        let [host, guest] = &mut players;
+        assert_eq!(host.lockstep.take_desync(), Some(HASH_INTERVAL));
+        assert_eq!(guest.lockstep.take_desync(), Some(HASH_INTERVAL));
+        assert_eq!(host.lockstep.epoch, 1);
+
+        play(&mut players, &mut in_flight, 90..400, 2, mashing);
+        settle(&mut players, &mut in_flight);
+        let [host, guest] = &mut players;
+        assert_eq!(guest.lockstep.epoch, 1);
+        assert_eq!(guest.lockstep.take_resync_error(), None);
+        assert!(host.lockstep.frame > 300);
+        assert_eq!(host.lockstep.frame, guest.lockstep.frame);
+        assert_eq!(host.cpu.state_hash(), guest.cpu.state_hash());
+        assert_eq!(host.lockstep.take_desync(), None);
+        assert_eq!(guest.lockstep.take_desync(), None);
+    }
+
+    #[test]
+    fn failed_resync_is_reported() {
+        let mut cpu = Cpu::with_seed(&ROMS_BY_NAME["PONG"], 0);
+        let mut guest = Lockstep::new(1, [&[1, 4], &[0xC, 0xD]]);
+
+        let state = cpu.save_state();
+        guest.receive(
+            &mut cpu,
+            Message::Resync {
+                epoch: 1,
+                frame: 0,
+                seed: 0,
+                state: state[..100].to_vec(),
+            },
+        );
+        assert_eq!(guest.take_resync_error(), Some(StateError::Truncated));
+        assert_eq!(guest.take_resync_error(), None);
+
+        guest.receive(
+            &mut cpu,
+            Message::Resync {
+                epoch: 1,
+                frame: 0,
+                seed: 0,
+                state,
+            },
+        );
+        assert_eq!(guest.take_resync_error(), None);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{
+    lockstep::Message,
+    page::{element_by_id, selected_rom_name},
+    roms::ROMS_BY_NAME,
+    session::Command,
+    worker::Backend,
+};
+use gloo_console::log;
+use gloo_events::EventListener;
+use gloo_utils::window;
+use serde::Deserialize;
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{
+    HtmlButtonElement, HtmlElement, HtmlInputElement, HtmlSelectElement, MessageEvent, WebSocket,
+};
+
+/// What the server's netplay relay sends about the room.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum RoomMessage {
+    /// Joined an empty room as the host.
+    Waiting,
+    Start {
+        player: usize,
+        rom: String,
+        seed: u64,
+        keys: [Vec<u8>; 2],
+    },
+    PeerLeft,
+    Full,
+    /// A message from the other player.
+    Peer {
+        message: Message,
+    },
+}
+
+#[derive(Debug)]
+struct Connection {
+    socket: WebSocket,
+    _listeners: [EventListener; 2],
+}
+
+thread_local! {
+    /// The open netplay connection. Events from the emulator are plain functions, so they reach
+    /// it through here.
+    static CONNECTION: RefCell<Option<Connection>> = const { RefCell::new(None) };
+}
+
+fn show_status(status: &str) {
+    element_by_id::<HtmlElement>("netplay-status").set_text_content(Some(status));
+}
+
+fn show_connected(is_connected: bool) {
+    element_by_id::<HtmlButtonElement>("btn-netplay-join").set_disabled(is_connected);
+    element_by_id::<HtmlButtonElement>("btn-netplay-leave").set_disabled(!is_connected);
+}
+
+/// Sends `message` to the other player, if connected.
+pub fn send(message: &Message) {
+    CONNECTION.with_borrow(|connection| {
+        if let Some(Connection { socket, .. }) = connection {
+            if socket.ready_state() == WebSocket::OPEN {
+                let _ = socket.send_with_str(&serde_json::to_string(message).unwrap_throw());
+            }
+        }
+    });
+}
+
+pub fn show_desync(frame: u32) {
+    log!("Netplay desynced at frame", frame);
+    show_status(&format!(
+        "The games drifted apart at frame {frame}, so the host's was restored"
+    ));
+}
+
+/// Closes the connection, and returns whether there was one.
+fn close() -> bool {
+    let connection = CONNECTION.take();
+    let was_connected = connection.is_some();
+    if let Some(connection) = connection {
+        let _ = connection.socket.close();
+
+        // This may be called from one of the connection's own listeners, which can't be dropped
+        // while they run
+        wasm_bindgen_futures::spawn_local(async move { drop(connection) });
+    }
+    show_connected(false);
+    was_connected
+}
+
+fn leave(backend: &Backend) {
+    if close() {
+        backend.send(Command::StopNetplay);
+    }
+}
+
+/// Leaves the room after the emulator has already stopped the game, showing why.
+pub fn disconnect(reason: &str) {
+    log!("Left netplay:", reason);
+    close();
+    show_status(reason);
+}
+
+fn on_room_message(backend: &Backend, room: &str, message: RoomMessage) {
+    match message {
+        RoomMessage::Waiting => show_status(&format!("Waiting for player 2 to join {room}...")),
+        RoomMessage::Start {
+            player,
+            rom,
+            seed,
+            keys,
+        } => {
+            let Some(rom_buf) = ROMS_BY_NAME.get(&rom) else {
+                return;
+            };
+
+            // Let the palette and bindings follow the game, as if it had been selected
+            let select_game = element_by_id::<HtmlSelectElement>("select-game");
+            select_game.set_value(&rom);
+            select_game
+                .dispatch_event(&web_sys::Event::new("change").unwrap_throw())
+                .unwrap_throw();
+
+            let own_keys = keys[player]
+                .iter()
+                .map(|key| format!("{key:X}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            show_status(&format!(
+                "Playing {rom} as player {}, with keys {own_keys}",
+                player + 1
+            ));
+
+            backend.send(Command::StartNetplay {
+                name: rom,
+                rom: rom_buf.to_vec(),
+                player,
+                seed,
+                keys,
+            });
+        }
+        RoomMessage::PeerLeft => {
+            leave(backend);
+            show_status("The other player left");
+        }
+        RoomMessage::Full => {
+            leave(backend);
+            show_status(&format!("{room} already has two players"));
+        }
+        RoomMessage::Peer { message } => backend.send(Command::Netplay { message }),
+    }
+}
+
+/// Joins netplay room `room`, which starts a game of `rom_name` once a second player joins.
+fn join(backend: &Rc<Backend>, room: &str, rom_name: &str) -> Result<(), JsValue> {
+    let location = window().location();
+    let scheme = if location.protocol()? == "https:" {
+        "wss"
+    } else {
+        "ws"
+    };
+    let url = format!(
+        "{scheme}://{}/api/netplay/{}?rom={rom_name}",
+        location.host()?,
+        js_sys::encode_uri_component(room),
+    );
+    let socket = WebSocket::new(&url)?;
+
+    let on_message = {
+        let backend = Rc::clone(backend);
+        let room = room.to_owned();
+        EventListener::new(&socket, "message", move |event| {
+            let data = event.unchecked_ref::<MessageEvent>().data();
+            // Everything comes from the relay, which wraps what the other player sends
+            if let Some(message) = data
+                .as_string()
+                .and_then(|message| serde_json::from_str::<RoomMessage>(&message).ok())
+            {
+                on_room_message(&backend, &room, message);
+            }
+        })
+    };
+
+    let on_close = {
+        let backend = Rc::clone(backend);
+        EventListener::new(&socket, "close", move |_| {
+            if CONNECTION.with_borrow(Option::is_some) {
+                leave(&backend);
+                show_status("Disconnected from the server");
+            }
+        })
+    };
+
+    leave(backend);
+    CONNECTION.set(Some(Connection {
+        socket,
+        _listeners: [on_message, on_close],
+    }));
+    show_connected(true);
+    show_status(&format!("Joining {room}..."));
+    Ok(())
+}
+
+pub fn set_up_netplay_controls(backend: &Rc<Backend>) -> Vec<EventListener> {
+    show_connected(false);
+
+    let on_join = {
+        let backend = Rc::clone(backend);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-netplay-join"),
+            "click",
+            move |_| {
+                let room = element_by_id::<HtmlInputElement>("input-netplay-room").value();
+                let room = room.trim();
+                if room.is_empty() {
+                    show_status("Enter a room name to share with the other player");
+                    return;
+                }
+
+                if let Err(err) = join(&backend, room, &selected_rom_name()) {
+                    log!("Failed to join netplay room:", err);
+                }
+            },
+        )
+    };
+
+    let on_leave = {
+        let backend = Rc::clone(backend);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-netplay-leave"),
+            "click",
+            move |_| {
+                leave(&backend);
+                show_status("");
+            },
+        )
+    };
+
+    vec![on_join, on_leave]
+}
@@ -1,10 +1,10 @@
 use crate::{cpu::Cpu, keypad::KeyState};
-use rand::random;
+use rand::Rng;
 
-const BYTES_PER_SPRITE: u16 = 5;
+pub(crate) const BYTES_PER_SPRITE: u16 = 5;
 
 #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Opcode {
     /// `0nnn` - Jump to a machine code routine at nnn. (ignored)
     SYS,
@@ -39,11 +39,11 @@ pub enum Opcode {
     /// `8xy5` - Set Vx = Vx - Vy, set VF = NOT borrow.
     SUB_R { vx: u8, vy: u8 },
     /// `8xy6` - Set Vx = Vx SHR 1.
-    SHR { vx: u8 },
+    SHR { vx: u8, vy: u8 },
     /// `8xy7` - Set Vx = Vy - Vx, set VF = NOT borrow.
     SUBN_R { vx: u8, vy: u8 },
     /// `8xyE` - Set Vx = Vx SHL 1.
-    SHL { vx: u8 },
+    SHL { vx: u8, vy: u8 },
     /// `9xy0` - Skip next instruction if Vx != Vy.
     SNE_R { vx: u8, vy: u8 },
     /// `Annn` - Set I = nnn.
@@ -133,12 +133,18 @@ impl From<u16> for Opcode {
                     vx: vx(opcode),
                     vy: vy(opcode),
                 },
-                0x6 => Self::SHR { vx: vx(opcode) },
+                0x6 => Self::SHR {
+                    vx: vx(opcode),
+                    vy: vy(opcode),
+                },
                 0x7 => Self::SUBN_R {
                     vx: vx(opcode),
                     vy: vy(opcode),
                 },
-                0xE => Self::SHL { vx: vx(opcode) },
+                0xE => Self::SHL {
+                    vx: vx(opcode),
+                    vy: vy(opcode),
+                },
                 _ => unimplemented!("Opcode not implemented!"),
             },
             0x9 => Self::SNE_R {
@@ -223,7 +229,7 @@ impl Opcode {
         match *self {
             Self::SYS => (),
             Self::CLS => {
-                cpu.view.clear();
+                cpu.framebuffer.clear();
             }
             Self::RET => {
                 cpu.sp -= 1;
@@ -263,12 +269,21 @@ impl Opcode {
             }
             Self::OR_R { vx, vy } => {
                 cpu.regs[vx as usize] |= cpu.regs[vy as usize];
+                if cpu.quirks.logic_resets_vf {
+                    cpu.regs[0xF] = 0;
+                }
             }
             Self::AND_R { vx, vy } => {
                 cpu.regs[vx as usize] &= cpu.regs[vy as usize];
+                if cpu.quirks.logic_resets_vf {
+                    cpu.regs[0xF] = 0;
+                }
             }
             Self::XOR_R { vx, vy } => {
                 cpu.regs[vx as usize] ^= cpu.regs[vy as usize];
+                if cpu.quirks.logic_resets_vf {
+                    cpu.regs[0xF] = 0;
+                }
             }
             Self::ADD_R { vx, vy } => {
                 let (sum, carry) = cpu.regs[vx as usize].overflowing_add(cpu.regs[vy as usize]);
@@ -280,18 +295,22 @@ impl Opcode {
                 cpu.regs[vx as usize] = diff;
                 cpu.regs[0xF] = (!borrow).into();
             }
-            Self::SHR { vx } => {
-                cpu.regs[0xF] = cpu.regs[vx as usize] & 1;
-                cpu.regs[vx as usize] >>= 1;
+            Self::SHR { vx, vy } => {
+                let src = if cpu.quirks.shift_uses_vy { vy } else { vx };
+                let val = cpu.regs[src as usize];
+                cpu.regs[0xF] = val & 1;
+                cpu.regs[vx as usize] = val >> 1;
             }
             Self::SUBN_R { vx, vy } => {
                 let (diff, borrow) = cpu.regs[vy as usize].overflowing_sub(cpu.regs[vx as usize]);
                 cpu.regs[vx as usize] = diff;
                 cpu.regs[0xF] = (!borrow).into();
             }
-            Self::SHL { vx } => {
-                cpu.regs[0xF] = cpu.regs[vx as usize] >> 7;
-                cpu.regs[vx as usize] <<= 1;
+            Self::SHL { vx, vy } => {
+                let src = if cpu.quirks.shift_uses_vy { vy } else { vx };
+                let val = cpu.regs[src as usize];
+                cpu.regs[0xF] = val >> 7;
+                cpu.regs[vx as usize] = val << 1;
             }
             Self::SNE_R { vx, vy } => {
                 if cpu.regs[vx as usize] != cpu.regs[vy as usize] {
@@ -302,28 +321,33 @@ impl Opcode {
                 cpu.i_reg = addr;
             }
             Self::JP_A { addr } => {
-                cpu.pc = (cpu.regs[0x0] as u16).wrapping_add(addr);
+                let vx = if cpu.quirks.jump_uses_vx {
+                    addr >> 8
+                } else {
+                    0x0
+                };
+                cpu.pc = (cpu.regs[vx as usize] as u16).wrapping_add(addr);
             }
             Self::RND { vx, byte } => {
-                cpu.regs[vx as usize] = random::<u8>() & byte;
+                cpu.regs[vx as usize] = cpu.rng.random::<u8>() & byte;
             }
             Self::DRW { vx, vy, n } => {
-                let collision = cpu.view.draw_sprite(
+                let collision = cpu.framebuffer.draw_sprite(
                     &cpu.memory[cpu.i_reg as usize..(cpu.i_reg + n as u16) as usize],
-                    n as u32,
                     cpu.regs[vx as usize] as u32,
                     cpu.regs[vy as usize] as u32,
+                    cpu.quirks.wrap_sprites,
                 );
                 cpu.regs[0xF] = collision.into();
             }
             Self::SKP { vx } => {
-                let key_states = cpu.keypad.borrow().key_states;
+                let key_states = cpu.keypad.key_states;
                 if key_states[cpu.regs[vx as usize] as usize] == KeyState::Down {
                     cpu.push_pc();
                 }
             }
             Self::SKNP { vx } => {
-                let key_states = cpu.keypad.borrow().key_states;
+                let key_states = cpu.keypad.key_states;
                 if key_states[cpu.regs[vx as usize] as usize] == KeyState::Up {
                     cpu.push_pc();
                 }
@@ -332,9 +356,9 @@ impl Opcode {
                 cpu.regs[vx as usize] = cpu.delay_timer;
             }
             Self::LD_R_K { vx } => {
-                let last_keypress = cpu.keypad.borrow_mut().try_take_last_keypress();
-                if let Some(last_keypress) = last_keypress {
-                    cpu.regs[vx as usize] = last_keypress as u8;
+                let keypress = cpu.keypad.try_take_keypress();
+                if let Some(keypress) = keypress {
+                    cpu.regs[vx as usize] = keypress as u8;
                 } else {
                     cpu.undo_pc();
                 }
@@ -357,16 +381,24 @@ impl Opcode {
                     cpu.memory[(cpu.i_reg + i) as usize] = vx_val % 10;
                     vx_val /= 10;
                 }
+                cpu.invalidate_decoded(cpu.i_reg, 3);
             }
             Self::LD_I_R { vx } => {
                 for vi in 0..=vx {
                     cpu.memory[cpu.i_reg as usize + vi as usize] = cpu.regs[vi as usize];
                 }
+                cpu.invalidate_decoded(cpu.i_reg, vx as usize + 1);
+                if cpu.quirks.load_store_increments_i {
+                    cpu.i_reg += vx as u16 + 1;
+                }
             }
             Self::LD_R_I { vx } => {
                 for vi in 0..=vx {
                     cpu.regs[vi as usize] = cpu.memory[cpu.i_reg as usize + vi as usize];
                 }
+                if cpu.quirks.load_store_increments_i {
+                    cpu.i_reg += vx as u16 + 1;
+                }
             }
         }
     }
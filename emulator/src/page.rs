@@ -0,0 +1,573 @@
+use crate::{
+    bindings::{self, InputSource, Remapper},
+    emulator::EmulatorState,
+    filter::Phosphor,
+    gamepad,
+    keypad::{KeyInput, KeyPressListeners, SharedKeyStates},
+    leaderboard, live_reload, netplay,
+    palette::{Color, Palette, THEMES},
+    roms::ROMS_BY_NAME,
+    session::{Command, Event, Session},
+    spectate, storage,
+    touch::{self, TouchKeypad},
+    view::{self, View},
+    worker::{self, Backend, EmulatorWorker},
+};
+use gloo_console::log;
+use gloo_events::EventListener;
+use gloo_utils::{document, window};
+use std::{cell::RefCell, mem, panic, rc::Rc};
+use wasm_bindgen::prelude::*;
+use web_sys::{
+    Blob, BlobPropertyBag, HtmlAnchorElement, HtmlButtonElement, HtmlCanvasElement,
+    HtmlInputElement, HtmlOptionElement, HtmlSelectElement, KeyboardEvent, Url, Window,
+};
+
+const CUSTOM_PALETTE: &str = "Custom";
+/// How long a download's object URL is kept for. `click` only starts the download, which may read
+/// the URL later.
+const DOWNLOAD_URL_LIFETIME_MS: i32 = 60_000;
+
+pub(crate) fn element_by_id<T: JsCast>(id: &str) -> T {
+    document()
+        .get_element_by_id(id)
+        .unwrap_throw()
+        .dyn_into::<T>()
+        .unwrap_throw()
+}
+
+pub(crate) fn selected_rom_name() -> String {
+    element_by_id::<HtmlSelectElement>("select-game").value()
+}
+
+/// Reflects the emulator's state in the play controls.
+fn show_emulator_state(state: EmulatorState) {
+    let btn_pause = element_by_id::<HtmlButtonElement>("btn-pause");
+    btn_pause.set_disabled(state == EmulatorState::Stopped);
+    btn_pause.set_text_content(Some(if state == EmulatorState::Paused {
+        "Resume"
+    } else {
+        "Pause"
+    }));
+
+    element_by_id::<HtmlButtonElement>("btn-reset").set_disabled(state == EmulatorState::Stopped);
+}
+
+/// Reacts to events from the emulator, wherever it runs.
+fn on_emulator_event(event: Event) {
+    match event {
+        Event::Ready => log!("Started emulator worker"),
+        Event::StateChanged { state } => show_emulator_state(state),
+        Event::Screenshot { png } => {
+            download(&png, &format!("{}.png", selected_rom_name()), "image/png");
+        }
+        Event::Recording { apng } => download(
+            &apng,
+            &format!("{}-recording.png", selected_rom_name()),
+            "image/apng",
+        ),
+        Event::Score {
+            rom_name,
+            score,
+            replay,
+        } => leaderboard::submit_score(rom_name, score, replay),
+        Event::Netplay { message } => netplay::send(&message),
+        Event::Broadcast { frame } => spectate::send(&frame),
+        Event::NetplayDesynced { frame } => netplay::show_desync(frame),
+        Event::NetplayFailed { message } => netplay::disconnect(&message),
+        Event::StateSaved { state } => live_reload::reload_with(state),
+        Event::Error { message } => log!(message),
+    }
+}
+
+fn set_up_emulator_controls(backend: &Rc<Backend>) -> Vec<EventListener> {
+    show_emulator_state(EmulatorState::Stopped);
+
+    // Sends `command` to the emulator, which reports any state change back
+    let control = |command: Command| {
+        let backend = Rc::clone(backend);
+        move |_: &web_sys::Event| backend.send(command.clone())
+    };
+
+    let on_play = {
+        let backend = Rc::clone(backend);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-play"),
+            "click",
+            move |_| {
+                let rom_name = selected_rom_name();
+                let rom_buf = ROMS_BY_NAME.get(&rom_name).unwrap_throw();
+                backend.send(Command::LoadRom {
+                    name: rom_name,
+                    rom: rom_buf.to_vec(),
+                });
+            },
+        )
+    };
+
+    let on_pause = EventListener::new(
+        &element_by_id::<HtmlButtonElement>("btn-pause"),
+        "click",
+        control(Command::TogglePause),
+    );
+
+    let on_reset = EventListener::new(
+        &element_by_id::<HtmlButtonElement>("btn-reset"),
+        "click",
+        control(Command::Reset),
+    );
+
+    // Escape pauses or resumes, and F2 resets
+    let on_hotkey = {
+        let toggle_pause = control(Command::TogglePause);
+        let reset = control(Command::Reset);
+        EventListener::new(&window(), "keydown", move |event| {
+            let keyboard_event = event.dyn_ref::<KeyboardEvent>().unwrap_throw();
+            // Keys already handled, e.g. while remapping, aren't hotkeys
+            if keyboard_event.default_prevented() || keyboard_event.repeat() {
+                return;
+            }
+
+            match keyboard_event.code().as_str() {
+                "Escape" => toggle_pause(event),
+                "F2" => {
+                    keyboard_event.prevent_default();
+                    reset(event);
+                }
+                _ => (),
+            }
+        })
+    };
+
+    let on_visibility_change = {
+        let backend = Rc::clone(backend);
+        EventListener::new(&document(), "visibilitychange", move |_| {
+            backend.send(Command::SetPageHidden {
+                is_hidden: document().hidden(),
+            });
+        })
+    };
+
+    vec![on_play, on_pause, on_reset, on_hotkey, on_visibility_change]
+}
+
+fn palette_storage_key(rom_name: &str) -> String {
+    format!("palette/{rom_name}")
+}
+
+/// Reflects `palette` in the palette controls, with `choice` selected: the name of a built-in
+/// theme, or [`CUSTOM_PALETTE`].
+fn show_palette(palette: Palette, choice: &str) {
+    element_by_id::<HtmlSelectElement>("select-palette").set_value(choice);
+    element_by_id::<HtmlInputElement>("input-background")
+        .set_value(&palette.background.to_string());
+    element_by_id::<HtmlInputElement>("input-foreground")
+        .set_value(&palette.foreground.to_string());
+}
+
+/// Applies the palette saved for the selected ROM, falling back to the default.
+///
+/// A theme is saved by name and custom colours as colours, so colours picked by hand stay custom
+/// even if they match a theme.
+fn load_palette(backend: &Backend) {
+    let saved = storage::get(&palette_storage_key(&selected_rom_name()))
+        .unwrap_or_else(|| THEMES[0].0.to_owned());
+    let (palette, choice) = match (Palette::from_theme_name(&saved), saved.parse()) {
+        (Some(palette), _) => (palette, saved.as_str()),
+        (None, Ok(palette)) => (palette, CUSTOM_PALETTE),
+        (None, Err(())) => (Palette::default(), THEMES[0].0),
+    };
+
+    backend.send(Command::SetPalette { palette });
+    show_palette(palette, choice);
+}
+
+/// Applies and saves `palette`, chosen as `choice` like in [`show_palette`].
+fn save_palette(backend: &Backend, palette: Palette, choice: &str) {
+    let saved = if choice == CUSTOM_PALETTE {
+        palette.to_string()
+    } else {
+        choice.to_owned()
+    };
+    storage::set(&palette_storage_key(&selected_rom_name()), &saved);
+
+    backend.send(Command::SetPalette { palette });
+    show_palette(palette, choice);
+}
+
+fn set_up_palette_controls(backend: &Rc<Backend>) -> Vec<EventListener> {
+    let select_palette = element_by_id::<HtmlSelectElement>("select-palette");
+    for name in THEMES.iter().map(|(name, _)| *name).chain([CUSTOM_PALETTE]) {
+        let option = HtmlOptionElement::new_with_text(name).unwrap_throw();
+        select_palette
+            .add_with_html_option_element(&option)
+            .unwrap_throw();
+    }
+
+    load_palette(backend);
+
+    let custom_palette = || {
+        let color = |id| Color::from_hex(&element_by_id::<HtmlInputElement>(id).value());
+        Some(Palette::new(
+            color("input-background")?,
+            color("input-foreground")?,
+        ))
+    };
+
+    let on_select_game = {
+        let backend = Rc::clone(backend);
+        EventListener::new(
+            &element_by_id::<HtmlSelectElement>("select-game"),
+            "change",
+            move |_| load_palette(&backend),
+        )
+    };
+
+    let on_select_palette = {
+        let backend = Rc::clone(backend);
+        EventListener::new(&select_palette.clone(), "change", move |_| {
+            let choice = select_palette.value();
+            // Choosing Custom keeps the colours shown, to be changed from there
+            if let Some(palette) = Palette::from_theme_name(&choice).or_else(custom_palette) {
+                save_palette(&backend, palette, &choice);
+            }
+        })
+    };
+
+    let on_input_colors = ["input-background", "input-foreground"].map(|id| {
+        let backend = Rc::clone(backend);
+        EventListener::new(&element_by_id::<HtmlInputElement>(id), "input", move |_| {
+            if let Some(palette) = custom_palette() {
+                save_palette(&backend, palette, CUSTOM_PALETTE);
+            }
+        })
+    });
+
+    [on_select_game, on_select_palette]
+        .into_iter()
+        .chain(on_input_colors)
+        .collect()
+}
+
+/// Reads the phosphor filter from its controls. Strength ranges from 0 to 100.
+fn selected_phosphor() -> Phosphor {
+    let strength = element_by_id::<HtmlInputElement>("input-phosphor-strength").value_as_number();
+    match element_by_id::<HtmlSelectElement>("select-phosphor")
+        .value()
+        .as_str()
+    {
+        "decay" => Phosphor::Decay {
+            persistence: (0.9 * strength / 100.) as f32,
+        },
+        "frame-or" => Phosphor::FrameOr {
+            frames: 2 + (3. * strength / 100.).round() as usize,
+        },
+        _ => Phosphor::Off,
+    }
+}
+
+fn set_up_phosphor_controls(backend: &Rc<Backend>) -> Vec<EventListener> {
+    let set_phosphor = |backend: &Backend| {
+        backend.send(Command::SetPhosphor {
+            phosphor: selected_phosphor(),
+        });
+    };
+    set_phosphor(backend);
+
+    let on_change = || {
+        let backend = Rc::clone(backend);
+        move |_: &web_sys::Event| set_phosphor(&backend)
+    };
+
+    vec![
+        EventListener::new(
+            &element_by_id::<HtmlSelectElement>("select-phosphor"),
+            "change",
+            on_change(),
+        ),
+        EventListener::new(
+            &element_by_id::<HtmlInputElement>("input-phosphor-strength"),
+            "input",
+            on_change(),
+        ),
+    ]
+}
+
+fn set_up_jit_controls(backend: &Rc<Backend>) -> EventListener {
+    let input_jit = element_by_id::<HtmlInputElement>("input-jit");
+    backend.send(Command::SetJit {
+        is_on: input_jit.checked(),
+    });
+
+    let backend = Rc::clone(backend);
+    EventListener::new(&input_jit.clone(), "change", move |_| {
+        backend.send(Command::SetJit {
+            is_on: input_jit.checked(),
+        });
+    })
+}
+
+/// Saves `bytes` to the user's downloads as `file_name`.
+fn download(bytes: &[u8], file_name: &str, mime_type: &str) {
+    let options = BlobPropertyBag::new();
+    options.set_type(mime_type);
+    let blob = Blob::new_with_u8_array_sequence_and_options(
+        &js_sys::Array::of1(&js_sys::Uint8Array::from(bytes)),
+        &options,
+    )
+    .unwrap_throw();
+    let url = Url::create_object_url_with_blob(&blob).unwrap_throw();
+
+    let anchor = document()
+        .create_element("a")
+        .unwrap_throw()
+        .dyn_into::<HtmlAnchorElement>()
+        .unwrap_throw();
+    anchor.set_href(&url);
+    anchor.set_download(file_name);
+    anchor.click();
+
+    let revoke = Closure::once_into_js(move || {
+        let _ = Url::revoke_object_url(&url);
+    });
+    window()
+        .set_timeout_with_callback_and_timeout_and_arguments_0(
+            revoke.unchecked_ref(),
+            DOWNLOAD_URL_LIFETIME_MS,
+        )
+        .unwrap_throw();
+}
+
+/// The scale in the capture controls, or 1 if it is empty or not a number.
+fn selected_capture_scale() -> u32 {
+    let scale = element_by_id::<HtmlInputElement>("input-capture-scale").value_as_number();
+    if scale.is_nan() {
+        1
+    } else {
+        scale.clamp(1., 64.) as u32
+    }
+}
+
+fn set_up_capture_controls(backend: &Rc<Backend>) -> Vec<EventListener> {
+    // The recording is downloaded once the emulator sends it back
+    let on_screenshot = {
+        let backend = Rc::clone(backend);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-screenshot"),
+            "click",
+            move |_| {
+                backend.send(Command::Screenshot {
+                    scale: selected_capture_scale(),
+                });
+            },
+        )
+    };
+
+    let btn_record = element_by_id::<HtmlButtonElement>("btn-record");
+    let on_record = {
+        let backend = Rc::clone(backend);
+        let mut is_recording = false;
+        EventListener::new(&btn_record.clone(), "click", move |_| {
+            is_recording = !is_recording;
+            if is_recording {
+                backend.send(Command::StartRecording);
+                btn_record.set_text_content(Some("Stop recording"));
+            } else {
+                backend.send(Command::StopRecording {
+                    scale: selected_capture_scale(),
+                });
+                btn_record.set_text_content(Some("Record"));
+            }
+        })
+    };
+
+    vec![on_screenshot, on_record]
+}
+
+fn set_up_remapper_buttons(
+    remapper: &Rc<RefCell<Remapper>>,
+    gamepad_remapper: &Rc<RefCell<Remapper>>,
+) -> Vec<EventListener> {
+    let on_select_game = {
+        let remapper = Rc::clone(remapper);
+        let gamepad_remapper = Rc::clone(gamepad_remapper);
+        EventListener::new(
+            &element_by_id::<HtmlSelectElement>("select-game"),
+            "change",
+            move |_| {
+                let rom_name = selected_rom_name();
+                remapper.borrow_mut().load(&rom_name);
+                gamepad_remapper.borrow_mut().load(&rom_name);
+            },
+        )
+    };
+
+    let on_export = {
+        let remapper = Rc::clone(remapper);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-export-bindings"),
+            "click",
+            move |_| {
+                download(
+                    remapper.borrow().to_json().as_bytes(),
+                    "key-bindings.json",
+                    "application/json",
+                );
+            },
+        )
+    };
+
+    let on_reset = {
+        let remapper = Rc::clone(remapper);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-reset-bindings"),
+            "click",
+            move |_| remapper.borrow_mut().reset(),
+        )
+    };
+
+    let on_reset_gamepad = {
+        let gamepad_remapper = Rc::clone(gamepad_remapper);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-reset-gamepad"),
+            "click",
+            move |_| gamepad_remapper.borrow_mut().reset(),
+        )
+    };
+
+    vec![on_select_game, on_export, on_reset, on_reset_gamepad]
+}
+
+fn set_up_touch_keypad_layout(touch_keypad: &Rc<RefCell<TouchKeypad>>) -> Vec<EventListener> {
+    let on_select_game = {
+        let touch_keypad = Rc::clone(touch_keypad);
+        EventListener::new(
+            &element_by_id::<HtmlSelectElement>("select-game"),
+            "change",
+            move |_| touch_keypad.borrow().show_layout(&selected_rom_name()),
+        )
+    };
+
+    let on_toggle_compact = {
+        let touch_keypad = Rc::clone(touch_keypad);
+        let input_compact = touch_keypad.borrow().input_compact.clone();
+        EventListener::new(&input_compact, "change", move |_| {
+            touch_keypad.borrow().show_layout(&selected_rom_name());
+        })
+    };
+
+    vec![on_select_game, on_toggle_compact]
+}
+
+#[wasm_bindgen(start)]
+pub fn entry() {
+    log!("Setting up emulator...");
+
+    panic::set_hook(Box::new(console_error_panic_hook::hook));
+
+    // The worker (see `worker::run_worker`) and pages embedding the emulator through `api::Chip8`
+    // set it up themselves
+    if !js_sys::global().is_instance_of::<Window>()
+        || document().get_element_by_id("select-game").is_none()
+    {
+        return;
+    }
+
+    // Run the emulator in a worker when possible, so that work on the page can't stall it
+    let canvas = element_by_id::<HtmlCanvasElement>("view");
+    let key_states = worker::is_supported(&canvas).then(SharedKeyStates::new);
+    let keypad = Rc::new(RefCell::new(match &key_states {
+        Some(key_states) => KeyInput::forwarding_to(key_states.clone()),
+        None => KeyInput::new(),
+    }));
+
+    let worker = key_states
+        .as_ref()
+        .map(|key_states| EmulatorWorker::new(&canvas, key_states, on_emulator_event));
+
+    let mut render_loop = None;
+    let backend = Rc::new(match worker {
+        Some(Ok(worker)) => Backend::Worker(worker),
+        worker => {
+            if let Some(Err(err)) = worker {
+                log!("Failed to start emulator worker:", err);
+            }
+
+            let session = Rc::new(RefCell::new(Session::new(
+                View::with_canvas(&canvas),
+                Rc::clone(&keypad),
+            )));
+            render_loop = Some({
+                let session = Rc::clone(&session);
+                view::set_up_render_loop(move |ticks| {
+                    let events = session.borrow_mut().run(ticks);
+                    for event in events {
+                        on_emulator_event(event);
+                    }
+                })
+            });
+            Backend::Local {
+                session,
+                on_event: on_emulator_event,
+            }
+        }
+    });
+    log!("Set up render loop");
+
+    let remapper = Rc::new(RefCell::new(Remapper::new(
+        InputSource::Keyboard,
+        &selected_rom_name(),
+    )));
+
+    let gamepad_remapper = Rc::new(RefCell::new(Remapper::new(
+        InputSource::Gamepad,
+        &selected_rom_name(),
+    )));
+    let key_press_listeners = KeyPressListeners::new(&keypad, &remapper, &gamepad_remapper);
+    let gamepad_polling = gamepad::set_up_gamepad_polling(&keypad, &gamepad_remapper);
+
+    let touch_keypad = Rc::new(RefCell::new(TouchKeypad::new(&selected_rom_name())));
+    let touch_keypad_listeners = touch::set_up_touch_keypad_controls(&touch_keypad, &keypad)
+        .into_iter()
+        .chain(set_up_touch_keypad_layout(&touch_keypad));
+
+    let remapper_listeners = bindings::set_up_remapper_controls(&remapper)
+        .into_iter()
+        .chain(bindings::set_up_remapper_controls(&gamepad_remapper))
+        .chain(set_up_remapper_buttons(&remapper, &gamepad_remapper));
+
+    let palette_listeners = set_up_palette_controls(&backend);
+    let phosphor_listeners = set_up_phosphor_controls(&backend);
+    let jit_listener = set_up_jit_controls(&backend);
+    let capture_listeners = set_up_capture_controls(&backend);
+    let emulator_listeners = set_up_emulator_controls(&backend);
+    let leaderboard_listeners = leaderboard::set_up_leaderboard_controls(&backend);
+    let netplay_listeners = netplay::set_up_netplay_controls(&backend);
+    let broadcast_listeners = spectate::set_up_broadcast_controls(&backend);
+    let live_reload_listeners = live_reload::set_up_live_reload(&backend);
+    live_reload::resume(&backend);
+
+    // Leaking is fine as the listeners should live forever
+    key_press_listeners.on_keydown.forget();
+    key_press_listeners.on_keyup.forget();
+    key_press_listeners.on_blur.forget();
+    key_press_listeners.on_visibility_change.forget();
+    mem::forget(gamepad_polling);
+    mem::forget(render_loop);
+    mem::forget(backend);
+    for listener in emulator_listeners
+        .into_iter()
+        .chain(palette_listeners)
+        .chain(phosphor_listeners)
+        .chain([jit_listener])
+        .chain(capture_listeners)
+        .chain(remapper_listeners)
+        .chain(touch_keypad_listeners)
+        .chain(leaderboard_listeners)
+        .chain(netplay_listeners)
+        .chain(broadcast_listeners)
+        .chain(live_reload_listeners)
+    {
+        listener.forget();
+    }
+}
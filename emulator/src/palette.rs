@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    #[must_use]
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Parses a `#rrggbb` colour, as produced by `<input type="color">`, or the CSS shorthand
+    /// `#rgb`.
+    #[must_use]
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+        match hex.len() {
+            6 => Some(Self::new(
+                channel(&hex[0..2])?,
+                channel(&hex[2..4])?,
+                channel(&hex[4..6])?,
+            )),
+            // Each digit is doubled, so `#f80` is `#ff8800`
+            3 => Some(Self::new(
+                channel(&hex[0..1])? * 0x11,
+                channel(&hex[1..2])? * 0x11,
+                channel(&hex[2..3])? * 0x11,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+/// Colours used to present the display.
+///
+/// CHIP-8 only has a single plane, so a palette is a background and a foreground colour.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Palette {
+    pub background: Color,
+    pub foreground: Color,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        THEMES[0].1
+    }
+}
+
+impl Palette {
+    #[must_use]
+    pub const fn new(background: Color, foreground: Color) -> Self {
+        Self {
+            background,
+            foreground,
+        }
+    }
+
+    #[must_use]
+    pub fn color(&self, is_filled: bool) -> Color {
+        if is_filled {
+            self.foreground
+        } else {
+            self.background
+        }
+    }
+
+    /// Interpolates between the background (`level` 0) and the foreground (`level` 255).
+    #[must_use]
+    pub fn mix(&self, level: u8) -> Color {
+        let channel = |background: u8, foreground: u8| {
+            let level = level as u16;
+            ((background as u16 * (255 - level) + foreground as u16 * level) / 255) as u8
+        };
+
+        Color::new(
+            channel(self.background.r, self.foreground.r),
+            channel(self.background.g, self.foreground.g),
+            channel(self.background.b, self.foreground.b),
+        )
+    }
+
+    #[must_use]
+    pub fn from_theme_name(name: &str) -> Option<Self> {
+        THEMES
+            .iter()
+            .find(|(theme_name, _)| *theme_name == name)
+            .map(|(_, palette)| *palette)
+    }
+}
+
+/// Serialized as `<background>,<foreground>`, e.g. `#000000,#ffffff`.
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.background, self.foreground)
+    }
+}
+
+impl FromStr for Palette {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (background, foreground) = s.split_once(',').ok_or(())?;
+        Ok(Self::new(
+            Color::from_hex(background).ok_or(())?,
+            Color::from_hex(foreground).ok_or(())?,
+        ))
+    }
+}
+
+pub const THEMES: &[(&str, Palette)] = &[
+    (
+        "Classic",
+        Palette::new(Color::new(0x00, 0x00, 0x00), Color::new(0xFF, 0xFF, 0xFF)),
+    ),
+    (
+        "Green phosphor",
+        Palette::new(Color::new(0x0B, 0x1A, 0x0E), Color::new(0x33, 0xFF, 0x66)),
+    ),
+    (
+        "Amber",
+        Palette::new(Color::new(0x1A, 0x10, 0x00), Color::new(0xFF, 0xB0, 0x00)),
+    ),
+    (
+        "LCD grey",
+        Palette::new(Color::new(0xB8, 0xBC, 0xA8), Color::new(0x30, 0x34, 0x2C)),
+    ),
+    (
+        "Octo",
+        Palette::new(Color::new(0x99, 0x66, 0x00), Color::new(0xFF, 0xCC, 0x00)),
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_colours_parse_in_long_and_short_form() {
+        let orange = Color::new(0xFF, 0x88, 0x00);
+        assert_eq!(Color::from_hex("#ff8800"), Some(orange));
+        assert_eq!(Color::from_hex("#FF8800"), Some(orange));
+        assert_eq!(Color::from_hex("ff8800"), Some(orange));
+        assert_eq!(Color::from_hex("#f80"), Some(orange));
+        assert_eq!(orange.to_string(), "#ff8800");
+    }
+
+    #[test]
+    fn bad_hex_colours_are_rejected() {
+        for hex in [
+            "", "#", "#ff88", "#ff88000", "#gg8800", "#+f+8+0", "#ff 800", "#ff880é", "red",
+        ] {
+            assert_eq!(Color::from_hex(hex), None, "{hex:?}");
+        }
+    }
+
+    #[test]
+    fn mix_goes_from_the_background_to_the_foreground() {
+        let palette = Palette::new(Color::new(0x10, 0x20, 0xFF), Color::new(0xF0, 0x20, 0x00));
+        assert_eq!(palette.mix(0), palette.background);
+        assert_eq!(palette.mix(255), palette.foreground);
+        assert_eq!(palette.mix(128), Color::new(0x80, 0x20, 0x7F));
+    }
+
+    #[test]
+    fn themes_are_found_by_name() {
+        for (name, palette) in THEMES {
+            assert_eq!(Palette::from_theme_name(name), Some(*palette));
+            // A saved theme name can't be mistaken for custom colours
+            assert_eq!(name.parse::<Palette>(), Err(()));
+        }
+        assert_eq!(Palette::from_theme_name("Custom"), None);
+        assert_eq!(Palette::default(), THEMES[0].1);
+    }
+
+    #[test]
+    fn custom_palettes_survive_saving() {
+        let palette = Palette::new(Color::new(0x12, 0x34, 0x56), Color::new(0xAB, 0xCD, 0xEF));
+        let saved = palette.to_string();
+        assert_eq!(saved, "#123456,#abcdef");
+        assert_eq!(saved.parse(), Ok(palette));
+        assert_eq!(Palette::from_theme_name(&saved), None);
+
+        for saved in [
+            "#123456",
+            "#123456,",
+            ",#abcdef",
+            "#123456;#abcdef",
+            "#123456,#abcdef,",
+        ] {
+            assert_eq!(saved.parse::<Palette>(), Err(()), "{saved:?}");
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+/// Behaviours that differ between CHIP-8 interpreters. The defaults match what this emulator has
+/// always done, which is what most of the bundled ROMs expect.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Quirks {
+    /// `8xy6` and `8xyE` shift Vy into Vx, like the COSMAC VIP, rather than shifting Vx in place.
+    pub shift_uses_vy: bool,
+    /// `Fx55` and `Fx65` leave I pointing past the last register, like the COSMAC VIP.
+    pub load_store_increments_i: bool,
+    /// `Bnnn` jumps to nnn + Vx, where x is the highest nibble of nnn, like SUPER-CHIP.
+    pub jump_uses_vx: bool,
+    /// `8xy1`, `8xy2` and `8xy3` reset VF to 0, like the COSMAC VIP.
+    pub logic_resets_vf: bool,
+    /// Sprites wrap around to the opposite edge instead of being clipped.
+    pub wrap_sprites: bool,
+}
+
+impl Quirks {
+    /// Packs the quirks into a byte, one bit each in field order from the least significant bit.
+    #[must_use]
+    pub fn to_bits(self) -> u8 {
+        [
+            self.shift_uses_vy,
+            self.load_store_increments_i,
+            self.jump_uses_vx,
+            self.logic_resets_vf,
+            self.wrap_sprites,
+        ]
+        .into_iter()
+        .enumerate()
+        .fold(0, |bits, (bit, is_on)| bits | (u8::from(is_on) << bit))
+    }
+
+    #[must_use]
+    pub fn from_bits(bits: u8) -> Self {
+        let is_on = |bit: u8| bits & (1 << bit) != 0;
+        Self {
+            shift_uses_vy: is_on(0),
+            load_store_increments_i: is_on(1),
+            jump_uses_vx: is_on(2),
+            logic_resets_vf: is_on(3),
+            wrap_sprites: is_on(4),
+        }
+    }
+}
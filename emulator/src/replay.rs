@@ -0,0 +1,280 @@
+use crate::{
+    cpu::Cpu,
+    keypad::{KeyEvent, KeyState, KEY_COUNT},
+    quirks::Quirks,
+    state::{Reader, StateError},
+};
+use std::{error::Error, fmt};
+
+const MAGIC: &[u8; 4] = b"C8RP";
+const VERSION: u8 = 1;
+/// Longest replay that is played back, so that checking one takes bounded time: four hours.
+pub const MAX_FRAMES: u32 = 4 * 60 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplayError {
+    NotAReplay,
+    UnsupportedVersion(u8),
+    Truncated,
+    InvalidInput,
+    TooLong,
+    /// Playing the replay back did not end in the state it was recorded with.
+    Mismatch,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAReplay => write!(f, "Not a CHIP-8 replay"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported replay version {version}"),
+            Self::Truncated => write!(f, "Replay is truncated"),
+            Self::InvalidInput => write!(f, "Replay has an invalid input"),
+            Self::TooLong => write!(f, "Replay is longer than {MAX_FRAMES} frames"),
+            Self::Mismatch => write!(f, "Replay does not match the game it was recorded from"),
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+impl From<StateError> for ReplayError {
+    fn from(_: StateError) -> Self {
+        Self::Truncated
+    }
+}
+
+/// A key event, queued before frame `frame` ran.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Input {
+    pub frame: u32,
+    pub event: KeyEvent,
+}
+
+/// A game recorded from the start of its program: enough to play it again exactly, given the
+/// same ROM.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Replay {
+    pub seed: u64,
+    pub quirks: Quirks,
+    /// Keys that were down when the program started.
+    pub initial_keys: [KeyState; KEY_COUNT],
+    pub frames: u32,
+    /// In the order they were queued.
+    pub inputs: Vec<Input>,
+}
+
+impl Replay {
+    /// Starts recording `cpu`, which must be at the start of its program.
+    #[must_use]
+    pub fn start(cpu: &mut Cpu) -> Self {
+        cpu.keypad.start_log();
+
+        Self {
+            seed: cpu.seed,
+            quirks: cpu.quirks,
+            initial_keys: cpu.keypad.key_states,
+            frames: 0,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Records the input for the frame `cpu` is about to run. Call before [`Cpu::run_frame`].
+    pub fn record_frame(&mut self, cpu: &mut Cpu) {
+        let frame = self.frames;
+        let events = cpu.keypad.take_log();
+        self.inputs
+            .extend(events.into_iter().map(|event| Input { frame, event }));
+        self.frames += 1;
+    }
+
+    /// Runs the recorded game on `rom` from the start, without presenting anything.
+    #[must_use]
+    pub fn play(&self, rom: &[u8]) -> Cpu {
+        let mut cpu = Cpu::with_seed(rom, self.seed);
+        cpu.keypad.key_states = self.initial_keys;
+        cpu.quirks = self.quirks;
+
+        let mut inputs = self.inputs.iter().peekable();
+        for frame in 0..self.frames {
+            while let Some(Input { event, .. }) = inputs.next_if(|input| input.frame == frame) {
+                cpu.keypad.update_key_state(event.key, event.state);
+            }
+            cpu.run_frame();
+        }
+
+        cpu
+    }
+
+    /// Serializes the replay, ending with a hash of the state `cpu` is in now for
+    /// [`Self::verify`] to check against.
+    #[must_use]
+    pub fn encode(&self, cpu: &Cpu) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MAGIC.len() + 32 + self.inputs.len() * 6);
+
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+
+        buf.extend_from_slice(&self.seed.to_be_bytes());
+        buf.push(self.quirks.to_bits());
+        let initial_keys = (0..KEY_COUNT)
+            .filter(|&key| self.initial_keys[key] == KeyState::Down)
+            .fold(0_u16, |keys, key| keys | (1 << key));
+        buf.extend_from_slice(&initial_keys.to_be_bytes());
+        buf.extend_from_slice(&self.frames.to_be_bytes());
+        buf.extend_from_slice(&cpu.state_hash().to_be_bytes());
+
+        buf.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for Input { frame, event } in &self.inputs {
+            buf.extend_from_slice(&frame.to_be_bytes());
+            buf.push(event.key as u8);
+            buf.push(u8::from(event.state == KeyState::Down));
+        }
+
+        buf
+    }
+
+    /// Parses a replay from [`Self::encode`], returning it with the state hash it ended with.
+    ///
+    /// # Errors
+    /// Returns an error if `bytes` is not a well-formed replay of a supported version.
+    pub fn decode(bytes: &[u8]) -> Result<(Self, u64), ReplayError> {
+        let mut reader = Reader { buf: bytes };
+        if reader.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(ReplayError::NotAReplay);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+
+        let seed = reader.u64()?;
+        let quirks = Quirks::from_bits(reader.u8()?);
+        let initial_keys = reader.u16()?;
+        let frames = reader.u32()?;
+        if frames > MAX_FRAMES {
+            return Err(ReplayError::TooLong);
+        }
+        let state_hash = reader.u64()?;
+
+        let input_count = reader.u32()?;
+        let mut inputs = Vec::new();
+        for _ in 0..input_count {
+            let frame = reader.u32()?;
+            let key = reader.u8()? as usize;
+            let state = match reader.u8()? {
+                0 => KeyState::Up,
+                1 => KeyState::Down,
+                _ => return Err(ReplayError::InvalidInput),
+            };
+
+            let is_in_order = inputs.last().is_none_or(|last: &Input| last.frame <= frame);
+            if key >= KEY_COUNT || frame >= frames || !is_in_order {
+                return Err(ReplayError::InvalidInput);
+            }
+            inputs.push(Input {
+                frame,
+                event: KeyEvent { key, state },
+            });
+        }
+
+        let replay = Self {
+            seed,
+            quirks,
+            initial_keys: std::array::from_fn(|key| {
+                if initial_keys & (1 << key) != 0 {
+                    KeyState::Down
+                } else {
+                    KeyState::Up
+                }
+            }),
+            frames,
+            inputs,
+        };
+        Ok((replay, state_hash))
+    }
+
+    /// Plays back an encoded replay on `rom`, and returns the machine it ends with if that is in
+    /// the state the replay was recorded with.
+    ///
+    /// # Errors
+    /// Returns an error if `bytes` is not a valid replay, or was not recorded from `rom`.
+    pub fn verify(bytes: &[u8], rom: &[u8]) -> Result<Cpu, ReplayError> {
+        let (replay, state_hash) = Self::decode(bytes)?;
+
+        let cpu = replay.play(rom);
+        if cpu.state_hash() != state_hash {
+            return Err(ReplayError::Mismatch);
+        }
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roms::ROMS_BY_NAME;
+
+    /// Plays BRIX for 10 seconds, moving the paddle back and forth, and returns the replay of it
+    /// along with the machine it ended with.
+    fn record() -> (Replay, Cpu) {
+        let mut cpu = Cpu::with_seed(&ROMS_BY_NAME["BRIX"], 42);
+        cpu.quirks.logic_resets_vf = true;
+        cpu.keypad.update_key_state(0x6, KeyState::Down);
+        let mut replay = Replay::start(&mut cpu);
+
+        for frame in 0..600 {
+            if frame % 45 == 0 {
+                let keys = if frame % 90 == 0 { 1 << 0x4 } else { 1 << 0x6 };
+                cpu.keypad.hold_keys(keys);
+            }
+            replay.record_frame(&mut cpu);
+            cpu.run_frame();
+        }
+        (replay, cpu)
+    }
+
+    #[test]
+    fn replay_survives_encoding() {
+        let (replay, cpu) = record();
+        assert_eq!(replay.frames, 600);
+        assert!(!replay.inputs.is_empty());
+
+        let (decoded, state_hash) = Replay::decode(&replay.encode(&cpu)).unwrap();
+        assert_eq!(decoded, replay);
+        assert_eq!(state_hash, cpu.state_hash());
+    }
+
+    #[test]
+    fn recorded_game_verifies() {
+        let (replay, cpu) = record();
+        let bytes = replay.encode(&cpu);
+
+        let played = Replay::verify(&bytes, &ROMS_BY_NAME["BRIX"]).unwrap();
+        assert_eq!(played.state_hash(), cpu.state_hash());
+        assert_eq!(played.memory, cpu.memory);
+
+        // Only on the ROM it was recorded from
+        assert_eq!(
+            Replay::verify(&bytes, &ROMS_BY_NAME["PONG"]).unwrap_err(),
+            ReplayError::Mismatch
+        );
+    }
+
+    #[test]
+    fn altered_replays_are_rejected() {
+        let (mut replay, cpu) = record();
+        let bytes = replay.encode(&cpu);
+        assert_eq!(
+            Replay::verify(&bytes[..bytes.len() - 1], &ROMS_BY_NAME["BRIX"]).unwrap_err(),
+            ReplayError::Truncated
+        );
+
+        // Leaving out a key press plays a different game
+        replay.inputs.remove(replay.inputs.len() / 2);
+        let bytes = replay.encode(&cpu);
+        assert_eq!(
+            Replay::verify(&bytes, &ROMS_BY_NAME["BRIX"]).unwrap_err(),
+            ReplayError::Mismatch
+        );
+    }
+}
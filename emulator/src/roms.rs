@@ -22,3 +22,178 @@ pub static ROMS_BY_NAME: LazyLock<HashMap<String, Vec<u8>>> = LazyLock::new(|| {
     .into_iter()
     .collect()
 });
+
+/// Player controls of a game, as CHIP-8 keys.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Controls {
+    pub up: Option<u8>,
+    pub down: Option<u8>,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+    pub action: Option<u8>,
+}
+
+/// How a score is stored in memory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScoreEncoding {
+    /// Decimal digits, one per byte and most significant first, as written by `Fx33`.
+    Bcd { digits: u8 },
+}
+
+/// Where a game keeps its score.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScoreSource {
+    pub address: u16,
+    pub encoding: ScoreEncoding,
+}
+
+impl ScoreSource {
+    /// Reads the score from `memory`, treating bytes past its end as 0.
+    #[must_use]
+    pub fn read(&self, memory: &[u8]) -> u32 {
+        let byte = |offset: u16| {
+            memory
+                .get(self.address as usize + offset as usize)
+                .map_or(0, |&byte| byte as u32)
+        };
+
+        match self.encoding {
+            ScoreEncoding::Bcd { digits } => {
+                (0..digits as u16).fold(0, |score, offset| score * 10 + byte(offset).min(9))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RomInfo {
+    /// Every CHIP-8 key the game reads.
+    pub keys: &'static [u8],
+    pub controls: Controls,
+    /// Where the score can be read, for single-player games that keep one.
+    pub score: Option<ScoreSource>,
+    /// The register that holds the lives left, for games that are over once it reaches 0.
+    pub lives: Option<u8>,
+    /// The keys each player controls in netplay. Games played in turns share all their keys.
+    pub players: [&'static [u8]; 2],
+}
+
+const ALL_KEYS: &[u8] = &[
+    0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9, 0xA, 0xB, 0xC, 0xD, 0xE, 0xF,
+];
+
+/// Metadata for the bundled ROMs. Unknown ROMs are assumed to use every key.
+#[must_use]
+pub fn rom_info(rom_name: &str) -> RomInfo {
+    let keys: &[u8] = match rom_name {
+        "BLINKY" => &[0x1, 0x3, 0x6, 0x7, 0x8, 0xF],
+        "BLITZ" => &[0x5],
+        "BRIX" | "WIPEOFF" => &[0x4, 0x6],
+        "CONNECT4" | "INVADERS" | "UFO" => &[0x4, 0x5, 0x6],
+        "HIDDEN" | "KALEID" | "PUZZLE" => &[0x0, 0x2, 0x4, 0x5, 0x6, 0x8],
+        "MERLIN" => &[0x4, 0x5, 0x7, 0x8],
+        "MISSILE" => &[0x8],
+        "PONG" | "PONG2" => &[0x1, 0x4, 0xC, 0xD],
+        "SYZYGY" => &[0x3, 0x6, 0x7, 0x8, 0xB, 0xE, 0xF],
+        "TANK" => &[0x2, 0x4, 0x5, 0x6, 0x8],
+        "TETRIS" => &[0x1, 0x4, 0x5, 0x6],
+        "TICTAC" => &[0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9],
+        "VBRIX" => &[0x1, 0x4, 0x7],
+        _ => ALL_KEYS,
+    };
+
+    let controls = match rom_name {
+        "BLINKY" | "SYZYGY" => Controls {
+            up: Some(0x3),
+            down: Some(0x6),
+            left: Some(0x7),
+            right: Some(0x8),
+            action: None,
+        },
+        "BLITZ" => Controls {
+            action: Some(0x5),
+            ..Controls::default()
+        },
+        "BRIX" | "WIPEOFF" => Controls {
+            left: Some(0x4),
+            right: Some(0x6),
+            ..Controls::default()
+        },
+        "CONNECT4" | "INVADERS" => Controls {
+            left: Some(0x4),
+            right: Some(0x6),
+            action: Some(0x5),
+            ..Controls::default()
+        },
+        "HIDDEN" | "KALEID" | "PUZZLE" | "TANK" => Controls {
+            up: Some(0x2),
+            down: Some(0x8),
+            left: Some(0x4),
+            right: Some(0x6),
+            action: Some(0x5),
+        },
+        "MISSILE" => Controls {
+            action: Some(0x8),
+            ..Controls::default()
+        },
+        "PONG" | "PONG2" => Controls {
+            up: Some(0x1),
+            down: Some(0x4),
+            ..Controls::default()
+        },
+        "TETRIS" => Controls {
+            up: Some(0x4),
+            down: Some(0x1),
+            left: Some(0x5),
+            right: Some(0x6),
+            action: Some(0x4),
+        },
+        "UFO" => Controls {
+            up: Some(0x5),
+            left: Some(0x4),
+            right: Some(0x6),
+            action: Some(0x5),
+            ..Controls::default()
+        },
+        "VBRIX" => Controls {
+            up: Some(0x1),
+            down: Some(0x4),
+            action: Some(0x7),
+            ..Controls::default()
+        },
+        _ => Controls::default(),
+    };
+
+    // These games draw their score from the BCD that `Fx33` writes, which stays in memory after
+    // game over
+    let score_bcd_address = match rom_name {
+        "BRIX" => Some(0x314),
+        "TETRIS" => Some(0x804),
+        "VBRIX" => Some(0x3A6),
+        "WIPEOFF" => Some(0x2F0),
+        _ => None,
+    };
+    let score = score_bcd_address.map(|address| ScoreSource {
+        address,
+        encoding: ScoreEncoding::Bcd { digits: 3 },
+    });
+
+    let lives = match rom_name {
+        "BRIX" => Some(0xE),
+        "VBRIX" => Some(0x7),
+        _ => None,
+    };
+
+    let players = match rom_name {
+        "PONG" | "PONG2" => [&[0x1, 0x4][..], &[0xC, 0xD]],
+        _ => [keys, keys],
+    };
+
+    RomInfo {
+        keys,
+        controls,
+        score,
+        lives,
+        players,
+    }
+}
@@ -0,0 +1,308 @@
+use crate::{
+    cpu::Cpu,
+    emulator::{Emulator, EmulatorState},
+    filter::Phosphor,
+    keypad::{self, KeyInput},
+    lockstep::{Lockstep, Message},
+    palette::Palette,
+    roms,
+    stream::Encoder,
+    view::View,
+};
+use gloo_console::log;
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, rc::Rc};
+
+/// Requests from the page's controls to the emulator, wherever it runs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Command {
+    LoadRom {
+        name: String,
+        rom: Vec<u8>,
+    },
+    TogglePause,
+    Reset,
+    SetPageHidden {
+        is_hidden: bool,
+    },
+    SetPalette {
+        palette: Palette,
+    },
+    SetPhosphor {
+        phosphor: Phosphor,
+    },
+    /// Turns compiling hot code to WebAssembly on or off.
+    SetJit {
+        is_on: bool,
+    },
+    Screenshot {
+        scale: u32,
+    },
+    StartRecording,
+    StopRecording {
+        scale: u32,
+    },
+    SaveState,
+    LoadState {
+        state: Vec<u8>,
+    },
+    ReadScore,
+    /// Starts a netplay game as `player`, where `keys` are the keys each player controls.
+    StartNetplay {
+        name: String,
+        rom: Vec<u8>,
+        player: usize,
+        seed: u64,
+        keys: [Vec<u8>; 2],
+    },
+    /// Carries on alone with the local keypad, paused.
+    StopNetplay,
+    /// A message from the other netplay player.
+    Netplay {
+        message: Message,
+    },
+    /// Starts sending what is on screen and the keys that are down, for spectators.
+    StartBroadcast,
+    StopBroadcast,
+}
+
+/// Notifications from the emulator back to the page.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    /// The worker is listening for messages.
+    Ready,
+    StateChanged {
+        state: EmulatorState,
+    },
+    Screenshot {
+        png: Vec<u8>,
+    },
+    Recording {
+        apng: Vec<u8>,
+    },
+    /// Reply to [`Command::ReadScore`]. `score` is `None` if the game's score can't be read, and
+    /// `replay` if the game can't be replayed to check it.
+    Score {
+        rom_name: String,
+        score: Option<u32>,
+        replay: Option<Vec<u8>>,
+    },
+    /// A message for the other netplay player.
+    Netplay {
+        message: Message,
+    },
+    /// The two netplay players' machines differed at `frame`, and the host's has been restored on
+    /// both.
+    NetplayDesynced {
+        frame: u32,
+    },
+    /// The netplay game ended because the host's state couldn't be loaded, and the emulator
+    /// carries on alone, paused.
+    NetplayFailed {
+        message: String,
+    },
+    /// The next frame for spectators, from a [`crate::stream::Encoder`].
+    Broadcast {
+        frame: Vec<u8>,
+    },
+    /// Reply to [`Command::SaveState`], or `None` if no ROM is loaded.
+    StateSaved {
+        state: Option<Vec<u8>>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// An [`Emulator`] together with the [`View`] it presents on, driven by [`Command`]s.
+#[derive(Debug)]
+pub struct Session {
+    emulator: Emulator,
+    view: View,
+    keypad: Rc<RefCell<KeyInput>>,
+    /// Name of the loaded ROM, which determines where its score is kept.
+    rom_name: Option<String>,
+    /// The game in progress with another player, whose CPU has its own keypad fed by both.
+    netplay: Option<Lockstep>,
+    broadcast: Option<Encoder>,
+}
+
+impl Session {
+    pub fn new(view: View, keypad: Rc<RefCell<KeyInput>>) -> Self {
+        Self {
+            emulator: Emulator::new(),
+            view,
+            keypad,
+            rom_name: None,
+            netplay: None,
+            broadcast: None,
+        }
+    }
+
+    /// Runs `ticks` 60Hz ticks and presents the display. Called once per animation frame.
+    pub fn run(&mut self, ticks: u32) -> Vec<Event> {
+        let mut events = match &mut self.netplay {
+            Some(_) => self.run_netplay(ticks),
+            None => {
+                self.emulator.run(ticks, &mut self.view);
+                Vec::new()
+            }
+        };
+
+        if let (Some(encoder), Some(cpu)) = (&mut self.broadcast, self.emulator.cpu()) {
+            let keys = keypad::key_mask(&cpu.keypad.key_states);
+            if let Some(frame) = encoder.encode(cpu.framebuffer.rows(), keys) {
+                events.push(Event::Broadcast { frame });
+            }
+        }
+        events
+    }
+
+    fn run_netplay(&mut self, ticks: u32) -> Vec<Event> {
+        let Some(netplay) = &mut self.netplay else {
+            return Vec::new();
+        };
+
+        let is_running = self.emulator.state() == EmulatorState::Running;
+        if let (true, Some(cpu)) = (is_running, self.emulator.cpu_mut()) {
+            for _ in 0..ticks {
+                let mut input = self.keypad.borrow_mut();
+                input.keypad.process_events();
+                netplay.sample_input(&input.keypad.key_states);
+                drop(input);
+
+                if netplay.advance(cpu) {
+                    self.view.sample(&cpu.framebuffer);
+                }
+            }
+        }
+        self.emulator.run(0, &mut self.view);
+
+        Self::netplay_events(netplay)
+    }
+
+    /// Carries on alone with the local keypad, paused.
+    fn stop_netplay(&mut self) {
+        if self.netplay.take().is_some() {
+            self.emulator.set_input(Rc::clone(&self.keypad));
+            self.emulator.pause();
+        }
+    }
+
+    fn netplay_events(netplay: &mut Lockstep) -> Vec<Event> {
+        let mut events = netplay
+            .take_messages()
+            .into_iter()
+            .map(|message| Event::Netplay { message })
+            .collect::<Vec<_>>();
+        if let Some(frame) = netplay.take_desync() {
+            events.push(Event::NetplayDesynced { frame });
+        }
+        events
+    }
+
+    pub fn handle(&mut self, command: Command) -> Vec<Event> {
+        let state = self.emulator.state();
+        let mut events = Vec::new();
+
+        match command {
+            // Either player changing the machine on their own would desync the game
+            Command::Reset | Command::LoadState { .. } if self.netplay.is_some() => {
+                events.push(Event::Error {
+                    message: "Leave netplay first".to_owned(),
+                });
+            }
+            Command::LoadRom { name, rom } => {
+                self.emulator.load_rom(&rom, Rc::clone(&self.keypad));
+                self.rom_name = Some(name);
+                self.netplay = None;
+                log!("Created CPU");
+            }
+            Command::StartNetplay {
+                name,
+                rom,
+                player,
+                seed,
+                keys,
+            } => {
+                // Both players' keys reach the CPU through `netplay` instead
+                self.emulator.load_rom(&rom, Rc::default());
+                self.emulator.stop_recording();
+                if let Some(cpu) = self.emulator.cpu_mut() {
+                    cpu.reseed(seed);
+                }
+                self.rom_name = Some(name);
+                self.netplay = Some(Lockstep::new(player, [&keys[0], &keys[1]]));
+            }
+            Command::StopNetplay => self.stop_netplay(),
+            Command::Netplay { message } => {
+                if let (Some(netplay), Some(cpu)) = (&mut self.netplay, self.emulator.cpu_mut()) {
+                    netplay.receive(cpu, message);
+                    events.extend(Self::netplay_events(netplay));
+                    if let Some(err) = netplay.take_resync_error() {
+                        self.stop_netplay();
+                        events.push(Event::NetplayFailed {
+                            message: format!("Failed to load the host's game: {err}"),
+                        });
+                    }
+                }
+            }
+            Command::StartBroadcast => self.broadcast = Some(Encoder::new()),
+            Command::StopBroadcast => self.broadcast = None,
+            Command::TogglePause => self.emulator.toggle_pause(),
+            Command::Reset => self.emulator.reset(),
+            Command::SetPageHidden { is_hidden } => self.emulator.set_page_hidden(is_hidden),
+            Command::SetPalette { palette } => self.view.set_palette(palette),
+            Command::SetPhosphor { phosphor } => self.view.set_phosphor(phosphor),
+            Command::SetJit { is_on } => self.emulator.set_jit(is_on),
+            Command::Screenshot { scale } => events.push(match self.view.screenshot(scale) {
+                Ok(png) => Event::Screenshot { png },
+                Err(err) => Event::Error {
+                    message: format!("Failed to take screenshot: {err}"),
+                },
+            }),
+            Command::StartRecording => self.view.start_recording(),
+            Command::StopRecording { scale } => {
+                events.push(match self.view.stop_recording(scale) {
+                    Some(Ok(apng)) => Event::Recording { apng },
+                    Some(Err(err)) => Event::Error {
+                        message: format!("Failed to encode recording: {err}"),
+                    },
+                    None => Event::Error {
+                        message: "Nothing was recorded".to_owned(),
+                    },
+                })
+            }
+            Command::ReadScore => {
+                if let (Some(rom_name), Some(cpu)) = (&self.rom_name, self.emulator.cpu()) {
+                    events.push(Event::Score {
+                        rom_name: rom_name.clone(),
+                        score: roms::rom_info(rom_name)
+                            .score
+                            .map(|score| score.read(&cpu.memory)),
+                        replay: self.emulator.replay(),
+                    });
+                }
+            }
+            Command::SaveState => events.push(Event::StateSaved {
+                state: self.emulator.cpu().map(Cpu::save_state),
+            }),
+            Command::LoadState { state } => {
+                if let Some(Err(err)) = self.emulator.load_state(&state) {
+                    events.push(Event::Error {
+                        message: err.to_string(),
+                    });
+                }
+            }
+        }
+
+        if self.emulator.state() != state {
+            events.push(Event::StateChanged {
+                state: self.emulator.state(),
+            });
+        }
+        events
+    }
+}
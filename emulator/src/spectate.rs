@@ -0,0 +1,242 @@
+use crate::{
+    display::Framebuffer,
+    page::{element_by_id, selected_rom_name},
+    session::Command,
+    stream::Decoder,
+    view::View,
+    worker::Backend,
+};
+use gloo_console::log;
+use gloo_events::EventListener;
+use gloo_utils::{document, window};
+use std::{cell::RefCell, mem, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{
+    BinaryType, HtmlAnchorElement, HtmlButtonElement, HtmlCanvasElement, HtmlElement,
+    HtmlInputElement, MessageEvent, WebSocket,
+};
+
+/// CHIP-8 keys in the layout of the COSMAC VIP's keypad.
+const KEYPAD_LAYOUT: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+const PRESSED_CLASS: &str = "bg-gray-800";
+
+#[derive(Debug)]
+struct Broadcast {
+    socket: WebSocket,
+    _listeners: [EventListener; 2],
+}
+
+thread_local! {
+    /// The open broadcast. Events from the emulator are plain functions, so they reach it
+    /// through here.
+    static BROADCAST: RefCell<Option<Broadcast>> = const { RefCell::new(None) };
+}
+
+fn socket_url(path: &str) -> Result<String, JsValue> {
+    let location = window().location();
+    let scheme = if location.protocol()? == "https:" {
+        "wss"
+    } else {
+        "ws"
+    };
+    Ok(format!("{scheme}://{}{path}", location.host()?))
+}
+
+fn show_broadcast_status(status: &str, channel: Option<&str>) {
+    element_by_id::<HtmlElement>("broadcast-status").set_text_content(Some(status));
+    element_by_id::<HtmlButtonElement>("btn-broadcast").set_text_content(Some(
+        if channel.is_some() {
+            "Stop broadcasting"
+        } else {
+            "Broadcast"
+        },
+    ));
+
+    let link = element_by_id::<HtmlAnchorElement>("broadcast-link");
+    link.set_hidden(channel.is_none());
+    if let Some(channel) = channel {
+        link.set_href(&format!(
+            "watch.html?channel={}",
+            js_sys::encode_uri_component(channel)
+        ));
+    }
+}
+
+/// Sends the next frame for spectators, if broadcasting.
+pub fn send(frame: &[u8]) {
+    BROADCAST.with_borrow(|broadcast| {
+        if let Some(Broadcast { socket, .. }) = broadcast {
+            if socket.ready_state() == WebSocket::OPEN {
+                let _ = socket.send_with_u8_array(frame);
+            }
+        }
+    });
+}
+
+fn stop_broadcast(backend: &Backend) {
+    if let Some(broadcast) = BROADCAST.take() {
+        let _ = broadcast.socket.close();
+        backend.send(Command::StopBroadcast);
+
+        // This may be called from one of the broadcast's own listeners, which can't be dropped
+        // while they run
+        wasm_bindgen_futures::spawn_local(async move { drop(broadcast) });
+    }
+}
+
+fn start_broadcast(backend: &Rc<Backend>, channel: &str) -> Result<(), JsValue> {
+    let url = socket_url(&format!(
+        "/api/spectate/{}/broadcast",
+        js_sys::encode_uri_component(channel)
+    ))?;
+    let socket = WebSocket::new(&url)?;
+
+    // The emulator starts with a keyframe once someone is listening
+    let on_open = {
+        let backend = Rc::clone(backend);
+        let channel = channel.to_owned();
+        EventListener::new(&socket, "open", move |_| {
+            backend.send(Command::StartBroadcast);
+            show_broadcast_status(
+                &format!("Broadcasting {} on {channel}", selected_rom_name()),
+                Some(&channel),
+            );
+        })
+    };
+
+    let on_close = {
+        let backend = Rc::clone(backend);
+        let channel = channel.to_owned();
+        EventListener::new(&socket, "close", move |_| {
+            if BROADCAST.with_borrow(Option::is_some) {
+                stop_broadcast(&backend);
+                show_broadcast_status(
+                    &format!("Stopped broadcasting; is someone else broadcasting on {channel}?"),
+                    None,
+                );
+            }
+        })
+    };
+
+    stop_broadcast(backend);
+    BROADCAST.set(Some(Broadcast {
+        socket,
+        _listeners: [on_open, on_close],
+    }));
+    Ok(())
+}
+
+pub fn set_up_broadcast_controls(backend: &Rc<Backend>) -> Vec<EventListener> {
+    show_broadcast_status("", None);
+
+    let on_toggle = {
+        let backend = Rc::clone(backend);
+        EventListener::new(
+            &element_by_id::<HtmlButtonElement>("btn-broadcast"),
+            "click",
+            move |_| {
+                if BROADCAST.with_borrow(Option::is_some) {
+                    stop_broadcast(&backend);
+                    show_broadcast_status("", None);
+                    return;
+                }
+
+                let channel = element_by_id::<HtmlInputElement>("input-broadcast-channel").value();
+                let channel = channel.trim();
+                if channel.is_empty() {
+                    show_broadcast_status("Enter a channel name for spectators to watch", None);
+                    return;
+                }
+                if let Err(err) = start_broadcast(&backend, channel) {
+                    log!("Failed to start broadcast:", err);
+                }
+            },
+        )
+    };
+
+    vec![on_toggle]
+}
+
+/// Lights up the keys in `keys`, a bitmask, on the spectator's keypad.
+fn show_keys(cells: &[HtmlElement], keys: u16) {
+    for (cell, key) in cells.iter().zip(KEYPAD_LAYOUT) {
+        let _ = cell
+            .class_list()
+            .toggle_with_force(PRESSED_CLASS, keys & (1 << key) != 0);
+    }
+}
+
+/// Entry point of `watch.html`: shows the broadcast on `channel` in the `view` canvas, and the
+/// keys the player is pressing in `watch-keys`, without running an emulator.
+#[wasm_bindgen(js_name = watchBroadcast)]
+pub fn watch_broadcast(channel: &str) -> Result<(), JsValue> {
+    let status = element_by_id::<HtmlElement>("watch-status");
+    let url = socket_url(&format!(
+        "/api/spectate/{}",
+        js_sys::encode_uri_component(channel)
+    ))?;
+    let socket = WebSocket::new(&url)?;
+    socket.set_binary_type(BinaryType::Arraybuffer);
+
+    let container = element_by_id::<HtmlElement>("watch-keys");
+    let cells = KEYPAD_LAYOUT
+        .iter()
+        .map(|key| {
+            let cell = document()
+                .create_element("div")
+                .unwrap_throw()
+                .dyn_into::<HtmlElement>()
+                .unwrap_throw();
+            cell.set_class_name("py-1 text-center rounded-sm border border-gray-200");
+            cell.set_text_content(Some(&format!("{key:X}")));
+            container.append_child(&cell).unwrap_throw();
+            cell
+        })
+        .collect::<Vec<_>>();
+
+    let mut view = View::with_canvas(&element_by_id::<HtmlCanvasElement>("view"));
+    let decoder = Rc::new(RefCell::new(Decoder::new()));
+
+    let on_message = {
+        let status = status.clone();
+        let channel = channel.to_owned();
+        let decoder = Rc::clone(&decoder);
+        EventListener::new(&socket, "message", move |event| {
+            let data = event.unchecked_ref::<MessageEvent>().data();
+            let frame = js_sys::Uint8Array::new(&data).to_vec();
+
+            let mut decoder = decoder.borrow_mut();
+            if let Err(err) = decoder.apply(&frame) {
+                log!("Skipping broadcast frame:", err.to_string());
+                return;
+            }
+            if let Some(rows) = decoder.rows() {
+                view.render(&mut Framebuffer::from_rows(*rows));
+            }
+            show_keys(&cells, decoder.keys());
+            status.set_text_content(Some(&format!("Watching {channel}")));
+        })
+    };
+
+    let on_close = {
+        let status = status.clone();
+        let channel = channel.to_owned();
+        EventListener::new(&socket, "close", move |_| {
+            status.set_text_content(Some(&if decoder.borrow().rows().is_some() {
+                format!("The broadcast on {channel} ended")
+            } else {
+                format!("Nobody is broadcasting on {channel}")
+            }));
+        })
+    };
+
+    status.set_text_content(Some(&format!("Connecting to {channel}...")));
+
+    // Watching lasts as long as the page
+    on_message.forget();
+    on_close.forget();
+    mem::forget(socket);
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use crate::{
+    cpu::{Cpu, MAX_ROM_BYTES, REGISTER_COUNT, STACK_SIZE, TOTAL_MEMORY_BYTES},
+    display::{Framebuffer, HEIGHT},
+};
+use std::{error::Error, fmt};
+
+const MAGIC: &[u8; 4] = b"C8ST";
+const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u8),
+    Truncated,
+    /// A register holds a value the machine can never be in, e.g. from a corrupt or crafted
+    /// state.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAState => write!(f, "Not a CHIP-8 save state"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version {version}")
+            }
+            Self::Truncated => write!(f, "Save state is truncated"),
+            Self::Invalid(field) => write!(f, "Save state has an invalid {field}"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+pub(crate) struct Reader<'a> {
+    pub buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.buf.len() < len {
+            return Err(StateError::Truncated);
+        }
+
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+impl Cpu {
+    /// Serializes the machine state, including the display and loaded ROM but not the keypad or
+    /// quirks, which belong to the host.
+    #[must_use]
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MAGIC.len() + 1 + TOTAL_MEMORY_BYTES + 512);
+
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+
+        buf.extend_from_slice(&self.memory);
+        buf.extend_from_slice(&self.regs);
+        buf.extend_from_slice(&self.i_reg.to_be_bytes());
+        buf.extend_from_slice(&self.pc.to_be_bytes());
+        for addr in self.stack {
+            buf.extend_from_slice(&addr.to_be_bytes());
+        }
+        buf.push(self.sp);
+        buf.push(self.delay_timer);
+        buf.push(self.sound_timer);
+        for row in self.framebuffer.rows() {
+            buf.extend_from_slice(&row.to_be_bytes());
+        }
+        buf.extend_from_slice(&(self.rom.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.rom);
+
+        buf
+    }
+
+    /// FNV-1a hash of [`Self::save_state`], to check that two machines ended up the same.
+    #[must_use]
+    pub fn state_hash(&self) -> u64 {
+        self.save_state()
+            .into_iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    /// Restores a state produced by [`Self::save_state`]. On error, the machine is left unchanged.
+    ///
+    /// # Errors
+    /// Returns an error if `state` is not a save state of a supported version, or holds values
+    /// that would crash the machine later, like a stack pointer past the end of the stack.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = Reader { buf: state };
+        if reader.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(StateError::NotAState);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let memory = reader.bytes(TOTAL_MEMORY_BYTES)?.try_into().unwrap();
+        let regs = reader.bytes(REGISTER_COUNT)?.try_into().unwrap();
+        let i_reg = reader.u16()?;
+        let pc = reader.u16()?;
+        let mut stack = [0; STACK_SIZE];
+        for addr in &mut stack {
+            *addr = reader.u16()?;
+        }
+        let sp = reader.u8()?;
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let mut rows = [0; HEIGHT as usize];
+        for row in &mut rows {
+            *row = reader.u64()?;
+        }
+        let rom_len = reader.u16()?;
+        let rom = reader.bytes(rom_len as usize)?.to_vec();
+
+        // Instructions are fetched from an even address with both bytes in memory
+        let is_valid_pc = |pc: u16| pc.is_multiple_of(2) && (pc as usize) < TOTAL_MEMORY_BYTES - 1;
+        if !is_valid_pc(pc) {
+            return Err(StateError::Invalid("program counter"));
+        }
+        if sp as usize > STACK_SIZE {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+        if !stack[..sp as usize].iter().all(|&addr| is_valid_pc(addr)) {
+            return Err(StateError::Invalid("return address"));
+        }
+        if rom.len() > MAX_ROM_BYTES {
+            return Err(StateError::Invalid("ROM length"));
+        }
+
+        self.memory = memory;
+        self.invalidate_decoded(0, TOTAL_MEMORY_BYTES);
+        self.regs = regs;
+        self.i_reg = i_reg;
+        self.pc = pc;
+        self.stack = stack;
+        self.sp = sp;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.framebuffer = Framebuffer::from_rows(rows);
+        self.rom = rom;
+
+        // The restored program can't be partway through `Fx0A`'s wait.
+        self.keypad.cancel_wait();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roms::ROMS_BY_NAME;
+
+    const PC_AT: usize = MAGIC.len() + 1 + TOTAL_MEMORY_BYTES + REGISTER_COUNT + 2;
+    const SP_AT: usize = PC_AT + 2 + STACK_SIZE * 2;
+    const ROM_LEN_AT: usize = SP_AT + 3 + HEIGHT as usize * 8;
+
+    fn cpu(rom_name: &str, seed: u64) -> Cpu {
+        Cpu::with_seed(&ROMS_BY_NAME[rom_name], seed)
+    }
+
+    fn run_frames(cpu: &mut Cpu, frames: u32) {
+        for _ in 0..frames {
+            cpu.run_frame();
+        }
+    }
+
+    #[test]
+    fn loaded_state_plays_on_like_the_saved_machine() {
+        let mut saved = cpu("BRIX", 1);
+        run_frames(&mut saved, 300);
+        let state = saved.save_state();
+
+        let mut loaded = cpu("PONG", 2);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+
+        // The seed isn't part of the state, so reseed both the same way
+        saved.reseed(3);
+        loaded.reseed(3);
+        run_frames(&mut saved, 300);
+        run_frames(&mut loaded, 300);
+        assert_eq!(loaded.state_hash(), saved.state_hash());
+    }
+
+    #[test]
+    fn malformed_states_are_rejected_without_changing_the_machine() {
+        let state = cpu("BRIX", 1).save_state();
+        let with = |changes: &[(usize, &[u8])]| {
+            let mut state = state.clone();
+            for &(at, bytes) in changes {
+                state[at..at + bytes.len()].copy_from_slice(bytes);
+            }
+            state
+        };
+
+        let mut too_long_rom = with(&[(ROM_LEN_AT, &(MAX_ROM_BYTES as u16 + 1).to_be_bytes())]);
+        too_long_rom.resize(ROM_LEN_AT + 2 + MAX_ROM_BYTES + 1, 0);
+        let cases = [
+            (b"NOPE".to_vec(), StateError::NotAState),
+            (
+                with(&[(MAGIC.len(), &[VERSION + 1])]),
+                StateError::UnsupportedVersion(VERSION + 1),
+            ),
+            (state[..state.len() - 1].to_vec(), StateError::Truncated),
+            (
+                with(&[(PC_AT, &0x201_u16.to_be_bytes())]),
+                StateError::Invalid("program counter"),
+            ),
+            (
+                with(&[(PC_AT, &0x1000_u16.to_be_bytes())]),
+                StateError::Invalid("program counter"),
+            ),
+            (
+                with(&[(SP_AT, &[STACK_SIZE as u8 + 1])]),
+                StateError::Invalid("stack pointer"),
+            ),
+            (
+                with(&[(PC_AT + 2, &0x1000_u16.to_be_bytes()), (SP_AT, &[1])]),
+                StateError::Invalid("return address"),
+            ),
+            (too_long_rom, StateError::Invalid("ROM length")),
+        ];
+
+        let mut machine = cpu("PONG", 2);
+        let hash = machine.state_hash();
+        for (state, expected) in &cases {
+            assert_eq!(machine.load_state(state), Err(*expected));
+            assert_eq!(machine.state_hash(), hash);
+        }
+    }
+}
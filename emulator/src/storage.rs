@@ -0,0 +1,24 @@
+use gloo_utils::window;
+use web_sys::Storage;
+
+/// `localStorage` can be unavailable (e.g. disabled by the user), in which case settings simply
+/// aren't persisted.
+fn local_storage() -> Option<Storage> {
+    window().local_storage().ok().flatten()
+}
+
+pub fn get(key: &str) -> Option<String> {
+    local_storage()?.get_item(key).ok().flatten()
+}
+
+pub fn set(key: &str, value: &str) {
+    if let Some(storage) = local_storage() {
+        let _ = storage.set_item(key, value);
+    }
+}
+
+pub fn remove(key: &str) {
+    if let Some(storage) = local_storage() {
+        let _ = storage.remove_item(key);
+    }
+}
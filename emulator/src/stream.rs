@@ -0,0 +1,261 @@
+use crate::{
+    display::HEIGHT,
+    state::{Reader, StateError},
+};
+use std::{error::Error, fmt};
+
+const KEYFRAME: u8 = 0;
+const DELTA: u8 = 1;
+
+/// The display, as [`crate::display::Framebuffer::rows`].
+pub type Rows = [u64; HEIGHT as usize];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StreamError {
+    UnknownKind(u8),
+    Truncated,
+    InvalidRow(u8),
+    /// A delta arrived before any keyframe.
+    NoKeyframe,
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownKind(kind) => write!(f, "Unknown stream frame kind {kind}"),
+            Self::Truncated => write!(f, "Stream frame is truncated"),
+            Self::InvalidRow(row) => write!(f, "Stream frame changes row {row}, past the display"),
+            Self::NoKeyframe => write!(f, "Stream delta arrived before a keyframe"),
+        }
+    }
+}
+
+impl Error for StreamError {}
+
+impl From<StateError> for StreamError {
+    fn from(_: StateError) -> Self {
+        Self::Truncated
+    }
+}
+
+/// Encodes what a player sees and presses as a stream of frames, each only holding the rows that
+/// changed since the last.
+///
+/// A frame is a kind byte (0 for a keyframe, 1 for a delta) and the keys that are down as a
+/// big-endian `u16`. A keyframe follows with every row as a big-endian `u64`, and a delta with a
+/// count byte and that many row numbers, each followed by its row.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    last: Option<(Rows, u16)>,
+}
+
+impl Encoder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encodes the next frame, or returns `None` if nothing changed. The first frame is a
+    /// keyframe.
+    pub fn encode(&mut self, rows: &Rows, keys: u16) -> Option<Vec<u8>> {
+        let frame = match &self.last {
+            None => keyframe(rows, keys),
+            Some((last_rows, last_keys)) => {
+                let changed = (0..HEIGHT as u8)
+                    .filter(|&y| rows[y as usize] != last_rows[y as usize])
+                    .collect::<Vec<_>>();
+                if changed.is_empty() && keys == *last_keys {
+                    return None;
+                }
+
+                let mut frame = Vec::with_capacity(4 + changed.len() * 9);
+                frame.push(DELTA);
+                frame.extend_from_slice(&keys.to_be_bytes());
+                frame.push(changed.len() as u8);
+                for y in changed {
+                    frame.push(y);
+                    frame.extend_from_slice(&rows[y as usize].to_be_bytes());
+                }
+                frame
+            }
+        };
+
+        self.last = Some((*rows, keys));
+        Some(frame)
+    }
+}
+
+fn keyframe(rows: &Rows, keys: u16) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(3 + rows.len() * 8);
+    frame.push(KEYFRAME);
+    frame.extend_from_slice(&keys.to_be_bytes());
+    for row in rows {
+        frame.extend_from_slice(&row.to_be_bytes());
+    }
+    frame
+}
+
+/// Rebuilds the display and keys from frames made by an [`Encoder`].
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// `None` until the first keyframe.
+    current: Option<(Rows, u16)>,
+}
+
+impl Decoder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `frame`. On error, the decoder is left unchanged.
+    ///
+    /// # Errors
+    /// Returns an error if `frame` is malformed, or is a delta and no keyframe came before it.
+    pub fn apply(&mut self, frame: &[u8]) -> Result<(), StreamError> {
+        let mut reader = Reader { buf: frame };
+        let kind = reader.u8()?;
+        let keys = reader.u16()?;
+
+        let rows = match kind {
+            KEYFRAME => {
+                let mut rows = [0; HEIGHT as usize];
+                for row in &mut rows {
+                    *row = reader.u64()?;
+                }
+                rows
+            }
+            DELTA => {
+                let Some((mut rows, _)) = self.current else {
+                    return Err(StreamError::NoKeyframe);
+                };
+                for _ in 0..reader.u8()? {
+                    let y = reader.u8()?;
+                    let row = rows.get_mut(y as usize).ok_or(StreamError::InvalidRow(y))?;
+                    *row = reader.u64()?;
+                }
+                rows
+            }
+            kind => return Err(StreamError::UnknownKind(kind)),
+        };
+
+        self.current = Some((rows, keys));
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn rows(&self) -> Option<&Rows> {
+        self.current.as_ref().map(|(rows, _)| rows)
+    }
+
+    #[must_use]
+    pub fn keys(&self) -> u16 {
+        self.current.map_or(0, |(_, keys)| keys)
+    }
+
+    /// A keyframe of the current display, for someone who starts watching partway through.
+    #[must_use]
+    pub fn keyframe(&self) -> Option<Vec<u8>> {
+        self.current
+            .as_ref()
+            .map(|(rows, keys)| keyframe(rows, *keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(lit_rows: &[usize]) -> Rows {
+        let mut rows = [0; HEIGHT as usize];
+        for &y in lit_rows {
+            rows[y] = 0x0123_4567_89ab_cdef ^ y as u64;
+        }
+        rows
+    }
+
+    #[test]
+    fn frames_rebuild_the_display_and_keys() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        for (rows, keys) in [
+            (display(&[0, 5]), 0),
+            (display(&[0, 5, 31]), 0b10),
+            (display(&[31]), 0b10),
+            (display(&[]), 0),
+        ] {
+            decoder
+                .apply(&encoder.encode(&rows, keys).unwrap())
+                .unwrap();
+            assert_eq!(decoder.rows(), Some(&rows));
+            assert_eq!(decoder.keys(), keys);
+        }
+    }
+
+    #[test]
+    fn deltas_only_hold_changed_rows() {
+        let mut encoder = Encoder::new();
+        let keyframe = encoder.encode(&display(&[0]), 1).unwrap();
+        assert_eq!(keyframe.len(), 3 + 8 * HEIGHT as usize);
+        assert_eq!(keyframe[0], KEYFRAME);
+
+        let delta = encoder.encode(&display(&[0, 7]), 1).unwrap();
+        assert_eq!(delta[..5], [DELTA, 0, 1, 1, 7]);
+        assert_eq!(delta.len(), 4 + 9);
+
+        assert_eq!(encoder.encode(&display(&[0, 7]), 1), None);
+        assert_eq!(
+            encoder.encode(&display(&[0, 7]), 2).unwrap(),
+            [DELTA, 0, 2, 0]
+        );
+    }
+
+    #[test]
+    fn late_decoders_start_from_a_keyframe() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        decoder
+            .apply(&encoder.encode(&display(&[1]), 0).unwrap())
+            .unwrap();
+        decoder
+            .apply(&encoder.encode(&display(&[1, 2]), 3).unwrap())
+            .unwrap();
+
+        let mut late = Decoder::new();
+        assert_eq!(late.keyframe(), None);
+        late.apply(&decoder.keyframe().unwrap()).unwrap();
+        assert_eq!(late.rows(), decoder.rows());
+        assert_eq!(late.keys(), 3);
+    }
+
+    #[test]
+    fn malformed_frames_leave_the_decoder_unchanged() {
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            decoder.apply(&[DELTA, 0, 0, 0]),
+            Err(StreamError::NoKeyframe)
+        );
+
+        let keyframe = Encoder::new().encode(&display(&[4]), 5).unwrap();
+        assert_eq!(
+            decoder.apply(&keyframe[..keyframe.len() - 1]),
+            Err(StreamError::Truncated)
+        );
+        decoder.apply(&keyframe).unwrap();
+
+        assert_eq!(decoder.apply(&[]), Err(StreamError::Truncated));
+        assert_eq!(decoder.apply(&[2, 0, 0]), Err(StreamError::UnknownKind(2)));
+        let past_the_display = [DELTA, 0, 0, 1, HEIGHT as u8, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            decoder.apply(&past_the_display),
+            Err(StreamError::InvalidRow(HEIGHT as u8))
+        );
+        assert_eq!(
+            decoder.apply(&[DELTA, 0, 0, 1, 0]),
+            Err(StreamError::Truncated)
+        );
+
+        assert_eq!(decoder.rows(), Some(&display(&[4])));
+        assert_eq!(decoder.keys(), 5);
+    }
+}
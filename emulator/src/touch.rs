@@ -0,0 +1,204 @@
+use crate::{
+    bindings::KEYPAD_LAYOUT,
+    keypad::{KeyInput, KeyState, KEY_COUNT},
+    roms,
+};
+use gloo_events::{EventListener, EventListenerOptions};
+use gloo_utils::{document, window};
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{HtmlButtonElement, HtmlInputElement, PointerEvent};
+
+const HAPTIC_FEEDBACK_MS: u32 = 10;
+
+/// Pointers currently pressing each key. Several fingers can hold the same key, and it is only
+/// released once all of them lift.
+#[derive(Debug, Default)]
+struct KeyPointers {
+    pointers: [Vec<i32>; KEY_COUNT],
+}
+
+impl KeyPointers {
+    /// Returns whether this pointer is the first to press `key`.
+    fn press(&mut self, key: usize, pointer_id: i32) -> bool {
+        let pointers = &mut self.pointers[key];
+        if pointers.contains(&pointer_id) {
+            return false;
+        }
+
+        pointers.push(pointer_id);
+        pointers.len() == 1
+    }
+
+    /// Returns whether this pointer was the last one pressing `key`.
+    fn release(&mut self, key: usize, pointer_id: i32) -> bool {
+        let pointers = &mut self.pointers[key];
+        let len = pointers.len();
+        pointers.retain(|&id| id != pointer_id);
+
+        len > 0 && pointers.is_empty()
+    }
+}
+
+/// An on-screen keypad in the original COSMAC VIP layout, for devices without a keyboard.
+#[derive(Debug)]
+pub struct TouchKeypad {
+    buttons: Vec<HtmlButtonElement>,
+    pointers: KeyPointers,
+    pub input_compact: HtmlInputElement,
+    input_haptics: HtmlInputElement,
+}
+
+impl TouchKeypad {
+    /// Creates a button for each CHIP-8 key inside the `touch-keypad` element.
+    pub fn new(rom_name: &str) -> Self {
+        let container = document().get_element_by_id("touch-keypad").unwrap_throw();
+        let buttons = (0..KEY_COUNT)
+            .map(|key| {
+                let button = document()
+                    .create_element("button")
+                    .unwrap_throw()
+                    .dyn_into::<HtmlButtonElement>()
+                    .unwrap_throw();
+                button.set_type("button");
+                button.set_text_content(Some(&format!("{key:X}")));
+                button.set_class_name(
+                    "h-14 rounded-sm border border-gray-300 bg-gray-50 text-xl font-bold \
+                     select-none touch-none active:bg-gray-200",
+                );
+                button
+            })
+            .collect::<Vec<_>>();
+        for &key in &KEYPAD_LAYOUT {
+            container.append_child(&buttons[key]).unwrap_throw();
+        }
+
+        let input_by_id = |id| {
+            document()
+                .get_element_by_id(id)
+                .unwrap_throw()
+                .dyn_into::<HtmlInputElement>()
+                .unwrap_throw()
+        };
+
+        let touch_keypad = Self {
+            buttons,
+            pointers: Default::default(),
+            input_compact: input_by_id("input-touch-compact"),
+            input_haptics: input_by_id("input-touch-haptics"),
+        };
+        touch_keypad.show_layout(rom_name);
+        touch_keypad
+    }
+
+    /// In the compact layout, only shows the keys that `rom_name` uses.
+    pub fn show_layout(&self, rom_name: &str) {
+        let keys = roms::rom_info(rom_name).keys;
+        let is_compact = self.input_compact.checked();
+
+        for (key, button) in self.buttons.iter().enumerate() {
+            let is_hidden = is_compact && !keys.contains(&(key as u8));
+            button.set_hidden(is_hidden);
+        }
+    }
+
+    fn press(&mut self, key: usize, pointer_id: i32, keypad: &RefCell<KeyInput>) {
+        if self.pointers.press(key, pointer_id) {
+            keypad.borrow_mut().update_key_state(key, KeyState::Down);
+
+            if self.input_haptics.checked() {
+                window()
+                    .navigator()
+                    .vibrate_with_duration(HAPTIC_FEEDBACK_MS);
+            }
+        }
+    }
+
+    fn release(&mut self, key: usize, pointer_id: i32, keypad: &RefCell<KeyInput>) {
+        if self.pointers.release(key, pointer_id) {
+            keypad.borrow_mut().update_key_state(key, KeyState::Up);
+        }
+    }
+}
+
+pub fn set_up_touch_keypad_controls(
+    touch_keypad: &Rc<RefCell<TouchKeypad>>,
+    keypad: &Rc<RefCell<KeyInput>>,
+) -> Vec<EventListener> {
+    let buttons = touch_keypad.borrow().buttons.clone();
+
+    let mut listeners = Vec::new();
+    for (key, button) in buttons.into_iter().enumerate() {
+        let on_pointerdown = {
+            let touch_keypad = Rc::clone(touch_keypad);
+            let keypad = Rc::clone(keypad);
+            let button = button.clone();
+            EventListener::new_with_options(
+                &button.clone(),
+                "pointerdown",
+                EventListenerOptions::enable_prevent_default(),
+                move |event| {
+                    let event = event.dyn_ref::<PointerEvent>().unwrap_throw();
+                    event.prevent_default();
+
+                    // Keep receiving this pointer's events even if it slides off the button
+                    let _ = button.set_pointer_capture(event.pointer_id());
+                    touch_keypad
+                        .borrow_mut()
+                        .press(key, event.pointer_id(), &keypad);
+                },
+            )
+        };
+        listeners.push(on_pointerdown);
+
+        for event_type in ["pointerup", "pointercancel"] {
+            let touch_keypad = Rc::clone(touch_keypad);
+            let keypad = Rc::clone(keypad);
+            listeners.push(EventListener::new(&button, event_type, move |event| {
+                let event = event.dyn_ref::<PointerEvent>().unwrap_throw();
+                touch_keypad
+                    .borrow_mut()
+                    .release(key, event.pointer_id(), &keypad);
+            }));
+        }
+    }
+
+    listeners
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_key_is_released_only_after_its_last_pointer_lifts() {
+        let mut pointers = KeyPointers::default();
+        assert!(pointers.press(0x5, 1));
+        assert!(!pointers.press(0x5, 2));
+
+        assert!(!pointers.release(0x5, 1));
+        assert!(pointers.release(0x5, 2));
+    }
+
+    #[test]
+    fn repeated_and_unknown_pointers_are_ignored() {
+        let mut pointers = KeyPointers::default();
+        assert!(!pointers.release(0x5, 1));
+
+        assert!(pointers.press(0x5, 1));
+        assert!(!pointers.press(0x5, 1));
+        assert!(!pointers.release(0x5, 2));
+        assert!(pointers.release(0x5, 1));
+        assert!(!pointers.release(0x5, 1));
+    }
+
+    #[test]
+    fn keys_track_their_pointers_separately() {
+        let mut pointers = KeyPointers::default();
+        assert!(pointers.press(0x4, 1));
+        assert!(pointers.press(0x6, 2));
+
+        assert!(pointers.release(0x4, 1));
+        assert!(pointers.release(0x6, 2));
+    }
+}
@@ -1,127 +1,226 @@
-use gloo_console::log;
-use gloo_utils::{document, window};
-use std::{cell::RefCell, rc::Rc};
+use crate::{
+    capture::{self, Frame, Recorder},
+    display::{DirtyRect, Framebuffer, HEIGHT, WIDTH},
+    filter::{Phosphor, PhosphorFilter},
+    palette::Palette,
+};
+use gloo_events::EventListener;
+use gloo_utils::document;
+use std::{cell::RefCell, mem, rc::Rc};
 use wasm_bindgen::{prelude::*, Clamped, JsCast};
-use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+use web_sys::{
+    CanvasRenderingContext2d, HtmlCanvasElement, ImageData, OffscreenCanvas,
+    OffscreenCanvasRenderingContext2d, Window,
+};
 
 const SIXTY_FPS_FRAME_MS: f64 = 1000. / 60.;
-pub const WIDTH: u32 = 64;
-pub const HEIGHT: u32 = 32;
-const SCALE: u32 = 10;
+/// Upper bound on ticks run in a single animation frame. Anything beyond this is dropped rather
+/// than fast-forwarded, e.g. after the main thread stalls.
+const MAX_TICKS_PER_FRAME: u32 = 4;
 const IMAGE_DATA_ENTRIES_PER_PIXEL: u32 = 4;
 
+/// A 2D context on either a page's canvas or, in a worker, an [`OffscreenCanvas`].
+#[derive(Clone, Debug)]
+enum Context {
+    OnScreen(CanvasRenderingContext2d),
+    Offscreen(OffscreenCanvasRenderingContext2d),
+}
+
+impl Context {
+    fn put_image_data(&self, image_data: &ImageData, dirty: DirtyRect) {
+        let (x, y, width, height) = (
+            dirty.x as f64,
+            dirty.y as f64,
+            dirty.width as f64,
+            dirty.height as f64,
+        );
+
+        match self {
+            Self::OnScreen(ctx) => ctx
+                .put_image_data_with_dirty_x_and_dirty_y_and_dirty_width_and_dirty_height(
+                    image_data, 0., 0., x, y, width, height,
+                ),
+            Self::Offscreen(ctx) => ctx
+                .put_image_data_with_dirty_x_and_dirty_y_and_dirty_width_and_dirty_height(
+                    image_data, 0., 0., x, y, width, height,
+                ),
+        }
+        .unwrap_throw();
+    }
+}
+
+fn context_options() -> js_sys::Object {
+    let ctx_options = js_sys::Object::new();
+    js_sys::Reflect::set(&ctx_options, &"alpha".into(), &false.into()).unwrap_throw();
+    ctx_options
+}
+
+/// Presents a [`Framebuffer`] on a canvas.
+///
+/// The canvas is the size of the CHIP-8 display and is scaled up with CSS, so each frame is a
+/// single `putImageData` of at most 64x32 pixels regardless of how many sprites were drawn.
 #[derive(Clone, Debug)]
 pub struct View {
-    ctx: CanvasRenderingContext2d,
+    ctx: Context,
+    pixels: Vec<u8>,
+    palette: Palette,
+    phosphor_filter: PhosphorFilter,
+    needs_full_redraw: bool,
+    last_frame: Frame,
+    recorder: Option<Recorder>,
 }
 
 impl View {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        let ctx_options = js_sys::Object::new();
-        js_sys::Reflect::set(&ctx_options, &"alpha".into(), &false.into()).unwrap_throw();
+    /// Presents on `canvas`, resizing it to the CHIP-8 display.
+    pub fn with_canvas(canvas: &HtmlCanvasElement) -> Self {
+        canvas.set_width(WIDTH);
+        canvas.set_height(HEIGHT);
 
-        let canvas = document()
-            .get_element_by_id("view")
-            .unwrap_throw()
-            .dyn_into::<HtmlCanvasElement>()
-            .unwrap_throw();
         let ctx = canvas
-            .get_context_with_context_options("2d", ctx_options.as_ref())
+            .get_context_with_context_options("2d", &context_options())
             .unwrap_throw()
             .unwrap_throw()
             .dyn_into::<CanvasRenderingContext2d>()
             .unwrap_throw();
 
-        let view = Self { ctx };
-        view.clear();
-        view
-    }
-
-    fn draw_contiguous_sprite(
-        &self,
-        sprite: &[u8],
-        x_count: u32,
-        y_count: u32,
-        sx: u32,
-        sy: u32,
-    ) -> bool {
-        let mut collision = false;
-        let orig_image_data = self
-            .ctx
-            .get_image_data(
-                (sx * SCALE) as f64,
-                (sy * SCALE) as f64,
-                (x_count * SCALE) as f64,
-                (y_count * SCALE) as f64,
-            )
+        Self::with_context(Context::OnScreen(ctx))
+    }
+
+    /// Presents on `canvas` from a worker, resizing it to the CHIP-8 display.
+    pub fn with_offscreen_canvas(canvas: &OffscreenCanvas) -> Self {
+        canvas.set_width(WIDTH);
+        canvas.set_height(HEIGHT);
+
+        let ctx = canvas
+            .get_context_with_context_options("2d", &context_options())
+            .unwrap_throw()
+            .unwrap_throw()
+            .dyn_into::<OffscreenCanvasRenderingContext2d>()
             .unwrap_throw();
-        let mut image_data = orig_image_data.data();
-
-        for iy in 0..y_count {
-            let byte = sprite[iy as usize];
-            for ix in 0..x_count {
-                let base_pos =
-                    (IMAGE_DATA_ENTRIES_PER_PIXEL * SCALE * (SCALE * iy * x_count + ix)) as usize;
-
-                // Each pixel stores 4 values (RGBA), and R=255 is white
-                let curr_is_filled = image_data[base_pos] == 255 && image_data[base_pos + 3] == 255;
-                let mem_is_filled = (byte >> (7 - ix)) & 1 == 1;
-
-                let new_is_filled = curr_is_filled ^ mem_is_filled;
-
-                if curr_is_filled && !new_is_filled {
-                    collision = true;
-                }
-
-                for scale_dx in 0..SCALE {
-                    for scale_dy in 0..SCALE {
-                        let pos = base_pos
-                            + (IMAGE_DATA_ENTRIES_PER_PIXEL
-                                * (SCALE * scale_dy * x_count + scale_dx))
-                                as usize;
-
-                        if new_is_filled {
-                            image_data[pos] = 255;
-                            image_data[pos + 1] = 255;
-                            image_data[pos + 2] = 255;
-                            image_data[pos + 3] = 255;
-                        } else {
-                            image_data[pos] = 0;
-                            image_data[pos + 1] = 0;
-                            image_data[pos + 2] = 0;
-                            image_data[pos + 3] = 255;
-                        }
-                    }
-                }
+
+        Self::with_context(Context::Offscreen(ctx))
+    }
+
+    fn with_context(ctx: Context) -> Self {
+        Self {
+            ctx,
+            pixels: vec![0; (WIDTH * HEIGHT * IMAGE_DATA_ENTRIES_PER_PIXEL) as usize],
+            palette: Palette::default(),
+            phosphor_filter: PhosphorFilter::new(),
+            needs_full_redraw: true,
+            last_frame: [0; HEIGHT as usize],
+            recorder: None,
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        if self.palette != palette {
+            self.palette = palette;
+            self.needs_full_redraw = true;
+        }
+    }
+
+    pub fn set_phosphor(&mut self, phosphor: Phosphor) {
+        self.phosphor_filter.set_phosphor(phosphor);
+        self.needs_full_redraw = true;
+    }
+
+    /// Encodes the last rendered frame as a PNG, ignoring the phosphor filter.
+    pub fn screenshot(&self, scale: u32) -> Result<Vec<u8>, png::EncodingError> {
+        capture::encode_png(&self.last_frame, scale, &self.palette)
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recorder = Some(Recorder::new());
+    }
+
+    /// Stops recording and encodes the recording as an animated PNG. Returns `None` if nothing was
+    /// recorded.
+    pub fn stop_recording(&mut self, scale: u32) -> Option<Result<Vec<u8>, png::EncodingError>> {
+        self.recorder
+            .take()
+            .filter(|recorder| !recorder.is_empty())
+            .map(|recorder| recorder.encode_apng(scale, &self.palette))
+    }
+
+    /// Feeds the current frame to the phosphor filter and recorder. Should be called once per 60Hz
+    /// tick.
+    pub fn sample(&mut self, framebuffer: &Framebuffer) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.capture(framebuffer.rows());
+        }
+
+        if self.phosphor_filter.is_enabled() {
+            self.phosphor_filter.update(framebuffer);
+
+            // Faded pixels change every frame, not just those that were drawn to
+            self.needs_full_redraw = true;
+        }
+    }
+
+    /// Draws the parts of `framebuffer` that changed since the last call.
+    pub fn render(&mut self, framebuffer: &mut Framebuffer) {
+        let dirty = match framebuffer.take_dirty() {
+            _ if mem::take(&mut self.needs_full_redraw) => DirtyRect::FULL,
+            Some(dirty) => dirty,
+            None => return,
+        };
+        self.last_frame = *framebuffer.rows();
+
+        for y in dirty.y..dirty.y + dirty.height {
+            for x in dirty.x..dirty.x + dirty.width {
+                let pos = (IMAGE_DATA_ENTRIES_PER_PIXEL * (y * WIDTH + x)) as usize;
+
+                // Each pixel stores 4 values (RGBA)
+                let color = if self.phosphor_filter.is_enabled() {
+                    self.palette.mix(self.phosphor_filter.level(x, y))
+                } else {
+                    self.palette.color(framebuffer.pixel(x, y))
+                };
+                self.pixels[pos] = color.r;
+                self.pixels[pos + 1] = color.g;
+                self.pixels[pos + 2] = color.b;
+                self.pixels[pos + 3] = 255;
             }
         }
 
-        let new_image_data =
-            ImageData::new_with_u8_clamped_array(Clamped(&image_data), x_count * SCALE)
+        let image_data =
+            ImageData::new_with_u8_clamped_array_and_sh(Clamped(&self.pixels), WIDTH, HEIGHT)
                 .unwrap_throw();
 
-        self.ctx
-            .put_image_data(&new_image_data, (sx * SCALE) as f64, (sy * SCALE) as f64)
-            .unwrap_throw();
-
-        collision
+        self.ctx.put_image_data(&image_data, dirty);
     }
+}
 
-    pub fn draw_sprite(&self, sprite: &[u8], n: u32, sx: u32, sy: u32) -> bool {
-        let sx = sx % WIDTH;
-        let sy = sy % HEIGHT;
+/// Converts animation frame timestamps into a whole number of 60Hz ticks, carrying leftover
+/// time over to the next frame.
+#[derive(Debug, Default)]
+struct FixedTimestep {
+    last_time_ms: Option<f64>,
+    accumulator_ms: f64,
+}
 
-        let x_count = 8.min(WIDTH - sx);
-        let y_count = n.min(HEIGHT - sy);
+impl FixedTimestep {
+    fn advance(&mut self, time_ms: f64) -> u32 {
+        let Some(last_time_ms) = self.last_time_ms.replace(time_ms) else {
+            return 0;
+        };
 
-        self.draw_contiguous_sprite(sprite, x_count, y_count, sx, sy)
+        self.accumulator_ms += (time_ms - last_time_ms).max(0.);
+        let ticks = (self.accumulator_ms / SIXTY_FPS_FRAME_MS) as u32;
+        if ticks > MAX_TICKS_PER_FRAME {
+            self.accumulator_ms = 0.;
+            return MAX_TICKS_PER_FRAME;
+        }
+
+        self.accumulator_ms -= ticks as f64 * SIXTY_FPS_FRAME_MS;
+        ticks
     }
 
-    pub fn clear(&self) {
-        log!("Clearing canvas");
-        self.ctx
-            .clear_rect(0., 0., (WIDTH * SCALE) as f64, (HEIGHT * SCALE) as f64);
+    /// Forget the previous timestamp so that time spent with no animation frames (e.g. while the
+    /// tab is hidden) is not caught up on.
+    fn reset(&mut self) {
+        *self = Self::default();
     }
 }
 
@@ -130,38 +229,52 @@ pub struct AnimationFrame {
     #[allow(clippy::type_complexity)]
     closure: Rc<RefCell<Option<Closure<dyn FnMut(JsValue)>>>>,
     render_id: Rc<RefCell<Option<i32>>>,
+    _on_visibility_change: Option<EventListener>,
 }
 
 impl Drop for AnimationFrame {
     fn drop(&mut self) {
         self.closure.take();
         if let Some(render_id) = self.render_id.take() {
-            window().cancel_animation_frame(render_id).unwrap_throw();
+            call_global("cancelAnimationFrame", &render_id.into());
         }
     }
 }
 
-fn request_animation_frame(f: &Closure<dyn FnMut(JsValue)>) -> i32 {
-    window()
-        .request_animation_frame(f.as_ref().unchecked_ref())
+/// Calls a function on the global object, which is either the page's window or a dedicated
+/// worker's scope. Both have animation frames, but `web-sys` only exposes them on `Window`.
+fn call_global(name: &str, arg: &JsValue) -> JsValue {
+    let global = js_sys::global();
+    js_sys::Reflect::get(&global, &name.into())
+        .unwrap_throw()
+        .dyn_into::<js_sys::Function>()
+        .unwrap_throw()
+        .call1(&global, arg)
         .unwrap_throw()
 }
 
-pub fn set_up_render_loop(mut f: impl FnMut() + 'static) -> AnimationFrame {
-    let mut last_time_ms = 0.;
+fn request_animation_frame(f: &Closure<dyn FnMut(JsValue)>) -> i32 {
+    call_global("requestAnimationFrame", f.as_ref())
+        .as_f64()
+        .unwrap_throw() as i32
+}
+
+/// Calls `f` once per animation frame with the number of 60Hz ticks that have elapsed since the
+/// previous call. `f` should run that many ticks and then present the result once.
+pub fn set_up_render_loop(mut f: impl FnMut(u32) + 'static) -> AnimationFrame {
+    let timestep = Rc::new(RefCell::new(FixedTimestep::default()));
 
     let closure = Rc::new(RefCell::new(None));
     let render_id = Rc::new(RefCell::new(None));
 
     let closure_internal = Rc::clone(&closure);
     let render_id_internal = Rc::clone(&render_id);
+    let timestep_internal = Rc::clone(&timestep);
 
     *closure.borrow_mut() = Some(Closure::new(move |v: JsValue| {
         let time_ms = v.as_f64().unwrap_or(0.);
-        if time_ms - last_time_ms >= SIXTY_FPS_FRAME_MS {
-            f();
-            last_time_ms = time_ms;
-        }
+        let ticks = timestep_internal.borrow_mut().advance(time_ms);
+        f(ticks);
 
         if let Some(closure_internal) = closure_internal.borrow().as_ref() {
             *render_id_internal.borrow_mut() = Some(request_animation_frame(closure_internal));
@@ -170,5 +283,66 @@ pub fn set_up_render_loop(mut f: impl FnMut() + 'static) -> AnimationFrame {
 
     *render_id.borrow_mut() = Some(request_animation_frame(closure.borrow().as_ref().unwrap()));
 
-    AnimationFrame { closure, render_id }
+    // Workers have no document, but their animation frames stop along with the page's anyway
+    let on_visibility_change = js_sys::global().is_instance_of::<Window>().then(|| {
+        EventListener::new(&document(), "visibilitychange", move |_| {
+            timestep.borrow_mut().reset();
+        })
+    });
+
+    AnimationFrame {
+        closure,
+        render_id,
+        _on_visibility_change: on_visibility_change,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_frame_runs_no_ticks() {
+        let mut timestep = FixedTimestep::default();
+        assert_eq!(timestep.advance(1000.), 0);
+        assert_eq!(timestep.advance(1017.), 1);
+    }
+
+    #[test]
+    fn leftover_time_carries_over_to_the_next_frame() {
+        let mut timestep = FixedTimestep::default();
+        timestep.advance(0.);
+
+        // At 144Hz, most frames run no ticks, but they add up to 60 a second
+        let frame_ms = 1000. / 144.;
+        let ticks = (1..=144)
+            .map(|frame| timestep.advance(f64::from(frame) * frame_ms))
+            .collect::<Vec<_>>();
+        assert!(ticks.iter().all(|&ticks| ticks <= 1));
+        assert!((59..=60).contains(&ticks.iter().sum::<u32>()));
+    }
+
+    #[test]
+    fn long_frames_are_capped_and_not_caught_up_on() {
+        let mut timestep = FixedTimestep::default();
+        timestep.advance(0.);
+        assert_eq!(timestep.advance(1000.), MAX_TICKS_PER_FRAME);
+        assert_eq!(timestep.advance(1017.), 1);
+    }
+
+    #[test]
+    fn time_going_backwards_runs_no_ticks() {
+        let mut timestep = FixedTimestep::default();
+        timestep.advance(100.);
+        assert_eq!(timestep.advance(50.), 0);
+        assert_eq!(timestep.advance(67.), 1);
+    }
+
+    #[test]
+    fn reset_forgets_the_last_frame() {
+        let mut timestep = FixedTimestep::default();
+        timestep.advance(0.);
+        timestep.reset();
+        assert_eq!(timestep.advance(10_000.), 0);
+    }
 }
@@ -0,0 +1,186 @@
+use crate::{
+    keypad::{KeyInput, SharedKeyStates},
+    session::{Command, Event, Session},
+    view::{self, View},
+};
+use gloo_console::log;
+use gloo_events::EventListener;
+use gloo_utils::window;
+use std::{cell::RefCell, mem, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{
+    DedicatedWorkerGlobalScope, HtmlCanvasElement, MessageEvent, OffscreenCanvas, Worker,
+    WorkerOptions, WorkerType,
+};
+
+/// Script that instantiates the Wasm module in the worker and calls [`run_worker`].
+const WORKER_URL: &str = "./worker.js";
+
+/// Whether the page can run the emulator in a worker: `SharedArrayBuffer` needs cross-origin
+/// isolation, and the canvas has to be transferable.
+#[must_use]
+pub fn is_supported(canvas: &HtmlCanvasElement) -> bool {
+    let is_isolated = js_sys::Reflect::get(&window(), &"crossOriginIsolated".into())
+        .is_ok_and(|is_isolated| is_isolated.is_truthy());
+    let can_transfer =
+        js_sys::Reflect::has(canvas, &"transferControlToOffscreen".into()).unwrap_or(false);
+
+    is_isolated && can_transfer
+}
+
+fn post_event(scope: &DedicatedWorkerGlobalScope, event: &Event) {
+    scope
+        .post_message(&serde_json::to_string(event).unwrap_throw().into())
+        .unwrap_throw();
+}
+
+/// Entry point of the worker. The page first sends `[canvas, keyStates]` with an `OffscreenCanvas`
+/// and the [`SharedKeyStates`] buffer, and then JSON-encoded [`Command`]s.
+#[wasm_bindgen(js_name = runWorker)]
+pub fn run_worker() {
+    let scope = js_sys::global().unchecked_into::<DedicatedWorkerGlobalScope>();
+    let session = Rc::new(RefCell::new(None::<Session>));
+
+    let on_message = {
+        let scope = scope.clone();
+        EventListener::new(&scope.clone(), "message", move |event| {
+            let data = event.unchecked_ref::<MessageEvent>().data();
+
+            if let Some(command) = data.as_string() {
+                // e.g. from a page that is still running an older build
+                let command = match serde_json::from_str::<Command>(&command) {
+                    Ok(command) => command,
+                    Err(err) => {
+                        log!("Ignoring invalid command:", err.to_string());
+                        return;
+                    }
+                };
+                if let Some(session) = session.borrow_mut().as_mut() {
+                    for event in session.handle(command) {
+                        post_event(&scope, &event);
+                    }
+                }
+                return;
+            }
+
+            let init = data.unchecked_into::<js_sys::Array>();
+            let canvas = init.get(0).unchecked_into::<OffscreenCanvas>();
+            let mut key_states = SharedKeyStates::from_buffer(&init.get(1).unchecked_into());
+
+            let input = Rc::new(RefCell::new(KeyInput::new()));
+            *session.borrow_mut() = Some(Session::new(
+                View::with_offscreen_canvas(&canvas),
+                Rc::clone(&input),
+            ));
+
+            let session = Rc::clone(&session);
+            let scope = scope.clone();
+            let render_loop = view::set_up_render_loop(move |ticks| {
+                key_states.receive(&mut input.borrow_mut().keypad);
+                if let Some(session) = session.borrow_mut().as_mut() {
+                    for event in session.run(ticks) {
+                        post_event(&scope, &event);
+                    }
+                }
+            });
+
+            // The worker lives as long as the page
+            mem::forget(render_loop);
+        })
+    };
+    on_message.forget();
+
+    post_event(&scope, &Event::Ready);
+}
+
+/// The page's handle to the emulator running in a worker.
+#[derive(Debug)]
+pub struct EmulatorWorker {
+    worker: Worker,
+    /// Commands sent before the worker was ready, or `None` once it is.
+    pending: Rc<RefCell<Option<Vec<String>>>>,
+    _on_message: EventListener,
+}
+
+impl EmulatorWorker {
+    /// Starts the worker, handing it `canvas` and `key_states`. Events from the worker are passed
+    /// to `on_event`.
+    pub fn new(
+        canvas: &HtmlCanvasElement,
+        key_states: &SharedKeyStates,
+        on_event: fn(Event),
+    ) -> Result<Self, JsValue> {
+        let options = WorkerOptions::new();
+        options.set_type(WorkerType::Module);
+        let worker = Worker::new_with_options(WORKER_URL, &options)?;
+
+        let canvas = canvas.transfer_control_to_offscreen()?;
+        let key_states = key_states.buffer();
+        let pending = Rc::new(RefCell::new(Some(Vec::<String>::new())));
+
+        let on_message = {
+            let worker = worker.clone();
+            let pending = Rc::clone(&pending);
+            EventListener::new(&worker.clone(), "message", move |event| {
+                let data = event.unchecked_ref::<MessageEvent>().data();
+                let Some(event) = data
+                    .as_string()
+                    .and_then(|event| serde_json::from_str::<Event>(&event).ok())
+                else {
+                    return;
+                };
+
+                if let Event::Ready = event {
+                    worker
+                        .post_message_with_transfer(
+                            &js_sys::Array::of2(&canvas, &key_states),
+                            &js_sys::Array::of1(&canvas),
+                        )
+                        .unwrap_throw();
+                    for command in pending.borrow_mut().take().into_iter().flatten() {
+                        worker.post_message(&command.into()).unwrap_throw();
+                    }
+                }
+
+                on_event(event);
+            })
+        };
+
+        Ok(Self {
+            worker,
+            pending,
+            _on_message: on_message,
+        })
+    }
+
+    pub fn send(&self, command: &Command) {
+        let command = serde_json::to_string(command).unwrap_throw();
+        match self.pending.borrow_mut().as_mut() {
+            Some(pending) => pending.push(command),
+            None => self.worker.post_message(&command.into()).unwrap_throw(),
+        }
+    }
+}
+
+/// Where the emulator runs: in a worker when the browser allows it, or else on the page itself.
+#[derive(Debug)]
+pub enum Backend {
+    Local {
+        session: Rc<RefCell<Session>>,
+        on_event: fn(Event),
+    },
+    Worker(EmulatorWorker),
+}
+
+impl Backend {
+    pub fn send(&self, command: Command) {
+        match self {
+            Self::Local { session, on_event } => {
+                for event in session.borrow_mut().handle(command) {
+                    on_event(event);
+                }
+            }
+            Self::Worker(worker) => worker.send(&command),
+        }
+    }
+}
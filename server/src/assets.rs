@@ -0,0 +1,75 @@
+use axum::{
+    body::Body,
+    response::{IntoResponse, Response},
+};
+use http::{header, HeaderMap, HeaderValue, StatusCode, Uri};
+use rust_embed::RustEmbed;
+use std::fmt::Write;
+
+/// The page and its build outputs. Run `./build-wasm.sh` and build the CSS before building the
+/// server, or they will be missing.
+#[derive(RustEmbed)]
+#[folder = "../web-src"]
+#[include = "index.html"]
+#[include = "watch.html"]
+#[include = "worker.js"]
+#[include = "dist/*"]
+#[include = "pkg/*"]
+#[exclude = "*.br"]
+#[exclude = "*.gz"]
+struct Assets;
+
+/// Checks that the wasm build was embedded, rather than failing on the first page load.
+pub fn check() -> anyhow::Result<()> {
+    for path in ["index.html", "dist/output.css", "pkg/chip_8_emulator.js"] {
+        anyhow::ensure!(
+            Assets::get(path).is_some(),
+            "{path} was not embedded; build the web assets before the server"
+        );
+    }
+
+    Ok(())
+}
+
+/// Serves an embedded asset. File names aren't content-hashed, so browsers are asked to always
+/// revalidate, which is cheap with the `ETag`.
+pub async fn serve(uri: Uri, headers: HeaderMap) -> Response {
+    let path = match uri.path().trim_start_matches('/') {
+        "" => "index.html",
+        path => path,
+    };
+    let Some(file) = Assets::get(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mut etag = String::from("\"");
+    for byte in file.metadata.sha256_hash() {
+        write!(etag, "{byte:02x}").unwrap();
+    }
+    etag.push('"');
+
+    let is_fresh = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+    let status = if is_fresh {
+        StatusCode::NOT_MODIFIED
+    } else {
+        StatusCode::OK
+    };
+
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    if !is_fresh {
+        response = response.header(header::CONTENT_TYPE, file.metadata.mimetype());
+    }
+
+    let body = if is_fresh {
+        Body::empty()
+    } else {
+        Body::from(file.data)
+    };
+    response.body(body).unwrap()
+}
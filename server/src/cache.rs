@@ -0,0 +1,58 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+use http::{header, HeaderValue};
+
+/// Whether a file name contains a content hash, e.g. `output.3f9a2b1c.css` or `app-3f9a2b1c.js`,
+/// so that its contents never change.
+fn is_hashed(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    file_name.split(['.', '-']).any(|part| {
+        part.len() >= 8
+            && part.bytes().all(|b| b.is_ascii_hexdigit())
+            && part.bytes().any(|b| b.is_ascii_digit())
+    })
+}
+
+/// Middleware that lets browsers keep hashed assets forever, and makes them revalidate everything
+/// else. Responses that already set `Cache-Control` are left alone.
+pub async fn set_cache_control(request: Request, next: Next) -> Response {
+    let is_hashed = is_hashed(request.uri().path());
+    let mut response = next.run(request).await;
+
+    if response.status().is_success() && !response.headers().contains_key(header::CACHE_CONTROL) {
+        let cache_control = if is_hashed {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        };
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(cache_control),
+        );
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_file_names() {
+        assert!(is_hashed("/output.3f9a2b1c.css"));
+        assert!(is_hashed("/pkg/app-3f9a2b1c.js"));
+        assert!(is_hashed("chip_8_emulator_bg-0123456789abcdef.wasm"));
+    }
+
+    #[test]
+    fn unhashed_file_names() {
+        assert!(!is_hashed("/"));
+        assert!(!is_hashed("/index.html"));
+        assert!(!is_hashed("/pkg/chip_8_emulator.js"));
+        // Too short, or a word that happens to be all hex letters
+        assert!(!is_hashed("/app.3f9a2b.js"));
+        assert!(!is_hashed("/deadbeefcafe.js"));
+        // Only the file name counts, not the directories leading to it
+        assert!(!is_hashed("/3f9a2b1c/index.html"));
+    }
+}
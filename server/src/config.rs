@@ -0,0 +1,306 @@
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use clap::{Parser, ValueEnum};
+use http::HeaderValue;
+use serde::Deserialize;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_PORT: u16 = 3000;
+
+/// Command-line flags. Each one overrides the same setting in the config file.
+#[derive(Debug, Parser)]
+pub struct Args {
+    /// TOML file to read settings from, with the same names as these flags
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    settings: Config,
+}
+
+/// Settings that can be given either as flags or in the config file.
+#[derive(Debug, Default, Deserialize, Parser)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    /// Address to listen on, e.g. `0.0.0.0` or `::` for every interface [default: 127.0.0.1]
+    #[clap(long)]
+    bind: Option<IpAddr>,
+
+    /// Port to start the server on [default: 3000]
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// Directory of web assets to serve [default: web-src, or the embedded assets if built with
+    /// them]
+    #[clap(long)]
+    root: Option<PathBuf>,
+
+    /// Directory of ROMs to serve under `/roms` [default: roms]
+    #[clap(long)]
+    roms_dir: Option<PathBuf>,
+
+    /// Accept high scores at `/api/scores`
+    #[clap(long)]
+    leaderboard: bool,
+
+    /// JSON Lines file the leaderboard is stored in [default: scores.jsonl]
+    #[clap(long)]
+    scores_file: Option<PathBuf>,
+
+    /// Pair up players for two-player games at `/api/netplay`
+    #[clap(long)]
+    netplay: bool,
+
+    /// Relay games broadcast to spectators at `/api/spectate`
+    #[clap(long)]
+    spectate: bool,
+
+    /// Most emulator sessions that `/api/sessions` hosts at once. The API is off while this is 0
+    /// [default: 0]
+    #[clap(long)]
+    max_sessions: Option<usize>,
+
+    /// Origin, e.g. `https://example.com`, whose pages may change things through the API, such as
+    /// submitting scores. Can be given more than once. Pages on any origin may read from it
+    #[clap(long)]
+    allow_origin: Vec<String>,
+
+    /// PEM certificate chain to serve HTTPS with
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS with a self-signed certificate generated at startup, for testing on a LAN
+    #[clap(long, conflicts_with = "tls_cert")]
+    self_signed: bool,
+
+    /// Rebuild the web assets when `emulator/src` or the static root changes, and reload open
+    /// pages. Must be run from the repository root
+    #[clap(long)]
+    dev: bool,
+
+    /// Format of the access logs. Verbosity is set with `RUST_LOG` [default: text]
+    #[clap(long)]
+    log_format: Option<LogFormat>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl Config {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let toml = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config = toml::from_str::<Self>(&toml)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+
+        // Paths in the config file are relative to it rather than to the working directory
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for path in [
+            &mut config.root,
+            &mut config.roms_dir,
+            &mut config.scores_file,
+            &mut config.tls_cert,
+            &mut config.tls_key,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+
+        Ok(config)
+    }
+
+    fn tls(&self) -> anyhow::Result<Option<Tls>> {
+        Ok(match (&self.tls_cert, &self.tls_key, self.self_signed) {
+            (None, None, false) => None,
+            (None, None, true) => Some(Tls::SelfSigned),
+            (Some(cert), Some(key), false) => Some(Tls::Files {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            (Some(_), Some(_), true) => {
+                anyhow::bail!("Use either a TLS certificate or a self-signed one, not both")
+            }
+            _ => anyhow::bail!("A TLS certificate and key must be given together"),
+        })
+    }
+
+    /// Whether any TLS setting is given.
+    fn has_tls(&self) -> bool {
+        self.tls_cert.is_some() || self.tls_key.is_some() || self.self_signed
+    }
+
+    /// Fills in settings missing here from `other`. The TLS settings are taken together from
+    /// whichever gives any, so that e.g. `--self-signed` replaces a certificate from the file.
+    fn or(self, other: Self) -> Self {
+        let (tls_cert, tls_key, self_signed) = if self.has_tls() {
+            (self.tls_cert, self.tls_key, self.self_signed)
+        } else {
+            (other.tls_cert, other.tls_key, other.self_signed)
+        };
+
+        Self {
+            bind: self.bind.or(other.bind),
+            port: self.port.or(other.port),
+            root: self.root.or(other.root),
+            roms_dir: self.roms_dir.or(other.roms_dir),
+            leaderboard: self.leaderboard || other.leaderboard,
+            scores_file: self.scores_file.or(other.scores_file),
+            netplay: self.netplay || other.netplay,
+            spectate: self.spectate || other.spectate,
+            max_sessions: self.max_sessions.or(other.max_sessions),
+            allow_origin: if self.allow_origin.is_empty() {
+                other.allow_origin
+            } else {
+                self.allow_origin
+            },
+            tls_cert,
+            tls_key,
+            self_signed,
+            dev: self.dev || other.dev,
+            log_format: self.log_format.or(other.log_format),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Tls {
+    Files { cert: PathBuf, key: PathBuf },
+    SelfSigned,
+}
+
+impl Tls {
+    pub async fn rustls_config(&self, addr: SocketAddr) -> anyhow::Result<RustlsConfig> {
+        match self {
+            Self::Files { cert, key } => RustlsConfig::from_pem_file(cert, key)
+                .await
+                .context("Failed to load TLS certificate"),
+            Self::SelfSigned => {
+                let mut names = vec!["localhost".to_owned()];
+                if !addr.ip().is_unspecified() {
+                    names.push(addr.ip().to_string());
+                }
+
+                let certified_key = rcgen::generate_simple_self_signed(names)?;
+                Ok(RustlsConfig::from_pem(
+                    certified_key.cert.pem().into_bytes(),
+                    certified_key.signing_key.serialize_pem().into_bytes(),
+                )
+                .await?)
+            }
+        }
+    }
+}
+
+/// Settings resolved from the flags, the config file and the defaults, in that order.
+#[derive(Debug)]
+pub struct Settings {
+    pub addr: SocketAddr,
+    /// `None` serves the embedded assets.
+    pub root: Option<PathBuf>,
+    pub roms_dir: PathBuf,
+    pub leaderboard: bool,
+    pub scores_file: PathBuf,
+    pub netplay: bool,
+    pub spectate: bool,
+    pub max_sessions: usize,
+    pub allowed_origins: Vec<HeaderValue>,
+    pub tls: Option<Tls>,
+    pub dev: bool,
+    pub log_format: LogFormat,
+}
+
+impl Settings {
+    pub fn from_args(args: Args) -> anyhow::Result<Self> {
+        let config = match &args.config {
+            Some(path) => args.settings.or(Config::load(path)?),
+            None => args.settings,
+        };
+
+        let tls = config.tls()?;
+        let allowed_origins = config
+            .allow_origin
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).with_context(|| format!("Invalid origin {origin}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let default_root = (!cfg!(feature = "embed")).then(|| PathBuf::from("web-src"));
+        Ok(Self {
+            addr: SocketAddr::new(
+                config.bind.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                config.port.unwrap_or(DEFAULT_PORT),
+            ),
+            root: config.root.or(default_root),
+            roms_dir: config.roms_dir.unwrap_or_else(|| PathBuf::from("roms")),
+            leaderboard: config.leaderboard,
+            scores_file: config
+                .scores_file
+                .unwrap_or_else(|| PathBuf::from("scores.jsonl")),
+            netplay: config.netplay,
+            spectate: config.spectate,
+            max_sessions: config.max_sessions.unwrap_or_default(),
+            allowed_origins,
+            tls,
+            dev: config.dev,
+            log_format: config.log_format.unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The TLS settings from `flags` on top of the config file `toml`.
+    fn tls(flags: &[&str], toml: &str) -> anyhow::Result<Option<Tls>> {
+        let flags = Config::try_parse_from(["chip-8-server"].iter().chain(flags))?;
+        flags.or(toml::from_str(toml)?).tls()
+    }
+
+    #[test]
+    fn tls_flags_replace_the_files_tls_settings() {
+        let files = r#"
+            tls-cert = "cert.pem"
+            tls-key = "key.pem"
+        "#;
+        assert!(matches!(
+            tls(&["--self-signed"], files).unwrap(),
+            Some(Tls::SelfSigned)
+        ));
+
+        let flags = ["--tls-cert", "flag-cert.pem", "--tls-key", "flag-key.pem"];
+        let Some(Tls::Files { cert, key }) = tls(&flags, "self-signed = true").unwrap() else {
+            panic!("The flags' certificate should be used");
+        };
+        assert_eq!((cert, key), ("flag-cert.pem".into(), "flag-key.pem".into()));
+    }
+
+    #[test]
+    fn tls_settings_from_one_source_are_checked_together() {
+        assert!(matches!(
+            tls(&[], "self-signed = true").unwrap(),
+            Some(Tls::SelfSigned)
+        ));
+        assert!(tls(&[], "").unwrap().is_none());
+        assert!(tls(&[], "tls-cert = \"cert.pem\"").is_err());
+        let both = "tls-key = \"key.pem\"\ntls-cert = \"cert.pem\"\nself-signed = true";
+        assert!(tls(&[], both).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use http::{header, request::Parts, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// The method a request uses, or for a preflight, the one the request that follows it will.
+fn requested_method(parts: &Parts) -> &[u8] {
+    match parts.headers.get(header::ACCESS_CONTROL_REQUEST_METHOD) {
+        Some(method) if parts.method == Method::OPTIONS => method.as_bytes(),
+        _ => parts.method.as_str().as_bytes(),
+    }
+}
+
+/// Lets pages on any origin read from the server, but only pages on `allowed_origins` (and the
+/// server's own, which CORS doesn't apply to) change anything, e.g. by submitting a score.
+pub fn layer(allowed_origins: Vec<HeaderValue>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
+        .allow_headers([header::CONTENT_TYPE])
+        .allow_origin(AllowOrigin::predicate(move |origin, parts| {
+            matches!(requested_method(parts), b"GET" | b"HEAD") || allowed_origins.contains(origin)
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    const ALLOWED: &str = "https://allowed.example";
+
+    /// The `Access-Control-Allow-Origin` that a request from `origin` gets back.
+    async fn allowed_origin(request: http::request::Builder, origin: &str) -> Option<HeaderValue> {
+        let router = Router::new()
+            .route("/api", get(|| async {}).post(|| async {}))
+            .layer(layer(vec![HeaderValue::from_static(ALLOWED)]));
+        let response = router
+            .oneshot(
+                request
+                    .header(header::ORIGIN, origin)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .cloned()
+    }
+
+    fn preflight(method: &str) -> http::request::Builder {
+        Request::options("/api").header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+    }
+
+    #[tokio::test]
+    async fn any_origin_can_read() {
+        let other = "https://other.example";
+        assert_eq!(
+            allowed_origin(Request::get("/api"), other).await.unwrap(),
+            other
+        );
+        assert_eq!(
+            allowed_origin(preflight("GET"), other).await.unwrap(),
+            other
+        );
+    }
+
+    #[tokio::test]
+    async fn only_allowed_origins_can_change_things() {
+        for method in ["POST", "PUT", "DELETE"] {
+            assert_eq!(
+                allowed_origin(preflight(method), "https://other.example").await,
+                None
+            );
+            assert_eq!(
+                allowed_origin(preflight(method), ALLOWED).await.unwrap(),
+                ALLOWED
+            );
+        }
+        assert_eq!(
+            allowed_origin(Request::post("/api"), "https://other.example").await,
+            None
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::Context;
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive},
+        Html, IntoResponse, Response, Sse,
+    },
+};
+use http::StatusCode;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    process::Command,
+    sync::{broadcast, mpsc},
+    time,
+};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+const EMULATOR_SRC: &str = "emulator/src";
+/// Changes arriving within this long of each other are handled with a single rebuild.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+/// Build outputs inside the static root, which must not trigger another build.
+const IGNORED_DIRS: [&str; 3] = ["pkg", "dist", "node_modules"];
+
+/// What connected pages are told after files change.
+#[derive(Clone, Debug)]
+enum Notice {
+    Reload,
+    BuildFailed(String),
+}
+
+/// Rebuilds the web assets when their sources change, and tells connected pages to reload.
+#[derive(Debug)]
+pub struct LiveReload {
+    root: PathBuf,
+    notices: broadcast::Sender<Notice>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl LiveReload {
+    /// Starts watching `emulator/src` and `root`. Must be run from the repository root.
+    pub fn spawn(root: &Path) -> anyhow::Result<Arc<Self>> {
+        let emulator_src = Path::new(EMULATOR_SRC)
+            .canonicalize()
+            .context("--dev must be run from the repository root")?;
+        let root = root.canonicalize().context("Static root not found")?;
+
+        let (changes_tx, changes_rx) = mpsc::unbounded_channel();
+        let mut watcher = {
+            let ignored = IGNORED_DIRS.map(|dir| root.join(dir));
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+                if !matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) {
+                    return;
+                }
+
+                for path in event.paths {
+                    if !ignored.iter().any(|dir| path.starts_with(dir)) {
+                        let _ = changes_tx.send(path);
+                    }
+                }
+            })?
+        };
+        watcher.watch(&emulator_src, RecursiveMode::Recursive)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        let (notices, _) = broadcast::channel(16);
+        tokio::spawn(rebuild_on_change(
+            changes_rx,
+            emulator_src,
+            root.clone(),
+            notices.clone(),
+        ));
+
+        Ok(Arc::new(Self {
+            root,
+            notices,
+            _watcher: watcher,
+        }))
+    }
+}
+
+/// Runs a build step, returning its output if it fails.
+async fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    tracing::info!("Running {program} {}", args.join(" "));
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|err| format!("Failed to run {program}: {err}"))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+}
+
+async fn rebuild_on_change(
+    mut changes: mpsc::UnboundedReceiver<PathBuf>,
+    emulator_src: PathBuf,
+    root: PathBuf,
+    notices: broadcast::Sender<Notice>,
+) {
+    while let Some(path) = changes.recv().await {
+        let mut paths = vec![path];
+        while let Ok(Some(path)) = time::timeout(DEBOUNCE, changes.recv()).await {
+            paths.push(path);
+        }
+
+        let needs_wasm = paths.iter().any(|path| path.starts_with(&emulator_src));
+        // Tailwind only generates the classes that the pages and the emulator, which sets some
+        // itself, use
+        let needs_css = needs_wasm
+            || paths.iter().any(|path| {
+                ["index.html", "watch.html", "input.css"]
+                    .iter()
+                    .any(|file| path == &root.join(file))
+            });
+
+        let mut result = Ok(());
+        if needs_wasm {
+            result = run("./build-wasm.sh", &[]).await;
+        }
+        if needs_css && result.is_ok() {
+            let root = root.to_string_lossy();
+            result = run("pnpm", &["--dir", &root, "build"]).await;
+        }
+
+        let notice = match result {
+            Ok(()) => Notice::Reload,
+            Err(output) => {
+                tracing::warn!("Build failed:\n{output}");
+                Notice::BuildFailed(output)
+            }
+        };
+        let _ = notices.send(notice);
+    }
+}
+
+/// Server-sent events telling the page to `reload`, or that a `build-failed`.
+pub async fn events(
+    State(live_reload): State<Arc<LiveReload>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let notices = BroadcastStream::new(live_reload.notices.subscribe()).filter_map(|notice| {
+        let event = match notice.ok()? {
+            Notice::Reload => Event::default().event("reload").data(""),
+            Notice::BuildFailed(output) => Event::default().event("build-failed").data(output),
+        };
+        Some(Ok(event))
+    });
+
+    Sse::new(notices).keep_alive(KeepAlive::default())
+}
+
+/// Serves `index.html` with a marker that tells the page to connect to [`events`].
+pub async fn index(State(live_reload): State<Arc<LiveReload>>) -> Response {
+    let Ok(html) = fs::read_to_string(live_reload.root.join("index.html")).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let marker = r#"<meta name="live-reload" content="/dev/events">"#;
+    Html(html.replacen("</head>", &format!("{marker}\n</head>"), 1)).into_response()
+}
@@ -0,0 +1,413 @@
+use anyhow::Context;
+use axum::{
+    extract::{Path as UrlPath, Query, State},
+    routing::{get, post},
+    Json, Router,
+};
+use chip_8_emulator::{
+    replay::Replay,
+    roms::{self, ROMS_BY_NAME},
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+};
+
+const MAX_ROM_NAME_LEN: usize = 32;
+const MAX_PLAYER_LEN: usize = 24;
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+/// Most replays played back at once, each of which keeps a blocking thread busy.
+const MAX_VERIFICATIONS: usize = 4;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Score {
+    pub rom: String,
+    pub player: String,
+    pub score: u32,
+    /// Seconds since the Unix epoch.
+    pub submitted_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewScore {
+    rom: String,
+    player: String,
+    score: u32,
+    /// Encoded [`Replay`] of the game, which is played again to check `score`.
+    replay: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Submitted {
+    /// 1-based position on the ROM's leaderboard.
+    rank: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Store {
+    /// Scores of each ROM, highest first and earliest first among ties.
+    scores: HashMap<String, Vec<Score>>,
+    file: File,
+}
+
+impl Store {
+    /// Inserts `score` in order and returns its rank.
+    fn insert(&mut self, score: Score) -> usize {
+        let scores = self.scores.entry(score.rom.clone()).or_default();
+        let index = scores.partition_point(|other| other.score >= score.score);
+        scores.insert(index, score);
+        index + 1
+    }
+}
+
+/// High scores, kept in memory and appended to a JSON Lines file so they survive restarts.
+#[derive(Debug)]
+pub struct Leaderboard {
+    store: Mutex<Store>,
+    verifications: Arc<Semaphore>,
+}
+
+impl Leaderboard {
+    pub async fn open(path: &Path) -> anyhow::Result<Arc<Self>> {
+        let lines = match fs::read_to_string(path).await {
+            Ok(lines) => lines,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut store = Store {
+            scores: HashMap::new(),
+            file,
+        };
+
+        for (index, line) in lines.lines().enumerate() {
+            match serde_json::from_str::<Score>(line) {
+                Ok(score) => {
+                    store.insert(score);
+                }
+                Err(err) => {
+                    tracing::warn!("Skipping line {} of {}: {err}", index + 1, path.display())
+                }
+            }
+        }
+
+        Ok(Arc::new(Self {
+            store: Mutex::new(store),
+            verifications: Arc::new(Semaphore::new(MAX_VERIFICATIONS)),
+        }))
+    }
+}
+
+/// `/api/scores`, serving `leaderboard`.
+pub fn routes(leaderboard: Arc<Leaderboard>) -> Router {
+    Router::new()
+        .route("/api/scores", post(submit))
+        .route("/api/scores/{rom}", get(list))
+        .with_state(leaderboard)
+}
+
+fn bad_request(message: &str) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, message.to_owned())
+}
+
+fn unverified(message: &str) -> (StatusCode, String) {
+    (StatusCode::UNPROCESSABLE_ENTITY, message.to_owned())
+}
+
+/// Plays `replay` back on the bundled ROM `rom_name` and reads the score it ends with. `permit`
+/// is held until the playback ends, even if the request is dropped before then.
+async fn verify(
+    rom_name: &str,
+    replay: Vec<u8>,
+    permit: OwnedSemaphorePermit,
+) -> Result<u32, (StatusCode, String)> {
+    let Some(rom) = ROMS_BY_NAME.get(rom_name) else {
+        return Err(bad_request("Unknown ROM"));
+    };
+    let Some(score_source) = roms::rom_info(rom_name).score else {
+        return Err(bad_request("This ROM has no leaderboard"));
+    };
+
+    // Playing back hours of frames takes a while, and a crafted replay may crash the CPU
+    let result = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        Replay::verify(&replay, rom).map(|cpu| score_source.read(&cpu.memory))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(score)) => Ok(score),
+        Ok(Err(err)) => Err(unverified(&err.to_string())),
+        Err(_) => Err(unverified("The replay crashed the emulator")),
+    }
+}
+
+/// `POST /api/scores` with `{ "rom": "BRIX", "player": "...", "score": 123, "replay": [...] }`.
+/// The score is only accepted if playing the replay back ends with it.
+pub async fn submit(
+    State(leaderboard): State<Arc<Leaderboard>>,
+    Json(new_score): Json<NewScore>,
+) -> Result<(StatusCode, Json<Submitted>), (StatusCode, String)> {
+    let rom = new_score.rom;
+    let is_valid_rom = !rom.is_empty()
+        && rom.len() <= MAX_ROM_NAME_LEN
+        && rom.bytes().all(|b| b.is_ascii_alphanumeric());
+    if !is_valid_rom {
+        return Err(bad_request("Invalid ROM name"));
+    }
+
+    let player = new_score.player.trim().to_owned();
+    if player.is_empty() || player.chars().count() > MAX_PLAYER_LEN {
+        return Err(bad_request("Player names must be 1 to 24 characters"));
+    }
+
+    let Ok(permit) = Arc::clone(&leaderboard.verifications).try_acquire_owned() else {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many scores are being checked, try again shortly".to_owned(),
+        ));
+    };
+    if verify(&rom, new_score.replay, permit).await? != new_score.score {
+        return Err(unverified("The score does not match the replay"));
+    }
+
+    let score = Score {
+        rom,
+        player,
+        score: new_score.score,
+        submitted_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs()),
+    };
+
+    let mut line = serde_json::to_string(&score).unwrap();
+    line.push('\n');
+
+    let mut store = leaderboard.store.lock().await;
+    if let Err(err) = store.file.write_all(line.as_bytes()).await {
+        tracing::error!("Failed to save score: {err}");
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save score".to_owned(),
+        ));
+    }
+    let rank = store.insert(score);
+
+    Ok((StatusCode::CREATED, Json(Submitted { rank })))
+}
+
+/// `GET /api/scores/{rom}?limit=10`, highest first.
+pub async fn list(
+    State(leaderboard): State<Arc<Leaderboard>>,
+    UrlPath(rom): UrlPath<String>,
+    Query(query): Query<ListQuery>,
+) -> Json<Vec<Score>> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let store = leaderboard.store.lock().await;
+    let scores = store
+        .scores
+        .get(&rom)
+        .map(|scores| scores.iter().take(limit).cloned().collect())
+        .unwrap_or_default();
+
+    Json(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{self, Body};
+    use chip_8_emulator::cpu::Cpu;
+    use http::{header, Request};
+    use std::{env, fs, path::PathBuf, process};
+    use tower::ServiceExt;
+
+    /// A scores file for the test `name`, starting out with `lines`.
+    fn scores_file(name: &str, lines: &[&str]) -> PathBuf {
+        let path = env::temp_dir().join(format!("chip-8-scores-{}-{name}.jsonl", process::id()));
+        fs::write(
+            &path,
+            lines
+                .iter()
+                .map(|line| format!("{line}\n"))
+                .collect::<String>(),
+        )
+        .unwrap();
+        path
+    }
+
+    fn saved_score(rom: &str, player: &str, score: u32) -> String {
+        format!(r#"{{"rom":"{rom}","player":"{player}","score":{score},"submittedAt":0}}"#)
+    }
+
+    /// Plays BRIX for 5 seconds, and returns the replay of it and the score it ended with.
+    fn play_brix() -> (Vec<u8>, u32) {
+        let mut cpu = Cpu::with_seed(&ROMS_BY_NAME["BRIX"], 1);
+        let mut replay = Replay::start(&mut cpu);
+        for frame in 0..300 {
+            cpu.keypad
+                .hold_keys(if frame < 150 { 1 << 0x4 } else { 1 << 0x6 });
+            replay.record_frame(&mut cpu);
+            cpu.run_frame();
+        }
+
+        let score = roms::rom_info("BRIX").score.unwrap().read(&cpu.memory);
+        (replay.encode(&cpu), score)
+    }
+
+    async fn send(leaderboard: &Arc<Leaderboard>, request: Request<Body>) -> (StatusCode, String) {
+        let response = routes(Arc::clone(leaderboard))
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn submit(
+        leaderboard: &Arc<Leaderboard>,
+        rom: &str,
+        player: &str,
+        score: u32,
+        replay: &[u8],
+    ) -> (StatusCode, String) {
+        let new_score = serde_json::json!({
+            "rom": rom,
+            "player": player,
+            "score": score,
+            "replay": replay,
+        });
+        let request = Request::post("/api/scores")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(new_score.to_string()))
+            .unwrap();
+        send(leaderboard, request).await
+    }
+
+    async fn players(leaderboard: &Arc<Leaderboard>, uri: &str) -> Vec<String> {
+        let (status, body) =
+            send(leaderboard, Request::get(uri).body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_str::<Vec<Score>>(&body)
+            .unwrap()
+            .into_iter()
+            .map(|score| score.player)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn scores_are_listed_highest_and_earliest_first() {
+        let path = scores_file(
+            "listed",
+            &[
+                &saved_score("BRIX", "a", 50),
+                &saved_score("BRIX", "b", 70),
+                "not a score",
+                &saved_score("TETRIS", "c", 90),
+                &saved_score("BRIX", "d", 50),
+            ],
+        );
+        let leaderboard = Leaderboard::open(&path).await.unwrap();
+
+        assert_eq!(
+            players(&leaderboard, "/api/scores/BRIX").await,
+            ["b", "a", "d"]
+        );
+        assert_eq!(
+            players(&leaderboard, "/api/scores/BRIX?limit=2").await,
+            ["b", "a"]
+        );
+        assert_eq!(players(&leaderboard, "/api/scores/TETRIS").await, ["c"]);
+        assert!(players(&leaderboard, "/api/scores/PONG").await.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verified_scores_are_saved_and_reloaded() {
+        let path = scores_file("saved", &[&saved_score("BRIX", "a", 1000)]);
+        let leaderboard = Leaderboard::open(&path).await.unwrap();
+        let (replay, score) = play_brix();
+
+        let (status, body) = submit(&leaderboard, "BRIX", "  b  ", score, &replay).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, r#"{"rank":2}"#);
+
+        let reloaded = Leaderboard::open(&path).await.unwrap();
+        assert_eq!(players(&reloaded, "/api/scores/BRIX").await, ["a", "b"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn scores_that_dont_match_their_replay_are_rejected() {
+        let path = scores_file("rejected", &[]);
+        let leaderboard = Leaderboard::open(&path).await.unwrap();
+        let (replay, score) = play_brix();
+
+        for (rom, score, replay) in [
+            ("BRIX", score + 10, &replay[..]),
+            ("TETRIS", score, &replay[..]),
+            ("BRIX", score, &replay[..replay.len() - 1]),
+        ] {
+            let (status, _) = submit(&leaderboard, rom, "a", score, replay).await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        assert_eq!(
+            submit(&leaderboard, "PONG", "a", score, &replay).await.0,
+            StatusCode::BAD_REQUEST
+        );
+
+        assert!(players(&leaderboard, "/api/scores/BRIX").await.is_empty());
+        assert!(fs::read_to_string(&path).unwrap().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn names_are_checked() {
+        let path = scores_file("names", &[]);
+        let leaderboard = Leaderboard::open(&path).await.unwrap();
+        let (replay, score) = play_brix();
+
+        for (rom, player) in [
+            ("BRIX", ""),
+            ("BRIX", "   "),
+            ("BRIX", &"a".repeat(MAX_PLAYER_LEN + 1)),
+            ("", "a"),
+            ("../BRIX", "a"),
+        ] {
+            let (status, _) = submit(&leaderboard, rom, player, score, &replay).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{rom:?} {player:?}");
+        }
+
+        let longest = "é".repeat(MAX_PLAYER_LEN);
+        let (status, _) = submit(&leaderboard, "BRIX", &longest, score, &replay).await;
+        assert_eq!(status, StatusCode::CREATED);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,35 +1,66 @@
-use axum::Router;
-use clap::Parser;
-use http::{
-    header::{self, HeaderName, HeaderValue},
-    Method,
+#[cfg(feature = "embed")]
+mod assets;
+mod cache;
+mod config;
+mod cors;
+mod dev;
+mod leaderboard;
+mod metrics;
+mod netplay;
+mod sessions;
+mod spectate;
+
+use crate::{
+    config::{Args, LogFormat, Settings},
+    dev::LiveReload,
+    leaderboard::Leaderboard,
+    metrics::Metrics,
 };
-use std::net::Ipv4Addr;
+use axum::{middleware, routing::get, Router};
+use clap::Parser;
+use http::header::{self, HeaderName, HeaderValue};
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_http::{
-    cors::{self, CorsLayer},
+    compression::CompressionLayer,
     services::ServeDir,
+    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
     ServiceBuilderExt,
 };
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
 
-#[derive(Debug, Parser)]
-struct Args {
-    /// Port to start the server on
-    #[clap(long, default_value_t = 3000)]
-    port: u16,
+fn set_up_logging(log_format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("chip_8_server=info,tower_http=info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let Args { port } = Args::try_parse()?;
-
-    let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::OPTIONS])
-        .allow_origin(cors::Any);
+    let settings = Settings::from_args(Args::try_parse()?)?;
+    set_up_logging(settings.log_format);
+    let metrics = Arc::new(Metrics::new());
 
     let service_builder = ServiceBuilder::new()
-        .layer(cors)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&metrics),
+            metrics::track,
+        ))
+        .layer(CompressionLayer::new())
+        .layer(middleware::from_fn(cache::set_cache_control))
+        .layer(cors::layer(settings.allowed_origins.clone()))
         .append_response_header(
             HeaderName::from_static("cross-origin-embedder-policy"),
             HeaderValue::from_static("require-corp"),
@@ -49,12 +80,83 @@ async fn main() -> anyhow::Result<()> {
         .append_response_header(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"))
         .append_response_header(header::X_XSS_PROTECTION, HeaderValue::from_static("0"));
 
+    // Serve `.br` and `.gz` files next to the originals instead of compressing on the fly
+    let serve_dir = |dir| ServeDir::new(dir).precompressed_br().precompressed_gzip();
+
     let router = Router::new()
-        .fallback_service(ServeDir::new("web-src"))
-        .layer(service_builder);
+        .route("/healthz", get(metrics::healthz))
+        .route("/metrics", get(metrics::serve))
+        .with_state(metrics)
+        .nest_service("/roms", serve_dir(&settings.roms_dir));
+    let router = if settings.leaderboard {
+        router.merge(leaderboard::routes(
+            Leaderboard::open(&settings.scores_file).await?,
+        ))
+    } else {
+        router
+    };
+    let router = if settings.netplay {
+        router.merge(netplay::routes(netplay::Lobby::new()))
+    } else {
+        router
+    };
+    let router = if settings.spectate {
+        router.merge(spectate::routes(spectate::Channels::new()))
+    } else {
+        router
+    };
+    let router = if settings.max_sessions > 0 {
+        router.merge(sessions::routes(sessions::Sessions::new(
+            settings.max_sessions,
+        )))
+    } else {
+        router
+    };
+    let router = if settings.dev {
+        let Some(root) = &settings.root else {
+            anyhow::bail!("--dev needs a static root to watch, rather than the embedded assets");
+        };
+        let live_reload = LiveReload::spawn(root)?;
+
+        router.merge(
+            Router::new()
+                .route("/", get(dev::index))
+                .route("/index.html", get(dev::index))
+                .route("/dev/events", get(dev::events))
+                .with_state(live_reload),
+        )
+    } else {
+        router
+    };
+    let router = match &settings.root {
+        Some(root) => router.fallback_service(serve_dir(root)),
+        #[cfg(feature = "embed")]
+        None => {
+            assets::check()?;
+            router.fallback(assets::serve)
+        }
+        #[cfg(not(feature = "embed"))]
+        None => unreachable!("The root defaults to `web-src` without embedded assets"),
+    };
+    let router = router.layer(service_builder);
+
+    match &settings.tls {
+        Some(tls) => {
+            // Only one provider is compiled in, so this can only fail if it is already installed
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            let rustls_config = tls.rustls_config(settings.addr).await?;
 
-    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
-    axum::serve(listener, router.into_make_service()).await?;
+            tracing::info!("Listening on https://{}", settings.addr);
+            axum_server::bind_rustls(settings.addr, rustls_config)
+                .serve(router.into_make_service())
+                .await?;
+        }
+        None => {
+            let listener = TcpListener::bind(settings.addr).await?;
+            tracing::info!("Listening on http://{}", settings.addr);
+            axum::serve(listener, router.into_make_service()).await?;
+        }
+    }
 
     Ok(())
 }
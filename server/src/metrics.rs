@@ -0,0 +1,176 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header, HeaderValue};
+use http_body_util::BodyExt;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// Request counters, exposed at `/metrics` in the Prometheus text format.
+#[derive(Debug)]
+pub struct Metrics {
+    started: Instant,
+    in_flight: AtomicU64,
+    /// Finished requests by status class, from 1xx to 5xx.
+    responses: [AtomicU64; 5],
+    response_bytes: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            in_flight: AtomicU64::new(0),
+            responses: Default::default(),
+            response_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+
+        let _ = writeln!(
+            text,
+            "# HELP chip8_uptime_seconds Time since the server started.\n\
+             # TYPE chip8_uptime_seconds gauge\n\
+             chip8_uptime_seconds {}",
+            self.started.elapsed().as_secs_f64()
+        );
+        let _ = writeln!(
+            text,
+            "# HELP chip8_http_requests_in_flight Requests being handled.\n\
+             # TYPE chip8_http_requests_in_flight gauge\n\
+             chip8_http_requests_in_flight {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            text,
+            "# HELP chip8_http_responses_total Responses sent, by status class.\n\
+             # TYPE chip8_http_responses_total counter"
+        );
+        for (class, count) in self.responses.iter().enumerate() {
+            let _ = writeln!(
+                text,
+                "chip8_http_responses_total{{status=\"{}xx\"}} {}",
+                class + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(
+            text,
+            "# HELP chip8_http_response_bytes_total Response body bytes sent, after compression.\n\
+             # TYPE chip8_http_response_bytes_total counter\n\
+             chip8_http_response_bytes_total {}",
+            self.response_bytes.load(Ordering::Relaxed)
+        );
+
+        text
+    }
+}
+
+/// Counts a request as in flight until dropped, which also happens when the client disconnects
+/// and the request's future is dropped.
+struct InFlight<'a>(&'a AtomicU64);
+
+impl<'a> InFlight<'a> {
+    fn start(in_flight: &'a AtomicU64) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Middleware that counts every request, and the bytes of each response's body as they are sent.
+pub async fn track(State(metrics): State<Arc<Metrics>>, request: Request, next: Next) -> Response {
+    let in_flight = InFlight::start(&metrics.in_flight);
+    let response = next.run(request).await;
+    drop(in_flight);
+
+    let class = (response.status().as_u16() / 100).clamp(1, 5) as usize;
+    metrics.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+
+    // Compressed and streamed bodies have no length up front, so each chunk is counted instead
+    response.map(|body| {
+        Body::new(body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                metrics
+                    .response_bytes
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            frame
+        }))
+    })
+}
+
+pub async fn serve(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        metrics.render(),
+    )
+}
+
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn streamed_bodies_are_counted_as_they_are_sent() {
+        let metrics = Arc::new(Metrics::new());
+        let router = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    let chunks = ["abc", "defg"].map(Ok::<_, std::convert::Infallible>);
+                    Body::from_stream(tokio_stream::iter(chunks))
+                }),
+            )
+            .layer(middleware::from_fn_with_state(Arc::clone(&metrics), track));
+
+        let response = router
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        assert_eq!(metrics.response_bytes.load(Ordering::Relaxed), 0);
+
+        body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(metrics.response_bytes.load(Ordering::Relaxed), 7);
+        assert!(metrics
+            .render()
+            .contains("chip8_http_responses_total{status=\"2xx\"} 1"));
+    }
+}
@@ -0,0 +1,319 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chip_8_emulator::{
+    lockstep,
+    roms::{self, ROMS_BY_NAME},
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::mpsc;
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+/// Large enough for a resync, which carries a whole save state.
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Everything the relay sends players, including what the other player sent, so that a player
+/// can't pass off their own messages as the relay's.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum RoomMessage {
+    /// Joined an empty room as the host.
+    Waiting,
+    /// Both players are here. `keys` are the keys each player controls.
+    Start {
+        player: usize,
+        rom: String,
+        seed: u64,
+        keys: [&'static [u8]; 2],
+    },
+    PeerLeft,
+    Full,
+    /// A message from the other player.
+    Peer {
+        message: lockstep::Message,
+    },
+}
+
+impl From<RoomMessage> for Message {
+    fn from(message: RoomMessage) -> Self {
+        Self::Text(serde_json::to_string(&message).unwrap().into())
+    }
+}
+
+#[derive(Debug)]
+struct Room {
+    /// Tells apart rooms that reuse the name of one that has closed.
+    id: u64,
+    rom: String,
+    players: [Option<mpsc::UnboundedSender<Message>>; 2],
+}
+
+/// Pairs up players who join the same room, and relays their messages to each other. The first
+/// to join picks the game.
+#[derive(Debug, Default)]
+pub struct Lobby {
+    rooms: Mutex<HashMap<String, Room>>,
+    next_room_id: AtomicU64,
+}
+
+impl Lobby {
+    #[must_use]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Adds a player to room `name`, and returns the room's ID and their player number, or `None`
+    /// if the room is full.
+    fn join(
+        &self,
+        name: &str,
+        rom: String,
+        player: mpsc::UnboundedSender<Message>,
+    ) -> Option<(u64, usize)> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get_mut(name) else {
+            let id = self.next_room_id.fetch_add(1, Ordering::Relaxed);
+            let _ = player.send(RoomMessage::Waiting.into());
+            rooms.insert(
+                name.to_owned(),
+                Room {
+                    id,
+                    rom,
+                    players: [Some(player), None],
+                },
+            );
+            return Some((id, 0));
+        };
+
+        if room.players[1].is_some() {
+            return None;
+        }
+        room.players[1] = Some(player);
+
+        let seed = rand::random();
+        let keys = roms::rom_info(&room.rom).players;
+        for (number, player) in room.players.iter().flatten().enumerate() {
+            let _ = player.send(
+                RoomMessage::Start {
+                    player: number,
+                    rom: room.rom.clone(),
+                    seed,
+                    keys,
+                }
+                .into(),
+            );
+        }
+        Some((room.id, 1))
+    }
+
+    /// Passes `message` on to the other player in the room.
+    fn relay(&self, name: &str, id: u64, player: usize, message: lockstep::Message) {
+        let rooms = self.rooms.lock().unwrap();
+        if let Some(peer) = rooms
+            .get(name)
+            .filter(|room| room.id == id)
+            .and_then(|room| room.players[1 - player].as_ref())
+        {
+            let _ = peer.send(RoomMessage::Peer { message }.into());
+        }
+    }
+
+    /// Closes the room once either player leaves, which disconnects the other.
+    fn leave(&self, name: &str, id: u64, player: usize) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.get(name).is_some_and(|room| room.id == id) {
+            let room = rooms.remove(name).unwrap();
+            if let Some(peer) = &room.players[1 - player] {
+                let _ = peer.send(RoomMessage::PeerLeft.into());
+            }
+        }
+    }
+}
+
+/// `/api/netplay`, pairing players in `lobby`.
+pub fn routes(lobby: Arc<Lobby>) -> Router {
+    Router::new()
+        .route("/api/netplay/{room}", get(join))
+        .with_state(lobby)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JoinQuery {
+    rom: String,
+}
+
+/// `GET /api/netplay/{room}?rom=PONG`, upgraded to a WebSocket.
+pub async fn join(
+    ws: WebSocketUpgrade,
+    State(lobby): State<Arc<Lobby>>,
+    Path(room): Path<String>,
+    Query(JoinQuery { rom }): Query<JoinQuery>,
+) -> Response {
+    let is_valid_room = !room.is_empty()
+        && room.len() <= MAX_ROOM_NAME_LEN
+        && room
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ');
+    if !is_valid_room {
+        return (StatusCode::BAD_REQUEST, "Invalid room name").into_response();
+    }
+    if !ROMS_BY_NAME.contains_key(&rom) {
+        return (StatusCode::BAD_REQUEST, "Unknown ROM").into_response();
+    }
+
+    ws.max_message_size(MAX_MESSAGE_BYTES)
+        .on_upgrade(move |socket| play(lobby, room, rom, socket))
+}
+
+async fn play(lobby: Arc<Lobby>, room: String, rom: String, mut socket: WebSocket) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let Some((id, player)) = lobby.join(&room, rom, tx) else {
+        let _ = socket.send(RoomMessage::Full.into()).await;
+        return;
+    };
+    tracing::info!("Player {} joined netplay room {room}", player + 1);
+
+    loop {
+        tokio::select! {
+            outgoing = rx.recv() => {
+                // The room closed
+                let Some(message) = outgoing else {
+                    break;
+                };
+                if socket.send(message).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                // Anything that isn't a lockstep message is dropped
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(message) = serde_json::from_str(&text) {
+                        lobby.relay(&room, id, player, message);
+                    }
+                }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+
+    lobby.leave(&room, id, player);
+    tracing::info!("Player {} left netplay room {room}", player + 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    type Player = (u64, usize, mpsc::UnboundedReceiver<Message>);
+
+    fn join(lobby: &Lobby, room: &str) -> Option<Player> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (id, player) = lobby.join(room, "PONG".to_owned(), tx)?;
+        Some((id, player, rx))
+    }
+
+    /// The messages that have been sent to a player, as JSON.
+    fn received(rx: &mut mpsc::UnboundedReceiver<Message>) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            let Message::Text(text) = message else {
+                panic!("Expected a text message, got {message:?}");
+            };
+            messages.push(serde_json::from_str(&text).unwrap());
+        }
+        messages
+    }
+
+    fn input(frame: u32) -> lockstep::Message {
+        lockstep::Message::Input {
+            epoch: 0,
+            frame,
+            keys: 1,
+        }
+    }
+
+    #[test]
+    fn the_first_two_players_are_paired_and_others_turned_away() {
+        let lobby = Lobby::new();
+        let (host_room, host, mut host_rx) = join(&lobby, "room").unwrap();
+        assert_eq!(host, 0);
+        assert_eq!(received(&mut host_rx), [json!({ "type": "waiting" })]);
+
+        let (guest_room, guest, mut guest_rx) = join(&lobby, "room").unwrap();
+        assert_eq!((guest_room, guest), (host_room, 1));
+        let [host_start] = &received(&mut host_rx)[..] else {
+            panic!("The host should be sent one message");
+        };
+        let [guest_start] = &received(&mut guest_rx)[..] else {
+            panic!("The guest should be sent one message");
+        };
+        assert_eq!(host_start["type"], "start");
+        assert_eq!(host_start["player"], 0);
+        assert_eq!(guest_start["player"], 1);
+        assert_eq!(host_start["rom"], "PONG");
+        assert_eq!(host_start["seed"], guest_start["seed"]);
+        assert_eq!(host_start["keys"], json!([[1, 4], [0xC, 0xD]]));
+
+        assert!(join(&lobby, "room").is_none());
+        assert!(join(&lobby, "other room").is_some());
+    }
+
+    #[test]
+    fn messages_only_reach_the_other_player_in_the_same_room() {
+        let lobby = Lobby::new();
+        let (room, _, mut host_rx) = join(&lobby, "room").unwrap();
+        let (_, _, mut guest_rx) = join(&lobby, "room").unwrap();
+        let (_, _, mut other_rx) = join(&lobby, "other room").unwrap();
+        for rx in [&mut host_rx, &mut guest_rx, &mut other_rx] {
+            received(rx);
+        }
+
+        lobby.relay("room", room, 0, input(4));
+        assert_eq!(
+            received(&mut guest_rx),
+            [
+                json!({ "type": "peer", "message": { "type": "input", "epoch": 0, "frame": 4, "keys": 1 } })
+            ]
+        );
+        assert!(received(&mut host_rx).is_empty());
+        assert!(received(&mut other_rx).is_empty());
+    }
+
+    #[test]
+    fn the_room_closes_when_a_player_leaves() {
+        let lobby = Lobby::new();
+        let (room, _, mut host_rx) = join(&lobby, "room").unwrap();
+        let (_, guest, _) = join(&lobby, "room").unwrap();
+        received(&mut host_rx);
+
+        lobby.leave("room", room, guest);
+        assert_eq!(received(&mut host_rx), [json!({ "type": "peerLeft" })]);
+        assert!(!lobby.rooms.lock().unwrap().contains_key("room"));
+
+        // A new room with the same name doesn't get the old players' messages
+        let (new_room, new_host, mut new_host_rx) = join(&lobby, "room").unwrap();
+        assert_ne!(new_room, room);
+        assert_eq!(new_host, 0);
+        received(&mut new_host_rx);
+        lobby.relay("room", room, 1, input(0));
+        lobby.leave("room", room, 1);
+        assert!(received(&mut new_host_rx).is_empty());
+        assert!(lobby.rooms.lock().unwrap().contains_key("room"));
+    }
+}
@@ -0,0 +1,569 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::{self, get, post},
+    Json, Router,
+};
+use chip_8_emulator::{
+    capture,
+    cpu::{Cpu, MAX_ROM_BYTES, REGISTER_COUNT, STACK_SIZE},
+    keypad::KEY_COUNT,
+    palette::Palette,
+    quirks::Quirks,
+    roms::ROMS_BY_NAME,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+/// Sessions that go this long without a request are ended.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// One minute of emulated time.
+const MAX_FRAMES_PER_STEP: u32 = 60 * 60;
+const DEFAULT_SCALE: u32 = 8;
+const MAX_SCALE: u32 = 32;
+
+/// A CPU and how far it has run. Each one lives on its own thread, so that long steps don't hold up
+/// the server, and is reached through [`Sessions::run`].
+#[derive(Debug)]
+struct Machine {
+    cpu: Cpu,
+    frame: u64,
+}
+
+impl Machine {
+    fn registers(&self) -> Registers {
+        let cpu = &self.cpu;
+        Registers {
+            v: cpu.regs,
+            i: cpu.i_reg,
+            pc: cpu.pc,
+            sp: cpu.sp,
+            stack: cpu.stack,
+            delay_timer: cpu.delay_timer,
+            sound_timer: cpu.sound_timer,
+            frame: self.frame,
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce(&mut Machine) + Send>;
+
+#[derive(Debug)]
+struct Session {
+    jobs: mpsc::Sender<Job>,
+    last_used: Instant,
+}
+
+/// Emulators hosted by the server and driven over HTTP, e.g. by scripts and bots.
+#[derive(Debug)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    max_sessions: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewSession {
+    /// Name of a bundled ROM. Either this or `program` must be given.
+    rom: Option<String>,
+    program: Option<Vec<u8>>,
+    /// Seed of the random number generator [default: random].
+    seed: Option<u64>,
+    #[serde(default)]
+    quirks: Quirks,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Created {
+    id: String,
+    seed: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StepQuery {
+    frames: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Step {
+    /// The keys to hold down while stepping. Any others are released.
+    keys: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameFormat {
+    #[default]
+    Png,
+    /// Each row as a big-endian `u64`, with the leftmost pixel in the highest bit.
+    Bits,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FrameQuery {
+    #[serde(default)]
+    format: FrameFormat,
+    scale: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Registers {
+    v: [u8; REGISTER_COUNT],
+    i: u16,
+    pc: u16,
+    sp: u8,
+    stack: [u16; STACK_SIZE],
+    delay_timer: u8,
+    sound_timer: u8,
+    /// Frames run since the session started.
+    frame: u64,
+}
+
+fn bad_request(message: &str) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, message.to_owned())
+}
+
+fn not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "No such session".to_owned())
+}
+
+impl Sessions {
+    /// Must be called from within the runtime, which ends idle sessions.
+    #[must_use]
+    pub fn new(max_sessions: usize) -> Arc<Self> {
+        let sessions = Arc::new(Self {
+            sessions: Mutex::new(HashMap::new()),
+            max_sessions,
+        });
+        tokio::spawn(end_idle_sessions(Arc::downgrade(&sessions)));
+        sessions
+    }
+
+    fn start(
+        &self,
+        program: Vec<u8>,
+        seed: u64,
+        quirks: Quirks,
+    ) -> Result<String, (StatusCode, String)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= self.max_sessions {
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many sessions are running".to_owned(),
+            ));
+        }
+
+        let id = format!("{:016x}", rand::random::<u64>());
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name(format!("session-{id}"))
+            .spawn(move || {
+                let mut cpu = Cpu::with_seed(&program, seed);
+                cpu.quirks = quirks;
+                let mut machine = Machine { cpu, frame: 0 };
+
+                // Ends once the session is dropped, or when a job panics because the program
+                // crashed the CPU
+                while let Ok(job) = queue.recv() {
+                    job(&mut machine);
+                }
+            })
+            .map_err(|err| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to start session: {err}"),
+                )
+            })?;
+
+        sessions.insert(
+            id.clone(),
+            Session {
+                jobs,
+                last_used: Instant::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Runs `job` on the session's machine and returns its result.
+    async fn run<T: Send + 'static>(
+        &self,
+        id: &str,
+        job: impl FnOnce(&mut Machine) -> T + Send + 'static,
+    ) -> Result<T, (StatusCode, String)> {
+        let (result_tx, result_rx) = oneshot::channel();
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions.get_mut(id).ok_or_else(not_found)?;
+            session.last_used = Instant::now();
+
+            let job: Job = Box::new(move |machine| {
+                let _ = result_tx.send(job(machine));
+            });
+            if session.jobs.send(job).is_err() {
+                sessions.remove(id);
+                return Err(not_found());
+            }
+        }
+
+        result_rx.await.map_err(|_| {
+            tracing::warn!("Session {id} crashed");
+            self.sessions.lock().unwrap().remove(id);
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The program crashed the emulator, and the session has ended".to_owned(),
+            )
+        })
+    }
+}
+
+async fn end_idle_sessions(sessions: Weak<Sessions>) {
+    let mut interval = tokio::time::interval(IDLE_TIMEOUT / 10);
+    loop {
+        interval.tick().await;
+        let Some(sessions) = sessions.upgrade() else {
+            return;
+        };
+
+        // Dropping a session's sender ends its thread
+        sessions
+            .sessions
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.last_used.elapsed() < IDLE_TIMEOUT);
+    }
+}
+
+/// `/api/sessions`, hosting `sessions`.
+pub fn routes(sessions: Arc<Sessions>) -> Router {
+    Router::new()
+        .route("/api/sessions", post(create))
+        .route("/api/sessions/{id}", routing::delete(delete))
+        .route("/api/sessions/{id}/step", post(step))
+        .route("/api/sessions/{id}/frame", get(frame))
+        .route("/api/sessions/{id}/registers", get(registers))
+        .route("/api/sessions/{id}/state", get(save_state).put(load_state))
+        .with_state(sessions)
+}
+
+/// `POST /api/sessions` with `{ "rom": "PONG" }` or `{ "program": [...] }`, and optionally
+/// `"seed"` and `"quirks"`. Starts a paused emulator and returns its `id`.
+pub async fn create(
+    State(sessions): State<Arc<Sessions>>,
+    Json(new_session): Json<NewSession>,
+) -> Result<(StatusCode, Json<Created>), (StatusCode, String)> {
+    let program = match (new_session.rom, new_session.program) {
+        (Some(rom), None) => ROMS_BY_NAME
+            .get(&rom)
+            .cloned()
+            .ok_or_else(|| bad_request("Unknown ROM"))?,
+        (None, Some(program)) => program,
+        _ => return Err(bad_request("Give either a ROM name or a program")),
+    };
+    if program.len() > MAX_ROM_BYTES {
+        return Err(bad_request("The program does not fit in memory"));
+    }
+
+    let seed = new_session.seed.unwrap_or_else(rand::random);
+    let id = sessions.start(program, seed, new_session.quirks)?;
+    tracing::info!("Session {id} started");
+    Ok((StatusCode::CREATED, Json(Created { id, seed })))
+}
+
+/// `DELETE /api/sessions/{id}`.
+pub async fn delete(
+    State(sessions): State<Arc<Sessions>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    sessions
+        .sessions
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or_else(not_found)?;
+    tracing::info!("Session {id} ended");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /api/sessions/{id}/step?frames=1`, optionally with `{ "keys": [...] }`. Runs that many
+/// 60Hz frames and returns the registers after them. Keys stay held between steps until the next
+/// step with a body.
+pub async fn step(
+    State(sessions): State<Arc<Sessions>>,
+    Path(id): Path<String>,
+    Query(query): Query<StepQuery>,
+    step: Option<Json<Step>>,
+) -> Result<Json<Registers>, (StatusCode, String)> {
+    let frames = query.frames.unwrap_or(1);
+    if frames > MAX_FRAMES_PER_STEP {
+        return Err(bad_request(&format!(
+            "At most {MAX_FRAMES_PER_STEP} frames can be run at once"
+        )));
+    }
+
+    let mask = match step {
+        Some(Json(Step { keys })) => {
+            if keys.iter().any(|&key| key as usize >= KEY_COUNT) {
+                return Err(bad_request("Keys are 0 to F"));
+            }
+            Some(keys.iter().fold(0_u16, |mask, &key| mask | (1 << key)))
+        }
+        None => None,
+    };
+
+    let registers = sessions
+        .run(&id, move |machine| {
+            if let Some(mask) = mask {
+                machine.cpu.keypad.hold_keys(mask);
+            }
+            for _ in 0..frames {
+                machine.cpu.run_frame();
+            }
+            machine.frame += u64::from(frames);
+            machine.registers()
+        })
+        .await?;
+    Ok(Json(registers))
+}
+
+/// `GET /api/sessions/{id}/registers`.
+pub async fn registers(
+    State(sessions): State<Arc<Sessions>>,
+    Path(id): Path<String>,
+) -> Result<Json<Registers>, (StatusCode, String)> {
+    Ok(Json(
+        sessions.run(&id, |machine| machine.registers()).await?,
+    ))
+}
+
+/// `GET /api/sessions/{id}/frame?format=png&scale=8`, or `format=bits` for the raw display.
+pub async fn frame(
+    State(sessions): State<Arc<Sessions>>,
+    Path(id): Path<String>,
+    Query(query): Query<FrameQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let scale = query.scale.unwrap_or(DEFAULT_SCALE);
+    if !(1..=MAX_SCALE).contains(&scale) {
+        return Err(bad_request(&format!("The scale must be 1 to {MAX_SCALE}")));
+    }
+
+    let rows = sessions
+        .run(&id, |machine| *machine.cpu.framebuffer.rows())
+        .await?;
+    Ok(match query.format {
+        FrameFormat::Png => {
+            let png = capture::encode_png(&rows, scale, &Palette::default()).map_err(|err| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to encode frame: {err}"),
+                )
+            })?;
+            ([(header::CONTENT_TYPE, "image/png")], png).into_response()
+        }
+        FrameFormat::Bits => {
+            let bits = rows
+                .iter()
+                .flat_map(|row| row.to_be_bytes())
+                .collect::<Vec<_>>();
+            ([(header::CONTENT_TYPE, "application/octet-stream")], bits).into_response()
+        }
+    })
+}
+
+/// `GET /api/sessions/{id}/state`, as saved by the page's Save button.
+pub async fn save_state(
+    State(sessions): State<Arc<Sessions>>,
+    Path(id): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let state = sessions
+        .run(&id, |machine| machine.cpu.save_state())
+        .await?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], state).into_response())
+}
+
+/// `PUT /api/sessions/{id}/state` with a state from [`save_state`] or the page.
+pub async fn load_state(
+    State(sessions): State<Arc<Sessions>>,
+    Path(id): Path<String>,
+    state: Bytes,
+) -> Result<StatusCode, (StatusCode, String)> {
+    sessions
+        .run(&id, move |machine| machine.cpu.load_state(&state))
+        .await?
+        .map_err(|err| bad_request(&err.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{self, Body};
+    use http::{HeaderMap, Method, Request};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    struct Reply {
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+    }
+
+    impl Reply {
+        fn json(&self) -> Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+    }
+
+    async fn send(sessions: &Arc<Sessions>, method: Method, uri: &str, body: Body) -> Reply {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+        let response = routes(Arc::clone(sessions)).oneshot(request).await.unwrap();
+        Reply {
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap(),
+        }
+    }
+
+    async fn get(sessions: &Arc<Sessions>, uri: &str) -> Reply {
+        send(sessions, Method::GET, uri, Body::empty()).await
+    }
+
+    async fn post(sessions: &Arc<Sessions>, uri: &str, body: Value) -> Reply {
+        send(sessions, Method::POST, uri, Body::from(body.to_string())).await
+    }
+
+    /// Starts a session of PONG, and returns its ID.
+    async fn create_pong(sessions: &Arc<Sessions>) -> String {
+        let reply = post(
+            sessions,
+            "/api/sessions",
+            json!({ "rom": "PONG", "seed": 1 }),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::CREATED);
+        assert_eq!(reply.json()["seed"], 1);
+        reply.json()["id"].as_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn sessions_step_draw_and_restore_their_state() {
+        let sessions = Sessions::new(1);
+        let id = create_pong(&sessions).await;
+
+        let stepped = post(
+            &sessions,
+            &format!("/api/sessions/{id}/step?frames=60"),
+            json!({ "keys": [1] }),
+        )
+        .await;
+        assert_eq!(stepped.status, StatusCode::OK);
+        let stepped = stepped.json();
+        assert_eq!(stepped["frame"], 60);
+
+        let png = get(&sessions, &format!("/api/sessions/{id}/frame?scale=2")).await;
+        assert_eq!(png.status, StatusCode::OK);
+        assert_eq!(png.headers[header::CONTENT_TYPE], "image/png");
+        assert!(png.body.starts_with(b"\x89PNG"));
+        let bits = get(&sessions, &format!("/api/sessions/{id}/frame?format=bits")).await;
+        assert_eq!(bits.body.len(), 32 * 8);
+        assert!(bits.body.iter().any(|&byte| byte != 0));
+
+        let state = get(&sessions, &format!("/api/sessions/{id}/state")).await;
+        assert_eq!(state.status, StatusCode::OK);
+        let uri = format!("/api/sessions/{id}/step?frames=30");
+        post(&sessions, &uri, json!({ "keys": [] })).await;
+        let loaded = send(
+            &sessions,
+            Method::PUT,
+            &format!("/api/sessions/{id}/state"),
+            Body::from(state.body),
+        )
+        .await;
+        assert_eq!(loaded.status, StatusCode::NO_CONTENT);
+
+        // The frame count isn't part of the state, but everything else is back where it was
+        let mut restored = get(&sessions, &format!("/api/sessions/{id}/registers"))
+            .await
+            .json();
+        assert_eq!(restored["frame"], 90);
+        restored["frame"] = stepped["frame"].clone();
+        assert_eq!(restored, stepped);
+
+        let uri = format!("/api/sessions/{id}");
+        let deleted = send(&sessions, Method::DELETE, &uri, Body::empty()).await;
+        assert_eq!(deleted.status, StatusCode::NO_CONTENT);
+        let registers = get(&sessions, &format!("/api/sessions/{id}/registers")).await;
+        assert_eq!(registers.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn only_so_many_sessions_run_at_once() {
+        let sessions = Sessions::new(1);
+        let id = create_pong(&sessions).await;
+
+        let reply = post(&sessions, "/api/sessions", json!({ "rom": "PONG" })).await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let uri = format!("/api/sessions/{id}");
+        send(&sessions, Method::DELETE, &uri, Body::empty()).await;
+        create_pong(&sessions).await;
+    }
+
+    #[tokio::test]
+    async fn bad_requests_are_rejected() {
+        let sessions = Sessions::new(1);
+        for new_session in [
+            json!({ "rom": "NOT A ROM" }),
+            json!({}),
+            json!({ "rom": "PONG", "program": [0] }),
+            json!({ "program": vec![0; MAX_ROM_BYTES + 1] }),
+        ] {
+            let reply = post(&sessions, "/api/sessions", new_session.clone()).await;
+            assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{new_session}");
+        }
+
+        let id = create_pong(&sessions).await;
+        let too_many_frames = format!("/api/sessions/{id}/step?frames={}", MAX_FRAMES_PER_STEP + 1);
+        let step = format!("/api/sessions/{id}/step");
+        for (uri, body) in [
+            (too_many_frames, json!({ "keys": [] })),
+            (step.clone(), json!({ "keys": [16] })),
+        ] {
+            assert_eq!(
+                post(&sessions, &uri, body).await.status,
+                StatusCode::BAD_REQUEST
+            );
+        }
+
+        for scale in [0, MAX_SCALE + 1] {
+            let uri = format!("/api/sessions/{id}/frame?scale={scale}");
+            assert_eq!(get(&sessions, &uri).await.status, StatusCode::BAD_REQUEST);
+        }
+
+        let uri = format!("/api/sessions/{id}/state");
+        let reply = send(&sessions, Method::PUT, &uri, Body::from("not a state")).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+
+        // Nothing went wrong with the session itself
+        let registers = get(&sessions, &format!("/api/sessions/{id}/registers")).await;
+        assert_eq!(registers.json()["frame"], 0);
+        let unknown = post(&sessions, "/api/sessions/0/step", json!({ "keys": [] })).await;
+        assert_eq!(unknown.status, StatusCode::NOT_FOUND);
+    }
+}
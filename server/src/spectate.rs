@@ -0,0 +1,181 @@
+use axum::{
+    body::Bytes,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chip_8_emulator::stream::Decoder;
+use http::StatusCode;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+use tokio::sync::broadcast;
+
+const MAX_CHANNEL_NAME_LEN: usize = 32;
+/// Frames buffered for each spectator. One who falls further behind skips to a keyframe.
+const BUFFERED_FRAMES: usize = 120;
+/// A keyframe, the largest frame, is 259 bytes.
+const MAX_FRAME_BYTES: usize = 1024;
+
+#[derive(Debug)]
+struct Channel {
+    frames: broadcast::Sender<Bytes>,
+    /// The display as of the last frame, for spectators who join partway through.
+    decoder: Mutex<Decoder>,
+}
+
+/// Live broadcasts of players' displays and keys, which any number of spectators can watch.
+#[derive(Debug, Default)]
+pub struct Channels {
+    channels: Mutex<HashMap<String, Arc<Channel>>>,
+}
+
+impl Channels {
+    #[must_use]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+}
+
+fn is_valid_channel(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_CHANNEL_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ')
+}
+
+/// `/api/spectate`, relaying broadcasts through `channels`.
+pub fn routes(channels: Arc<Channels>) -> Router {
+    Router::new()
+        .route("/api/spectate/{channel}", get(watch))
+        .route("/api/spectate/{channel}/broadcast", get(broadcast))
+        .with_state(channels)
+}
+
+/// `GET /api/spectate/{channel}/broadcast`, upgraded to a WebSocket that takes binary frames from
+/// a [`chip_8_emulator::stream::Encoder`]. Each channel has one broadcaster at a time.
+pub async fn broadcast(
+    ws: WebSocketUpgrade,
+    State(channels): State<Arc<Channels>>,
+    Path(name): Path<String>,
+) -> Response {
+    if !is_valid_channel(&name) {
+        return (StatusCode::BAD_REQUEST, "Invalid channel name").into_response();
+    }
+
+    if channels.channels.lock().unwrap().contains_key(&name) {
+        return (StatusCode::CONFLICT, "Someone is already broadcasting here").into_response();
+    }
+
+    ws.max_message_size(MAX_FRAME_BYTES)
+        .on_upgrade(move |socket| relay_broadcast(channels, name, socket))
+}
+
+async fn relay_broadcast(channels: Arc<Channels>, name: String, mut socket: WebSocket) {
+    // The channel is only taken once connected, as a failed upgrade would never give it back
+    let channel = {
+        let mut channels = channels.channels.lock().unwrap();
+        if channels.contains_key(&name) {
+            return;
+        }
+
+        let channel = Arc::new(Channel {
+            frames: broadcast::channel(BUFFERED_FRAMES).0,
+            decoder: Mutex::new(Decoder::new()),
+        });
+        channels.insert(name.clone(), Arc::clone(&channel));
+        channel
+    };
+    tracing::info!("Broadcast started on {name}");
+
+    while let Some(Ok(message)) = socket.recv().await {
+        match message {
+            Message::Binary(frame) => {
+                // Spectators are only sent frames that decode, so they can't be confused
+                if let Err(err) = channel.decoder.lock().unwrap().apply(&frame) {
+                    tracing::warn!("Ending broadcast on {name}: {err}");
+                    break;
+                }
+                let _ = channel.frames.send(frame);
+            }
+            Message::Close(_) => break,
+            _ => (),
+        }
+    }
+
+    // Spectators see the channel close once the last sender is dropped
+    channels.channels.lock().unwrap().remove(&name);
+    tracing::info!("Broadcast ended on {name}");
+}
+
+/// `GET /api/spectate/{channel}`, upgraded to a WebSocket that sends a keyframe of the current
+/// display and then each frame the broadcaster sends, until the broadcast ends.
+pub async fn watch(
+    ws: WebSocketUpgrade,
+    State(channels): State<Arc<Channels>>,
+    Path(name): Path<String>,
+) -> Response {
+    if !is_valid_channel(&name) {
+        return (StatusCode::BAD_REQUEST, "Invalid channel name").into_response();
+    }
+    let Some(channel) = channels
+        .channels
+        .lock()
+        .unwrap()
+        .get(&name)
+        .map(Arc::downgrade)
+    else {
+        return (StatusCode::NOT_FOUND, "Nobody is broadcasting here").into_response();
+    };
+
+    ws.on_upgrade(move |socket| relay_to_spectator(channel, socket))
+}
+
+/// The current display, and the frames that follow it.
+fn subscribe(channel: &Weak<Channel>) -> Option<(Option<Bytes>, broadcast::Receiver<Bytes>)> {
+    let channel = channel.upgrade()?;
+    // Subscribing while holding the decoder means no frame is missed between the two
+    let decoder = channel.decoder.lock().unwrap();
+    let frames = channel.frames.subscribe();
+    Some((decoder.keyframe().map(Bytes::from), frames))
+}
+
+async fn relay_to_spectator(channel: Weak<Channel>, mut socket: WebSocket) {
+    let Some((mut next_frame, mut frames)) = subscribe(&channel) else {
+        return;
+    };
+
+    loop {
+        if let Some(frame) = next_frame.take() {
+            if socket.send(Message::Binary(frame)).await.is_err() {
+                return;
+            }
+        }
+
+        tokio::select! {
+            frame = frames.recv() => match frame {
+                Ok(frame) => next_frame = Some(frame),
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let Some(resubscribed) = subscribe(&channel) else {
+                        break;
+                    };
+                    (next_frame, frames) = resubscribed;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Spectators have nothing to say, but their leaving is noticed here
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+
+    let _ = socket.send(Message::Close(None)).await;
+}
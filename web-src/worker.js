@@ -0,0 +1,4 @@
+import init, { runWorker } from './pkg/chip_8_emulator.js';
+
+await init();
+runWorker();